@@ -0,0 +1,128 @@
+use crate::snapshot::fnv1a;
+use crate::syscall::errno::EFBIG;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+// --- CHUNK STORE ---
+// File contents are split into fixed CHUNK_SIZE chunks, each an immutable, reference-counted buffer named by its
+// fnv1a hash. Cloning a Blob (and so a Vfs or a Machine) copies chunk handles, not bytes; writes re-intern only
+// the chunks they touch. The store holds weak handles, so identical chunks anywhere in the tree are shared and a
+// chunk is freed once no file refers to it.
+pub const CHUNK_SIZE: usize = 4096;
+// Growing a file costs one handle per chunk, so no blob may exceed this (EFBIG).
+pub const MAX_BLOB: usize = 1 << 30;
+static ZEROES: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+
+#[derive(Debug, Clone)]
+pub struct Chunk { pub hash: u64, pub data: Rc<[u8]> }
+
+// Every chunk but the last is exactly CHUNK_SIZE bytes, and the last is non-empty, so chunk boundaries (and the
+// hashes built on them) depend only on the content.
+#[derive(Debug, Clone, Default)]
+pub struct Blob { pub chunks: Vec<Chunk>, len: usize }
+
+impl PartialEq for Blob {
+    fn eq(&self, o: &Self) -> bool { self.len == o.len && self.chunks.iter().zip(&o.chunks).all(|(a, b)| a.hash == b.hash && a.data == b.data) }
+}
+
+impl Blob {
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    // Rebuilds a blob from decoded chunks, checking the size invariant.
+    pub fn from_chunks(chunks: Vec<Chunk>) -> Option<Blob> {
+        let n = chunks.len();
+        if chunks.iter().enumerate().any(|(i, c)| c.data.is_empty() || c.data.len() > CHUNK_SIZE || (i + 1 < n && c.data.len() != CHUNK_SIZE)) { return None; }
+        Some(Blob { len: chunks.iter().map(|c| c.data.len()).sum(), chunks })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> { self.chunks.iter().flat_map(|c| c.data.iter().copied()).collect() }
+
+    // Copies bytes from `pos` into `buf` and returns how many were available.
+    pub fn read_at(&self, pos: usize, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.len.saturating_sub(pos));
+        let mut done = 0;
+        while done < n {
+            let (i, off) = ((pos + done) / CHUNK_SIZE, (pos + done) % CHUNK_SIZE);
+            let k = (n - done).min(self.chunks[i].data.len() - off);
+            buf[done..done + k].copy_from_slice(&self.chunks[i].data[off..off + k]);
+            done += k;
+        }
+        n
+    }
+
+    // fnv1a over the chunk hashes: a file's leaf in the VFS Merkle tree.
+    pub fn hash(&self) -> u64 {
+        let mut b = (self.len as u64).to_le_bytes().to_vec();
+        for c in &self.chunks { b.extend_from_slice(&c.hash.to_le_bytes()); }
+        fnv1a(&b)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChunkStore { chunks: HashMap<u64, Weak<[u8]>>, prune_at: usize }
+
+impl ChunkStore {
+    pub fn intern(&mut self, bytes: &[u8]) -> Chunk {
+        let hash = fnv1a(bytes);
+        if let Some(data) = self.chunks.get(&hash).and_then(Weak::upgrade) {
+            // a hash collision keeps its own buffer rather than aliasing different bytes
+            if *data == *bytes { return Chunk { hash, data }; }
+            return Chunk { hash, data: Rc::from(bytes) };
+        }
+        if self.chunks.len() >= self.prune_at {
+            self.chunks.retain(|_, w| w.strong_count() > 0);
+            self.prune_at = (self.chunks.len() * 2).max(64);
+        }
+        let data: Rc<[u8]> = Rc::from(bytes);
+        self.chunks.insert(hash, Rc::downgrade(&data));
+        Chunk { hash, data }
+    }
+
+    // Distinct chunks currently referenced by some blob.
+    pub fn live(&self) -> usize { self.chunks.values().filter(|w| w.strong_count() > 0).count() }
+
+    pub fn blob(&mut self, bytes: &[u8]) -> Blob {
+        Blob { chunks: bytes.chunks(CHUNK_SIZE).map(|c| self.intern(c)).collect(), len: bytes.len() }
+    }
+
+    // Truncates, or extends with zeroes; whole zero chunks are all one shared chunk.
+    pub fn set_len(&mut self, b: &mut Blob, n: usize) -> Result<(), u64> {
+        if n > MAX_BLOB { return Err(EFBIG); }
+        if n <= b.len {
+            let keep = n.div_ceil(CHUNK_SIZE);
+            b.chunks.truncate(keep);
+            if let Some(last) = b.chunks.last_mut() {
+                let want = n - (keep - 1) * CHUNK_SIZE;
+                if last.data.len() > want { let c = self.intern(&last.data[..want]); *last = c; }
+            }
+        } else {
+            if let Some(last) = b.chunks.last_mut().filter(|c| c.data.len() < CHUNK_SIZE) {
+                let mut d = last.data.to_vec(); d.resize(CHUNK_SIZE.min(d.len() + n - b.len), 0);
+                *last = self.intern(&d);
+            }
+            let covered = b.chunks.iter().map(|c| c.data.len()).sum::<usize>();
+            let zero = self.intern(&ZEROES);
+            let (whole, rest) = ((n - covered) / CHUNK_SIZE, (n - covered) % CHUNK_SIZE);
+            b.chunks.extend(std::iter::repeat_n(zero, whole));
+            if rest > 0 { b.chunks.push(self.intern(&ZEROES[..rest])); }
+        }
+        b.len = n;
+        Ok(())
+    }
+
+    // Writes `data` at `pos`, zero-filling any gap past the end, and re-interns only the chunks it overlaps.
+    pub fn write_at(&mut self, b: &mut Blob, pos: usize, data: &[u8]) -> Result<(), u64> {
+        if data.is_empty() { return Ok(()); }
+        let end = pos.checked_add(data.len()).ok_or(EFBIG)?;
+        if end > b.len { self.set_len(b, end)?; }
+        for i in pos / CHUNK_SIZE..end.div_ceil(CHUNK_SIZE) {
+            let start = i * CHUNK_SIZE;
+            let mut d = b.chunks[i].data.to_vec();
+            let (from, to) = (pos.max(start), end.min(start + d.len()));
+            d[from - start..to - start].copy_from_slice(&data[from - pos..to - pos]);
+            b.chunks[i] = self.intern(&d);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use crate::debuginfo::DebugInfo;
+use crate::trace::{StepEvent, Tracer};
+use crate::{opcode_info, Machine, SymbolMap, CODE_BASE};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// --- COVERAGE ---
+// Counts executions per instruction and the outcome of every JZ, then folds them onto the image's line table.
+// One Coverage can observe several runs of the same image, so a whole test set accumulates into one report.
+pub struct Coverage {
+    debug: DebugInfo, symbols: SymbolMap,
+    instrs: Vec<(usize, u8)>, hits: BTreeMap<usize, u64>,
+    // JZ address -> (jumped, fell through)
+    branches: BTreeMap<usize, (u64, u64)>,
+}
+
+impl Coverage {
+    // Code is a contiguous instruction stream, so every JZ can be found statically, executed or not.
+    pub fn new(bef: &[u8], symbols: SymbolMap) -> Self {
+        let code_len = u32::from_le_bytes(bef[8..12].try_into().unwrap()) as usize;
+        let code = &bef[16..16 + code_len];
+        let mut instrs = Vec::new(); let mut a = 0;
+        while a < code.len() { instrs.push((CODE_BASE + a, code[a])); a += match opcode_info(code[a]) { Some((_, true)) => 9, _ => 1 }; }
+        let branches = instrs.iter().filter(|(_, op)| *op == 0x31).map(|(a, _)| (*a, (0, 0))).collect();
+        Self { debug: DebugInfo::from_bef(bef).unwrap_or_default(), symbols, instrs, hits: BTreeMap::new(), branches }
+    }
+
+    pub fn lcov(&self) -> String {
+        let hits = |a: usize| self.hits.get(&a).copied().unwrap_or(0);
+        // file -> line -> executions, counted at the first instruction of each line-table range
+        let mut lines: BTreeMap<&str, BTreeMap<usize, u64>> = BTreeMap::new();
+        for (a, f, l) in &self.debug.lines {
+            let Some(file) = self.debug.files.get(*f) else { continue };
+            let e = lines.entry(file).or_default().entry(*l).or_default(); *e = (*e).max(hits(*a));
+        }
+        let mut out = String::new();
+        for (file, file_lines) in &lines {
+            let _ = write!(out, "TN:\nSF:{}\n", file);
+            let funcs: Vec<(&str, usize, usize)> = self.symbols.functions.iter()
+                .filter_map(|(a, n)| match self.decl_line(*a) { Some((f, l)) if f == *file => Some((n.as_str(), *a, l)), _ => None })
+                .collect();
+            for (n, _, l) in &funcs { let _ = writeln!(out, "FN:{},{}", l, n); }
+            for (n, a, _) in &funcs { let _ = writeln!(out, "FNDA:{},{}", hits(*a), n); }
+            let _ = write!(out, "FNF:{}\nFNH:{}\n", funcs.len(), funcs.iter().filter(|(_, a, _)| hits(*a) > 0).count());
+            let (mut brf, mut brh) = (0, 0);
+            let mut block: BTreeMap<usize, usize> = BTreeMap::new();
+            for (a, (jumped, fell)) in &self.branches {
+                let Some((f, l)) = self.debug.line_at(*a) else { continue };
+                if f != *file { continue; }
+                let b = block.entry(l).or_default();
+                let show = |n: u64| if hits(*a) == 0 { "-".to_string() } else { n.to_string() };
+                let _ = write!(out, "BRDA:{},{},0,{}\nBRDA:{},{},1,{}\n", l, b, show(*jumped), l, b, show(*fell));
+                *b += 1; brf += 2; brh += (*jumped > 0) as usize + (*fell > 0) as usize;
+            }
+            let _ = write!(out, "BRF:{}\nBRH:{}\n", brf, brh);
+            for (l, n) in file_lines { let _ = writeln!(out, "DA:{},{}", l, n); }
+            let _ = write!(out, "LF:{}\nLH:{}\nend_of_record\n", file_lines.len(), file_lines.values().filter(|n| **n > 0).count());
+        }
+        out
+    }
+
+    // A function's first line-table entry is its declaration; later entries at the same address are its first statement.
+    fn decl_line(&self, addr: usize) -> Option<(&str, usize)> {
+        match self.debug.lines.iter().find(|(a, _, _)| *a == addr) { Some((_, f, l)) => Some((self.debug.files.get(*f)?.as_str(), *l)), None => self.debug.line_at(addr) }
+    }
+
+    // Instructions of the image that never ran, for quick terminal summaries.
+    pub fn unexecuted(&self) -> usize { self.instrs.iter().filter(|(a, _)| !self.hits.contains_key(a)).count() }
+}
+
+impl Tracer for Coverage {
+    fn on_step(&mut self, m: &Machine, ev: &StepEvent) {
+        *self.hits.entry(ev.ip).or_default() += 1;
+        if ev.op == 0x31 {
+            if let Some(b) = self.branches.get_mut(&ev.ip) { if m.ip == ev.ip + 9 { b.1 += 1; } else { b.0 += 1; } }
+        }
+    }
+}
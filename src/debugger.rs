@@ -0,0 +1,216 @@
+use crate::debuginfo::DebugInfo;
+use crate::trace::{StepEvent, Tracer};
+use crate::{opcode_info, Assembler, Machine, MiniCC, RunOutcome, SymbolMap, Trap};
+use crate::vfs::FileSystem;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+// --- DEBUGGER ---
+// Line-oriented front end over a Machine. The same `command` strings drive the CLI (`debug <file.c>`) and the
+// WASM shell (`debug_open` / `debug_command`).
+const HELP: &str = "\
+break <addr|label>    set a breakpoint          delete <addr|label>   remove a breakpoint
+watch <addr> [len]    stop on writes to a range unwatch <addr>        remove a watchpoint
+step [n] | s          execute n instructions    next | n              step over CALL/ICALL
+finish                run until the frame RETs  continue | c          run to the next stop
+regs                  registers and location    stack                 operand stack
+bt                    call_stack frames         locals                named locals, or slots [bp, sp)
+local <off>           u64 at bp+off             mem <addr> [len]      hex dump
+disas [addr] [n]      disassemble               info                  breakpoints and watchpoints
+limits                stack usage, high-water marks and limits";
+
+#[derive(Default)]
+struct WriteLog { writes: Vec<(usize, usize)> }
+impl Tracer for WriteLog {
+    fn on_step(&mut self, _m: &Machine, ev: &StepEvent) { self.writes.extend(ev.writes.iter().map(|(a, b)| (*a, b.len()))); }
+}
+
+enum Stop { Breakpoint, Watchpoint(usize), Halted, Trapped(Trap), Waiting(u64), Limit, Done }
+
+fn parse_num(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") { Some(h) => usize::from_str_radix(h, 16).ok(), None => s.parse().ok() }
+}
+
+pub struct Debugger {
+    pub vm: Machine, pub symbols: SymbolMap, pub debug: Option<DebugInfo>,
+    pub breakpoints: BTreeSet<usize>, pub watchpoints: Vec<(usize, usize)>,
+    // Instructions a single `continue`/`next`/`finish` may execute before giving control back.
+    pub step_limit: u64,
+}
+
+impl Debugger {
+    pub fn new(asm: &str, data: &[u8]) -> Self {
+        let bef = Assembler::compile_bef(asm, data);
+        let mut vm = Machine::new(); vm.load(&bef);
+        Self { vm, symbols: Assembler::symbols(asm), debug: DebugInfo::from_bef(&bef), breakpoints: BTreeSet::new(), watchpoints: Vec::new(), step_limit: 10_000_000 }
+    }
+
+    pub fn from_c(src: &str, headers: &dyn FileSystem) -> Self {
+        let mut cc = MiniCC::new(src, headers);
+        let asm = cc.compile();
+        Self::new(&asm, &cc.data)
+    }
+
+    fn resolve(&self, s: &str) -> Option<usize> { parse_num(s).or_else(|| self.symbols.addr(s)) }
+
+    pub fn disasm(&self, addr: usize) -> (String, usize) {
+        let op = self.vm.memory.get(addr).copied().unwrap_or(0);
+        match opcode_info(op) {
+            Some((name, true)) => {
+                let v = self.vm.memory.get(addr + 1..addr + 9).map(|b| u64::from_le_bytes(b.try_into().unwrap())).unwrap_or(0);
+                let arg = if matches!(op, 0x30 | 0x31 | 0x40) { self.symbols.describe(v as usize) } else { v.to_string() };
+                (format!("{} {}", name, arg), 9)
+            }
+            Some((name, false)) => (name.to_string(), 1),
+            None => (format!(".byte 0x{:02x}", op), 1),
+        }
+    }
+
+    // "<addr> <func+off>" plus "file:line in func" when the image carries debug info.
+    pub fn where_is(&self, addr: usize) -> String {
+        match &self.debug { Some(d) if d.line_at(addr).is_some() => format!("{} <{}> {}", addr, self.symbols.describe(addr), d.describe(addr, &self.symbols)), _ => format!("{} <{}>", addr, self.symbols.describe(addr)) }
+    }
+
+    fn location(&self) -> String { format!("{}: {}", self.where_is(self.vm.ip), self.disasm(self.vm.ip).0) }
+
+    fn single(&mut self) -> Option<Stop> {
+        if self.vm.halted { return Some(Stop::Halted); }
+        let mut log = WriteLog::default();
+        let r = self.vm.step_traced(&mut log);
+        match self.vm.schedule(r) {
+            None => {}
+            Some(RunOutcome::Trapped(t)) => return Some(Stop::Trapped(t)),
+            Some(RunOutcome::WaitingForInput(fd)) => return Some(Stop::Waiting(fd)),
+            Some(_) => return Some(Stop::Halted),
+        }
+        log.writes.iter()
+            .find_map(|(a, n)| self.watchpoints.iter().find(|(wa, wn)| *a < wa + wn && *wa < a + n).map(|(wa, _)| Stop::Watchpoint(*wa)))
+    }
+
+    fn run_until(&mut self, done: impl Fn(&Machine) -> bool) -> Stop {
+        for _ in 0..self.step_limit {
+            if let Some(stop) = self.single() { return stop; }
+            if done(&self.vm) { return Stop::Done; }
+            if self.breakpoints.contains(&self.vm.ip) { return Stop::Breakpoint; }
+        }
+        Stop::Limit
+    }
+
+    fn report(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Halted => format!("program halted, result {}", self.vm.exit_status.unwrap_or(0)),
+            Stop::Trapped(t) => match &self.debug { Some(d) => format!("{}\n{}", d.explain(&t, &self.symbols), self.location()), None => format!("{}\n{}", t, self.location()) },
+            Stop::Breakpoint => format!("breakpoint hit\n{}", self.location()),
+            Stop::Watchpoint(a) => format!("watchpoint {} written\n{}", a, self.location()),
+            Stop::Waiting(fd) => format!("waiting for input on fd {}\n{}", fd, self.location()),
+            Stop::Limit => format!("stopped after {} instructions\n{}", self.step_limit, self.location()),
+            Stop::Done => self.location(),
+        }
+    }
+
+    pub fn command(&mut self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = |i: usize| words.get(i).copied();
+        let mut out = String::new();
+        match words.first().copied().unwrap_or("") {
+            "break" | "b" => match arg(1).and_then(|a| self.resolve(a)) {
+                Some(a) => { self.breakpoints.insert(a); out = format!("breakpoint at {} <{}>", a, self.symbols.describe(a)); }
+                None => out = "unknown address or label".into(),
+            },
+            "delete" | "d" => match arg(1).and_then(|a| self.resolve(a)) {
+                Some(a) if self.breakpoints.remove(&a) => out = format!("deleted breakpoint at {}", a),
+                _ => out = "no such breakpoint".into(),
+            },
+            "watch" | "w" => match (arg(1).and_then(parse_num), arg(2).map_or(Some(8), parse_num)) {
+                (Some(a), Some(n)) if n > 0 => { self.watchpoints.push((a, n)); out = format!("watching [{}, {})", a, a + n); }
+                _ => out = "usage: watch <addr> [len]".into(),
+            },
+            "unwatch" => { let a = arg(1).and_then(parse_num); let before = self.watchpoints.len(); self.watchpoints.retain(|(wa, _)| Some(*wa) != a); out = if self.watchpoints.len() < before { "watchpoint removed".into() } else { "no such watchpoint".into() }; }
+            "info" => {
+                for b in &self.breakpoints { let _ = writeln!(out, "break {} <{}>", b, self.symbols.describe(*b)); }
+                for (a, n) in &self.watchpoints { let _ = writeln!(out, "watch [{}, {})", a, a + n); }
+                if out.is_empty() { out = "no breakpoints or watchpoints".into(); } else { out.pop(); }
+            }
+            "step" | "s" | "stepi" | "si" => {
+                let n = arg(1).and_then(parse_num).unwrap_or(1);
+                let mut stop = Stop::Done;
+                for _ in 0..n { if let Some(s) = self.single() { stop = s; break; } }
+                out = self.report(stop);
+            }
+            "next" | "n" => {
+                let depth = self.vm.call_stack.len();
+                let is_call = matches!(self.vm.memory.get(self.vm.ip), Some(0x40) | Some(0x41));
+                let stop = match self.single() {
+                    Some(s) => s,
+                    None if is_call && self.vm.call_stack.len() > depth => self.run_until(|m| m.call_stack.len() <= depth),
+                    None => Stop::Done,
+                };
+                out = self.report(stop);
+            }
+            "finish" => { let depth = self.vm.call_stack.len(); let stop = self.run_until(|m| m.call_stack.len() < depth); out = self.report(stop); }
+            "continue" | "c" => { let stop = self.run_until(|_| false); out = self.report(stop); }
+            "regs" | "r" => out = format!("ip={} bp={} sp={} brk={} depth={}\n{}", self.vm.ip, self.vm.bp, self.vm.sp, self.vm.brk, self.vm.call_stack.len(), self.location()),
+            "limits" => {
+                let (m, h, l) = (&self.vm, self.vm.high_water, self.vm.limits);
+                out = format!("operand stack  {:>8} now {:>8} high {:>8} limit\ncall depth     {:>8} now {:>8} high {:>8} limit\nframe bytes    {:>8} now {:>8} high {:>8} limit",
+                    m.stack.len(), h.stack_depth, l.stack_depth, m.call_stack.len(), h.call_depth, l.call_depth, m.sp.saturating_sub(m.frame_base), h.frame_bytes, l.frame_bytes);
+            }
+            "stack" => {
+                if self.vm.stack.is_empty() { out = "operand stack empty".into(); }
+                for (i, v) in self.vm.stack.iter().enumerate().rev() { let _ = writeln!(out, "[{}] {}", i, v); }
+                if out.ends_with('\n') { out.pop(); }
+            }
+            "bt" | "frames" => {
+                let _ = write!(out, "#0 {} bp={}", self.where_is(self.vm.ip), self.vm.bp);
+                for (i, (ri, ob)) in self.vm.call_stack.iter().rev().enumerate() { let _ = write!(out, "\n#{} {} bp={}", i + 1, self.where_is(*ri), ob); }
+            }
+            "locals" if self.debug.is_some() => {
+                let func = self.symbols.function_at(self.vm.ip).map(|(n, _)| n).unwrap_or("");
+                for v in self.debug.as_ref().unwrap().locals_of(func) {
+                    let value = if v.ty.starts_with("char") && !v.ty.contains('*') && !v.ty.contains('[') { self.vm.memory.get(self.vm.bp + v.offset).map_or("<out of bounds>".into(), |b| b.to_string()) } else { self.read(self.vm.bp + v.offset) };
+                    let _ = writeln!(out, "{} {} [bp+{}] = {}", v.ty, v.name, v.offset, value);
+                }
+                if out.is_empty() { out = format!("no locals in {}", func); } else { out.pop(); }
+            }
+            "locals" => {
+                let (bp, sp) = (self.vm.bp, self.vm.sp.min(self.vm.memory.len()));
+                for off in (0..sp.saturating_sub(bp)).step_by(8) { let _ = writeln!(out, "[bp+{}] {}", off, self.read(bp + off)); }
+                if out.is_empty() { out = "frame is empty".into(); } else { out.pop(); }
+            }
+            "local" => match arg(1).and_then(parse_num) {
+                Some(off) => out = format!("[bp+{}] {}", off, self.read(self.vm.bp + off)),
+                None => out = "usage: local <offset>".into(),
+            },
+            "mem" | "x" => match arg(1).and_then(|a| self.resolve(a)) {
+                Some(a) => {
+                    let n = arg(2).and_then(parse_num).unwrap_or(64);
+                    let end = a.saturating_add(n).min(self.vm.memory.len());
+                    for (i, row) in self.vm.memory.get(a..end).unwrap_or(&[]).chunks(16).enumerate() {
+                        let _ = write!(out, "{:08x}:", a + i * 16);
+                        for b in row { let _ = write!(out, " {:02x}", b); }
+                        out.push('\n');
+                    }
+                    out.pop();
+                }
+                None => out = "usage: mem <addr> [len]".into(),
+            },
+            "disas" => {
+                let mut a = arg(1).and_then(|a| self.resolve(a)).unwrap_or(self.vm.ip);
+                for _ in 0..arg(2).and_then(parse_num).unwrap_or(8) {
+                    let (text, len) = self.disasm(a);
+                    let mark = if a == self.vm.ip { "=>" } else { "  " };
+                    let _ = writeln!(out, "{} {} <{}>: {}", mark, a, self.symbols.describe(a), text);
+                    a += len;
+                }
+                out.pop();
+            }
+            "help" | "h" | "" => out = HELP.into(),
+            other => out = format!("unknown command '{}', try 'help'", other),
+        }
+        out
+    }
+
+    fn read(&self, a: usize) -> String {
+        match self.vm.memory.get(a..a + 8) { Some(b) => u64::from_le_bytes(b.try_into().unwrap()).to_string(), None => "<out of bounds>".into() }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::snapshot::{put, put_bytes, Reader};
+use crate::{SymbolMap, Trap};
+
+// --- DEBUG INFO ---
+// Optional BEF section produced from the `.file`/`.loc`/`.local` directives MiniCC emits. The section sits after
+// the data segment and its byte length is stored in header bytes 12..16 (0 when absent).
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalVar { pub func: String, pub name: String, pub offset: usize, pub ty: String }
+
+// `lines` holds (code address, file index, 1-based line), sorted by address; an entry covers code up to the next one.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo { pub files: Vec<String>, pub lines: Vec<(usize, usize, usize)>, pub locals: Vec<LocalVar> }
+
+impl DebugInfo {
+    pub fn is_empty(&self) -> bool { self.files.is_empty() && self.lines.is_empty() && self.locals.is_empty() }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = b"DBG1".to_vec();
+        put(&mut out, self.files.len() as u64);
+        for f in &self.files { put_bytes(&mut out, f.as_bytes()); }
+        put(&mut out, self.lines.len() as u64);
+        for (a, f, l) in &self.lines { put(&mut out, *a as u64); put(&mut out, *f as u64); put(&mut out, *l as u64); }
+        put(&mut out, self.locals.len() as u64);
+        for v in &self.locals { put_bytes(&mut out, v.func.as_bytes()); put_bytes(&mut out, v.name.as_bytes()); put(&mut out, v.offset as u64); put_bytes(&mut out, v.ty.as_bytes()); }
+        out
+    }
+
+    pub fn decode(d: &[u8]) -> Result<Self, String> {
+        let mut r = Reader { d, pos: 0 };
+        if r.take(4)? != b"DBG1" { return Err("not a debug info section".into()); }
+        let files = (0..r.usize()?).map(|_| r.string()).collect::<Result<_, _>>()?;
+        let lines = (0..r.usize()?).map(|_| Ok((r.usize()?, r.usize()?, r.usize()?))).collect::<Result<_, String>>()?;
+        let locals = (0..r.usize()?).map(|_| Ok(LocalVar { func: r.string()?, name: r.string()?, offset: r.usize()?, ty: r.string()? })).collect::<Result<_, String>>()?;
+        Ok(Self { files, lines, locals })
+    }
+
+    // Extracts the debug section of a BEF image, if it carries one.
+    pub fn from_bef(bef: &[u8]) -> Option<Self> {
+        let len = u32::from_le_bytes(bef.get(12..16)?.try_into().unwrap()) as usize;
+        if len == 0 || len > bef.len() { return None; }
+        Self::decode(&bef[bef.len() - len..]).ok()
+    }
+
+    pub fn line_at(&self, addr: usize) -> Option<(&str, usize)> {
+        let i = self.lines.partition_point(|(a, _, _)| *a <= addr);
+        if i == 0 { return None; }
+        let (_, f, l) = self.lines[i - 1];
+        Some((self.files.get(f)?.as_str(), l))
+    }
+
+    pub fn locals_of<'a>(&'a self, func: &'a str) -> impl Iterator<Item = &'a LocalVar> + 'a { self.locals.iter().filter(move |v| v.func == func) }
+
+    // "stdio.h:3 in fputs", falling back to the symbol map when the address has no line entry.
+    pub fn describe(&self, addr: usize, symbols: &SymbolMap) -> String {
+        let func = symbols.function_at(addr).map(|(n, _)| n);
+        match (self.line_at(addr), func) {
+            (Some((f, l)), Some(n)) => format!("{}:{} in {}", f, l, n),
+            (Some((f, l)), None) => format!("{}:{}", f, l),
+            (None, _) => symbols.describe(addr),
+        }
+    }
+
+    pub fn explain(&self, trap: &Trap, symbols: &SymbolMap) -> String { format!("TRAP @ {} ({}): {}", trap.ip, self.describe(trap.ip, symbols), trap.kind) }
+}
@@ -0,0 +1,177 @@
+use crate::snapshot::{put, Reader};
+use crate::syscall::errno::*;
+use crate::syscall::{S_IFCHR, S_IFIFO};
+
+// --- DEVICES ---
+// A character device is an inode whose reads, writes and ioctls go to a driver instead of file contents. Drivers
+// ignore the file offset. Their whole state is serialized by `encode` and rebuilt by `decode`, so devices
+// snapshot, clone and hash like everything else in the VFS.
+pub trait Device: std::fmt::Debug {
+    // Bytes copied into `buf`, 0 at end of input, or EAGAIN when a read should block until the host supplies more.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, u64>;
+    fn write(&mut self, data: &[u8]) -> Result<usize, u64>;
+    // The result value and any bytes to copy out to the argument pointer; ENOTTY for unknown requests.
+    fn ioctl(&mut self, _req: u64) -> Result<(u64, Vec<u8>), u64> { Err(ENOTTY) }
+    // (read inode, write inode) for alias devices such as /dev/tty; the VFS forwards I/O to them.
+    fn route(&self) -> Option<(u64, u64)> { None }
+    // Host side: takes buffered output, and marks input as finished.
+    fn drain(&mut self) -> Vec<u8> { Vec::new() }
+    fn hangup(&mut self) {}
+    // The last open file description reading (write false) or writing (write true) the device went away.
+    fn release(&mut self, _write: bool) {}
+    // st_mode type bits; a device reporting S_IFIFO is a pipe, and a process it blocks waits on other processes
+    // rather than on the host.
+    fn type_bits(&self) -> u64 { S_IFCHR }
+    // Driver name and state.
+    fn encode(&self) -> (&'static str, Vec<u8>);
+    fn box_clone(&self) -> Box<dyn Device>;
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Self { self.box_clone() }
+}
+
+impl PartialEq for Box<dyn Device> {
+    fn eq(&self, o: &Self) -> bool { self.encode() == o.encode() }
+}
+
+// ioctl requests, Linux values.
+pub const TCGETS: u64 = 0x5401;
+pub const TIOCGWINSZ: u64 = 0x5413;
+pub const TTY_ROWS: u16 = 24;
+pub const TTY_COLS: u16 = 80;
+
+// Terminal requests: TCGETS succeeds (which is all isatty asks), TIOCGWINSZ fills a struct winsize.
+fn tty_ioctl(req: u64) -> Result<(u64, Vec<u8>), u64> {
+    match req {
+        TCGETS => Ok((0, Vec::new())),
+        TIOCGWINSZ => Ok((0, [TTY_ROWS, TTY_COLS, 0, 0].iter().flat_map(|v| v.to_le_bytes()).collect())),
+        _ => Err(EINVAL),
+    }
+}
+
+// A byte queue between the guest and the host terminal: stdin, stdout and stderr. Reads consume from the front
+// and block while it is empty, until `hangup` turns an empty queue into end of file.
+#[derive(Debug, Clone, Default)]
+pub struct Stream { pub buf: Vec<u8>, pub closed: bool }
+
+impl Device for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, u64> {
+        if self.buf.is_empty() && !buf.is_empty() { return if self.closed { Ok(0) } else { Err(EAGAIN) }; }
+        let n = buf.len().min(self.buf.len());
+        buf[..n].copy_from_slice(&self.buf[..n]); self.buf.drain(..n);
+        Ok(n)
+    }
+    fn write(&mut self, data: &[u8]) -> Result<usize, u64> { self.buf.extend_from_slice(data); Ok(data.len()) }
+    fn ioctl(&mut self, req: u64) -> Result<(u64, Vec<u8>), u64> { tty_ioctl(req) }
+    fn drain(&mut self) -> Vec<u8> { std::mem::take(&mut self.buf) }
+    fn hangup(&mut self) { self.closed = true; }
+    fn encode(&self) -> (&'static str, Vec<u8>) { let mut s = vec![self.closed as u8]; s.extend_from_slice(&self.buf); ("stream", s) }
+    fn box_clone(&self) -> Box<dyn Device> { Box::new(self.clone()) }
+}
+
+// /dev/null: always at end of file, swallows writes.
+#[derive(Debug, Clone)]
+pub struct Null;
+
+impl Device for Null {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, u64> { Ok(0) }
+    fn write(&mut self, data: &[u8]) -> Result<usize, u64> { Ok(data.len()) }
+    fn encode(&self) -> (&'static str, Vec<u8>) { ("null", Vec::new()) }
+    fn box_clone(&self) -> Box<dyn Device> { Box::new(self.clone()) }
+}
+
+// /dev/zero: endless zero bytes, swallows writes.
+#[derive(Debug, Clone)]
+pub struct Zero;
+
+impl Device for Zero {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, u64> { buf.fill(0); Ok(buf.len()) }
+    fn write(&mut self, data: &[u8]) -> Result<usize, u64> { Ok(data.len()) }
+    fn encode(&self) -> (&'static str, Vec<u8>) { ("zero", Vec::new()) }
+    fn box_clone(&self) -> Box<dyn Device> { Box::new(self.clone()) }
+}
+
+// /dev/urandom: a splitmix64 stream from `Machine::seed_random`, so "random" bytes replay exactly. Writes are
+// accepted and ignored, as Linux does for unprivileged callers.
+#[derive(Debug, Clone)]
+pub struct Urandom { pub state: u64 }
+
+impl Urandom {
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Device for Urandom {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, u64> {
+        for chunk in buf.chunks_mut(8) { let v = self.next().to_le_bytes(); chunk.copy_from_slice(&v[..chunk.len()]); }
+        Ok(buf.len())
+    }
+    fn write(&mut self, data: &[u8]) -> Result<usize, u64> { Ok(data.len()) }
+    fn encode(&self) -> (&'static str, Vec<u8>) { ("urandom", self.state.to_le_bytes().to_vec()) }
+    fn box_clone(&self) -> Box<dyn Device> { Box::new(self.clone()) }
+}
+
+// /dev/tty: the controlling terminal, reading from the stdin stream and writing to the stdout one.
+#[derive(Debug, Clone)]
+pub struct Tty { pub input: u64, pub output: u64 }
+
+impl Device for Tty {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, u64> { Err(ENXIO) }
+    fn write(&mut self, _data: &[u8]) -> Result<usize, u64> { Err(ENXIO) }
+    fn ioctl(&mut self, req: u64) -> Result<(u64, Vec<u8>), u64> { tty_ioctl(req) }
+    fn route(&self) -> Option<(u64, u64)> { Some((self.input, self.output)) }
+    fn encode(&self) -> (&'static str, Vec<u8>) { let mut s = Vec::new(); put(&mut s, self.input); put(&mut s, self.output); ("tty", s) }
+    fn box_clone(&self) -> Box<dyn Device> { Box::new(self.clone()) }
+}
+
+// Bytes a pipe buffers before writers block.
+pub const PIPE_SIZE: usize = 4096;
+
+// The buffer behind the two ends `pipe` returns. A read takes what is there and blocks only while the pipe is
+// empty; a write stores what fits and blocks only while it is full. Once every write end is closed an empty pipe
+// reads as end of file, and once every read end is closed writes fail with EPIPE.
+#[derive(Debug, Clone)]
+pub struct Pipe { pub buf: Vec<u8>, pub readers: bool, pub writers: bool }
+
+impl Default for Pipe {
+    fn default() -> Self { Self { buf: Vec::new(), readers: true, writers: true } }
+}
+
+impl Device for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, u64> {
+        if self.buf.is_empty() && !buf.is_empty() { return if self.writers { Err(EAGAIN) } else { Ok(0) }; }
+        let n = buf.len().min(self.buf.len());
+        buf[..n].copy_from_slice(&self.buf[..n]); self.buf.drain(..n);
+        Ok(n)
+    }
+    fn write(&mut self, data: &[u8]) -> Result<usize, u64> {
+        if !self.readers { return Err(EPIPE); }
+        let n = data.len().min(PIPE_SIZE - self.buf.len());
+        if n == 0 && !data.is_empty() { return Err(EAGAIN); }
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+    fn release(&mut self, write: bool) { if write { self.writers = false; } else { self.readers = false; } }
+    fn type_bits(&self) -> u64 { S_IFIFO }
+    fn encode(&self) -> (&'static str, Vec<u8>) { let mut s = vec![self.readers as u8, self.writers as u8]; s.extend_from_slice(&self.buf); ("pipe", s) }
+    fn box_clone(&self) -> Box<dyn Device> { Box::new(self.clone()) }
+}
+
+// Rebuilds a driver from `Device::encode` output.
+pub fn decode(name: &str, state: &[u8]) -> Result<Box<dyn Device>, String> {
+    Ok(match name {
+        "stream" => { let (closed, buf) = state.split_first().ok_or("empty stream state")?; Box::new(Stream { buf: buf.to_vec(), closed: *closed != 0 }) }
+        "pipe" => { let [r, w, buf @ ..] = state else { return Err("short pipe state".into()) }; Box::new(Pipe { buf: buf.to_vec(), readers: *r != 0, writers: *w != 0 }) }
+        "null" => Box::new(Null),
+        "zero" => Box::new(Zero),
+        "urandom" => Box::new(Urandom { state: u64::from_le_bytes(state.try_into().map_err(|_| "bad urandom state")?) }),
+        "tty" => { let mut r = Reader { d: state, pos: 0 }; Box::new(Tty { input: r.get()?, output: r.get()? }) }
+        _ => return Err(format!("unknown device driver '{}'", name)),
+    })
+}
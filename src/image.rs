@@ -0,0 +1,135 @@
+use crate::snapshot::{fnv1a, put, put_bytes, Reader};
+use crate::vfs::{Kind, Vfs};
+use std::collections::{BTreeMap, HashMap};
+
+// --- VFS IMAGE FORMAT ---
+// "DVFI" | version u32 | varint entry count | entries | fnv1a(all preceding bytes) u64
+// entry: path | kind | mode | payload, with paths relative to the packed directory and entries sorted bytewise by
+// path, so parents always precede their children. Payloads: file = fnv1a(content) u64 + content, dir = nothing,
+// symlink = target, hard link = path of the earlier entry it shares an inode with. Inode numbers, mtimes and
+// mount flags are not stored, so the same tree packs to the same bytes however and whenever it was built.
+const MAGIC: &[u8; 4] = b"DVFI";
+const VERSION: u32 = 1;
+const FILE: u64 = 0;
+const DIR: u64 = 1;
+const SYMLINK: u64 = 2;
+const HARDLINK: u64 = 3;
+
+pub enum Entry { File(Vec<u8>), Dir, Symlink(String), Hardlink(String) }
+
+impl Vfs {
+    fn collect(&self, ino: u64, path: String, out: &mut Vec<(String, u64)>) {
+        if let Ok(Kind::Dir { entries, .. }) = self.node(ino).map(|n| &n.kind) {
+            for (name, i) in entries {
+                let p = if path.is_empty() { name.clone() } else { format!("{}/{}", path, name) };
+                out.push((p.clone(), *i));
+                self.collect(*i, p, out);
+            }
+        }
+    }
+
+    // Packs the directory at `guest` into an image. Character devices are skipped.
+    pub fn pack(&self, guest: &str) -> Result<Vec<u8>, String> {
+        let root = self.resolve(guest).map_err(|e| format!("{}: errno {}", guest, e))?;
+        let mut nodes = Vec::new();
+        self.collect(root, String::new(), &mut nodes);
+        nodes.sort();
+        let mut first: HashMap<u64, &str> = HashMap::new();
+        let mut entries = Vec::new();
+        for (path, ino) in &nodes {
+            let n = self.node(*ino).map_err(|_| "dangling inode")?;
+            if let Some(p) = first.get(ino) { entries.push((path, HARDLINK, n.mode, p.as_bytes().to_vec())); continue; }
+            let (kind, payload) = match &n.kind {
+                Kind::File(b) => (FILE, b.to_vec()),
+                Kind::Dir { .. } => (DIR, Vec::new()),
+                Kind::Symlink(t) => (SYMLINK, t.as_bytes().to_vec()),
+                Kind::CharDev(_) => continue,
+            };
+            if n.nlink > 1 { first.insert(*ino, path); }
+            entries.push((path, kind, n.mode, payload));
+        }
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        put(&mut out, entries.len() as u64);
+        for (path, kind, mode, payload) in entries {
+            put_bytes(&mut out, path.as_bytes()); put(&mut out, kind); put(&mut out, mode);
+            if kind == FILE { out.extend_from_slice(&fnv1a(&payload).to_le_bytes()); }
+            if kind != DIR { put_bytes(&mut out, &payload); }
+        }
+        let sum = fnv1a(&out);
+        out.extend_from_slice(&sum.to_le_bytes());
+        Ok(out)
+    }
+
+    // Recreates the image's tree under `guest` and returns the number of entries loaded.
+    pub fn unpack(&mut self, guest: &str, image: &[u8]) -> Result<usize, String> {
+        let entries = read_image(image)?;
+        let base = guest.trim_end_matches('/');
+        self.mkdir_all(base).map_err(|e| format!("{}: errno {}", guest, e))?;
+        for (path, mode, entry) in &entries {
+            let full = format!("{}/{}", base, path);
+            let errno = |e: u64| format!("{}: errno {}", full, e);
+            let ino = match entry {
+                Entry::Dir => self.mkdir_all(&full).map_err(errno)?,
+                Entry::File(d) => self.write_file(&full, d.clone()).map_err(errno)?,
+                Entry::Symlink(t) => self.symlink(t, &full).map_err(errno)?,
+                Entry::Hardlink(p) => { self.link(&format!("{}/{}", base, p), &full).map_err(errno)?; continue; }
+            };
+            self.node_mut(ino).map_err(errno)?.mode = *mode;
+        }
+        Ok(entries.len())
+    }
+}
+
+// Decodes and verifies an image: checksum, content hashes, modes, ordering, and that hard links point backwards.
+pub fn read_image(image: &[u8]) -> Result<Vec<(String, u64, Entry)>, String> {
+    if image.len() < 16 || &image[..4] != MAGIC { return Err("not a DVFI image".into()); }
+    let (body, sum) = image.split_at(image.len() - 8);
+    if fnv1a(body) != u64::from_le_bytes(sum.try_into().unwrap()) { return Err("image checksum mismatch".into()); }
+    let version = u32::from_le_bytes(body[4..8].try_into().unwrap());
+    if version != VERSION { return Err(format!("unsupported image version {}", version)); }
+    let mut r = Reader { d: body, pos: 8 };
+    let mut out: Vec<(String, u64, Entry)> = Vec::new();
+    for _ in 0..r.usize()? {
+        let path = r.string()?;
+        if path.is_empty() || path.split('/').any(|c| c.is_empty() || c == "." || c == "..") { return Err(format!("bad image path '{}'", path)); }
+        if out.last().is_some_and(|(p, _, _)| *p >= path) { return Err(format!("image entries out of order at '{}'", path)); }
+        let (kind, mode) = (r.get()?, r.get()?);
+        // only permission bits, as `Vfs::alloc` keeps them; the entry kind says what the inode is
+        if mode > 0o7777 { return Err(format!("bad mode {:o} for '{}'", mode, path)); }
+        let entry = match kind {
+            FILE => {
+                let hash = u64::from_le_bytes(r.take(8)?.try_into().unwrap());
+                let data = r.bytes()?;
+                if fnv1a(data) != hash { return Err(format!("content hash mismatch for '{}'", path)); }
+                Entry::File(data.to_vec())
+            }
+            DIR => Entry::Dir,
+            SYMLINK => Entry::Symlink(r.string()?),
+            HARDLINK => {
+                let target = r.string()?;
+                if !out.iter().any(|(p, _, e)| *p == target && matches!(e, Entry::File(_) | Entry::Symlink(_))) { return Err(format!("hard link '{}' to unknown entry '{}'", path, target)); }
+                Entry::Hardlink(target)
+            }
+            k => return Err(format!("unknown image entry kind {}", k)),
+        };
+        out.push((path, mode, entry));
+    }
+    if r.pos != body.len() { return Err("trailing bytes in image".into()); }
+    Ok(out)
+}
+
+// The text files under `dir` in an image, keyed by path relative to `dir`, in the form MiniCC takes its includes.
+pub fn include_map(image: &[u8], dir: &str) -> Result<HashMap<String, String>, String> {
+    let prefix = match dir.trim_matches('/') { "" => String::new(), d => format!("{}/", d) };
+    let mut files: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    for (path, _, entry) in read_image(image)? {
+        let Some(rel) = path.strip_prefix(&prefix) else { continue };
+        match entry {
+            Entry::File(d) => { files.insert(rel.to_string(), d); }
+            Entry::Hardlink(t) => if let Some(d) = t.strip_prefix(&prefix).and_then(|t| files.get(t)).cloned() { files.insert(rel.to_string(), d); },
+            _ => {}
+        }
+    }
+    files.into_iter().map(|(k, v)| String::from_utf8(v).map(|s| (k.clone(), s)).map_err(|_| format!("{}{} is not UTF-8 text", prefix, k))).collect()
+}
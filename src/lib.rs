@@ -1,5 +1,19 @@
 use wasm_bindgen::prelude::*;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+pub mod coverage;
+pub mod debugger;
+pub mod chunk;
+pub mod debuginfo;
+pub mod device;
+pub mod image;
+pub mod process;
+pub mod profiler;
+pub mod snapshot;
+pub mod syscall;
+pub mod trace;
+pub mod vfs;
 
 pub const SYSTEM_STATUS: &str = "\
 \x1b[36m================================================================================
@@ -11,38 +25,120 @@ Status: VM Upgrade [INDIRECT CALLS FIXED] Active.
 ";
 
 // --- PREPROCESSOR ---
-fn preprocess(src: &str, vfs: &HashMap<String, String>, processed_files: &mut Vec<String>) -> String {
-    let mut macros = HashMap::new();
-    let mut result = Vec::new();
-    for line in src.lines() {
-        let trimmed = line.trim();
-        if trimmed.starts_with("#include") {
-            let start = trimmed.find(|c| c == '"' || c == '<');
-            let end = trimmed.rfind(|c| c == '"' || c == '>');
-            if let (Some(s), Some(e)) = (start, end) {
-                if s < e {
-                    let filename = &trimmed[s+1..e];
-                    if !processed_files.contains(&filename.to_string()) {
-                        processed_files.push(filename.to_string());
-                        if let Some(file_content) = vfs.get(filename) {
-                            let included = preprocess(file_content, vfs, processed_files);
-                            result.push(included);
+// Macros are shared across #include boundaries, as in C, so constants defined by a header reach the includer.
+// `origins` receives the (file, 1-based line) of every emitted line, in output order. Function-like macros map
+// to their parameter names and body; a use and its arguments must sit on one line.
+type FnMacro = (Vec<String>, String);
+struct Preprocessor<'a> { vfs: &'a dyn vfs::FileSystem, processed_files: Vec<String>, origins: Vec<(String, usize)>, macros: HashMap<String, String>, fn_macros: HashMap<String, FnMacro> }
+
+impl Preprocessor<'_> {
+    // "x.h" is looked up next to the including file first; then both forms try /usr/include/x.h and plain x.h.
+    fn locate(&self, name: &str, quoted: bool, from: &str) -> Option<(String, Vec<u8>)> {
+        let dir = from.rsplit_once('/').map(|(d, _)| d);
+        let local = dir.filter(|_| quoted).map(|d| format!("{}/{}", d, name));
+        [local, Some(format!("/usr/include/{}", name)), Some(name.to_string())].into_iter().flatten()
+            .find_map(|p| self.vfs.read_file(&p).map(|c| (p, c)))
+    }
+
+    fn run(&mut self, src: &str, file: &str) -> String {
+        let mut result = Vec::new();
+        for (n, line) in src.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.starts_with("#include") {
+                let start = trimmed.find(['"', '<']);
+                let end = trimmed.rfind(['"', '>']);
+                if let (Some(s), Some(e)) = (start, end) {
+                    if let Some((path, content)) = (s < e).then(|| self.locate(&trimmed[s+1..e], trimmed.as_bytes()[s] == b'"', file)).flatten() {
+                        if !self.processed_files.contains(&path) {
+                            self.processed_files.push(path.clone());
+                            let before = self.origins.len();
+                            let included = self.run(&String::from_utf8_lossy(&content), &path);
+                            if self.origins.len() > before { result.push(included); }
                         }
                     }
                 }
+                continue;
             }
-            continue;
+            if let Some(rest) = trimmed.strip_prefix("#define") {
+                let rest = rest.trim_start();
+                let name_len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                if let (true, Some(close)) = (rest[name_len..].starts_with('('), rest.find(')')) {
+                    let params = rest[name_len + 1..close].split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+                    self.fn_macros.insert(rest[..name_len].to_string(), (params, rest[close + 1..].trim().to_string()));
+                    continue;
+                }
+                let parts: Vec<&str> = trimmed.split_whitespace().collect();
+                if parts.len() >= 3 { self.macros.insert(parts[1].to_string(), parts[2..].join(" ")); }
+                continue;
+            }
+            result.push(self.expand(line));
+            self.origins.push((file.to_string(), n + 1));
         }
-        if trimmed.starts_with("#define") {
-            let parts: Vec<&str> = trimmed.split_whitespace().collect();
-            if parts.len() >= 3 { macros.insert(parts[1].to_string(), parts[2..].join(" ")); }
-            continue;
+        result.join("\n")
+    }
+
+    // Replaces whole identifiers only, outside string and character literals.
+    fn expand(&self, line: &str) -> String { self.expand_nested(line, 0) }
+
+    // A function-like macro's expansion is expanded again, to a fixed depth in case one refers to itself.
+    fn expand_nested(&self, line: &str, depth: usize) -> String {
+        if self.macros.is_empty() && self.fn_macros.is_empty() { return line.to_string(); }
+        let mut out = String::new(); let mut chars = line.chars().peekable(); let mut quote = None;
+        while let Some(c) = chars.next() {
+            if let Some(q) = quote { out.push(c); if c == '\\' { if let Some(n) = chars.next() { out.push(n); } } else if c == q { quote = None; } continue; }
+            if c == '"' || c == '\'' { quote = Some(c); out.push(c); continue; }
+            if c.is_alphabetic() || c == '_' {
+                let mut w = String::from(c);
+                while let Some(&nc) = chars.peek() { if nc.is_alphanumeric() || nc == '_' { w.push(nc); chars.next(); } else { break; } }
+                if let Some((params, body)) = self.fn_macros.get(&w).filter(|_| depth < 16) {
+                    let mut ahead = chars.clone();
+                    while ahead.peek().is_some_and(|c| c.is_whitespace()) { ahead.next(); }
+                    if ahead.next() == Some('(') {
+                        chars = ahead;
+                        let args = macro_args(&mut chars);
+                        let text = replace_idents(body, |id| params.iter().position(|p| p == id).map(|i| args.get(i).cloned().unwrap_or_default()));
+                        out.push_str(&self.expand_nested(&text, depth + 1));
+                        continue;
+                    }
+                }
+                out.push_str(self.macros.get(&w).unwrap_or(&w));
+            } else { out.push(c); }
+        }
+        out
+    }
+}
+
+// The comma-separated arguments of a macro use, consuming its closing parenthesis.
+fn macro_args(chars: &mut std::iter::Peekable<std::str::Chars>) -> Vec<String> {
+    let (mut args, mut cur, mut depth, mut quote) = (Vec::new(), String::new(), 0, None);
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote { cur.push(c); if c == '\\' { if let Some(n) = chars.next() { cur.push(n); } } else if c == q { quote = None; } continue; }
+        match c {
+            '"' | '\'' => { quote = Some(c); cur.push(c); }
+            '(' => { depth += 1; cur.push(c); }
+            ')' if depth == 0 => break,
+            ')' => { depth -= 1; cur.push(c); }
+            ',' if depth == 0 => args.push(std::mem::take(&mut cur)),
+            _ => cur.push(c),
         }
-        let mut processed = line.to_string();
-        for (name, val) in &macros { processed = processed.replace(name, val); }
-        result.push(processed);
     }
-    result.join("\n")
+    args.push(cur);
+    args.into_iter().map(|a| a.trim().to_string()).collect()
+}
+
+// `text` with each identifier `f` maps to Some(..) replaced, leaving literals alone.
+fn replace_idents(text: &str, f: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new(); let mut chars = text.chars().peekable(); let mut quote = None;
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote { out.push(c); if c == '\\' { if let Some(n) = chars.next() { out.push(n); } } else if c == q { quote = None; } continue; }
+        if c == '"' || c == '\'' { quote = Some(c); out.push(c); continue; }
+        if c.is_alphabetic() || c == '_' {
+            let mut w = String::from(c);
+            while let Some(&nc) = chars.peek() { if nc.is_alphanumeric() || nc == '_' { w.push(nc); chars.next(); } else { break; } }
+            out.push_str(&f(&w).unwrap_or(w));
+        } else { out.push(c); }
+    }
+    out
 }
 
 // --- LEXER ---
@@ -50,32 +146,44 @@ fn preprocess(src: &str, vfs: &HashMap<String, String>, processed_files: &mut Ve
 enum Token {
     Int, Char, Struct, If, Else, While, Return, Syscall, Sizeof,
     Ident(String), Num(u64), StrLit(String),
-    Plus, Minus, Mul, Div, Assign, Lt, Gt, Eq, Arrow, Dot,
+    Plus, Minus, Mul, Div, Mod, Assign, Lt, Gt, Eq, Ne, Arrow, Dot, Ellipsis,
     LParen, RParen, LBrace, RBrace, LBracket, RBracket,
-    Ampersand, Semicolon, Comma, EOF
+    Ampersand, Semicolon, Comma, Eof
 }
 
-fn lex(src: &str) -> Vec<Token> {
-    let mut tokens = Vec::new();
+// Returns the tokens and, for each token, the index of the source line it starts on.
+fn lex(src: &str) -> (Vec<Token>, Vec<usize>) {
+    let mut tokens = Vec::new(); let mut lines = Vec::new(); let mut line = 0;
     let mut chars = src.chars().peekable();
     while let Some(c) = chars.next() {
+        let start_line = line;
         match c {
-            ' ' | '\n' | '\t' | '\r' => continue,
+            '\n' => { line += 1; continue; }
+            ' ' | '\t' | '\r' => continue,
             '{' => tokens.push(Token::LBrace), '}' => tokens.push(Token::RBrace),
             '(' => tokens.push(Token::LParen), ')' => tokens.push(Token::RParen),
             '[' => tokens.push(Token::LBracket), ']' => tokens.push(Token::RBracket),
             ';' => tokens.push(Token::Semicolon), ',' => tokens.push(Token::Comma),
-            '.' => tokens.push(Token::Dot), '+' => tokens.push(Token::Plus),
+            '.' => { let mut ahead = chars.clone(); if ahead.next() == Some('.') && ahead.next() == Some('.') { chars.nth(1); tokens.push(Token::Ellipsis); } else { tokens.push(Token::Dot); } }
+            '+' => tokens.push(Token::Plus), '%' => tokens.push(Token::Mod),
             '-' => if chars.peek() == Some(&'>') { chars.next(); tokens.push(Token::Arrow); } else { tokens.push(Token::Minus); },
             '*' => tokens.push(Token::Mul), '/' => tokens.push(Token::Div),
             '&' => tokens.push(Token::Ampersand), '<' => tokens.push(Token::Lt), '>' => tokens.push(Token::Gt),
             '=' => if chars.peek() == Some(&'=') { chars.next(); tokens.push(Token::Eq); } else { tokens.push(Token::Assign); },
+            '!' if chars.peek() == Some(&'=') => { chars.next(); tokens.push(Token::Ne); }
+            // Character constants are numbers: 'a', '\n', '\0', '\\', '\''.
+            '\'' => {
+                let c = match chars.next() { Some('\\') => match chars.next() { Some('n') => '\n', Some('t') => '\t', Some('r') => '\r', Some('0') => '\0', Some(e) => e, None => '\0' }, Some(c) => c, None => '\0' };
+                if chars.peek() == Some(&'\'') { chars.next(); }
+                tokens.push(Token::Num(c as u64));
+            }
             '"' => {
                 let mut s = String::new();
                 while let Some(&nc) = chars.peek() { if nc == '"' { chars.next(); break; } s.push(chars.next().unwrap()); }
+                line += s.matches('\n').count();
                 tokens.push(Token::StrLit(s));
             }
-            _ if c.is_alphabetic() => {
+            _ if c.is_alphabetic() || c == '_' => {
                 let mut s = String::from(c);
                 while let Some(&nc) = chars.peek() { if nc.is_alphanumeric() || nc == '_' { s.push(chars.next().unwrap()); } else { break; } }
                 match s.as_str() {
@@ -93,9 +201,10 @@ fn lex(src: &str) -> Vec<Token> {
             }
             _ => {}
         }
+        lines.resize(tokens.len(), start_line);
     }
-    tokens.push(Token::EOF);
-    tokens
+    tokens.push(Token::Eof); lines.push(line);
+    (tokens, lines)
 }
 
 // --- AST & DATA STRUCTURES ---
@@ -109,44 +218,88 @@ enum Expr {
 
 #[derive(Clone)] struct VarInfo { offset: usize, is_array: bool, stride: usize } 
 #[derive(Clone)] struct GlobalInfo { offset: usize, is_array: bool, stride: usize }
-#[derive(Clone)] struct StructField { offset: usize, size: usize }
+#[derive(Clone)] struct StructField { offset: usize }
 #[derive(Clone)] struct StructDef { size: usize, fields: HashMap<String, StructField> }
 
+// Type spelling for `.local` directives; spaces would split the directive, so `struct node*` is `struct:node*`.
+fn type_name(t: &Token, struct_name: Option<&str>, ptrs: usize, count: Option<usize>) -> String {
+    let mut s = match (t, struct_name) { (Token::Char, _) => "char".to_string(), (Token::Struct, Some(n)) => format!("struct:{}", n), _ => "int".to_string() };
+    for _ in 0..ptrs { s.push('*'); }
+    if let Some(n) = count { s.push_str(&format!("[{}]", n)); }
+    s
+}
+
 // --- COMPILER ---
+// Variadic calls pass exactly this many arguments after the named ones, padding with zeros, so a variadic
+// function finds them in fixed frame slots right after its last named parameter (see stdarg.h).
+pub const VA_MAX: usize = 16;
+
 pub struct MiniCC {
     tokens: Vec<Token>, pos: usize, 
     locals: HashMap<String, VarInfo>, local_offset: usize,
-    globals: HashMap<String, GlobalInfo>, global_offset: usize, 
+    globals: HashMap<String, GlobalInfo>, 
     structs: HashMap<String, StructDef>,
     label_count: usize, 
     pub data: Vec<u8>, out: String,
+    // String literal -> its address in the read-only pages at the start of the data segment.
+    strings: HashMap<String, usize>,
+    // Variadic function -> how many named parameters it has.
+    variadic: HashMap<String, usize>,
+    // Debug info: token -> preprocessed line -> (file, line), emitted as `.file`/`.loc`/`.local` directives.
+    token_lines: Vec<usize>, origins: Vec<(String, usize)>, files: Vec<String>, last_loc: Option<usize>, func_name: String,
+    // Parameters `main` declares, i.e. how many of argc, argv, envp the startup code passes it.
+    main_arity: usize,
+    // Whether the program defines `exit`, which the startup code then calls with main's result.
+    has_exit: bool,
 }
 
 impl MiniCC {
-    pub fn new(source: &str, host_vfs: &HashMap<String, String>) -> Self { 
-        let mut processed_files = Vec::new();
-        let preprocessed_src = preprocess(source, host_vfs, &mut processed_files);
+    pub fn new(source: &str, host_vfs: &dyn vfs::FileSystem) -> Self { Self::new_named("main.c", source, host_vfs) }
+
+    // `file` is the name line-table entries use for lines of `source` itself.
+    pub fn new_named(file: &str, source: &str, host_vfs: &dyn vfs::FileSystem) -> Self { 
+        let mut pp = Preprocessor { vfs: host_vfs, processed_files: Vec::new(), origins: Vec::new(), macros: HashMap::new(), fn_macros: HashMap::new() };
+        let preprocessed_src = pp.run(source, file);
+        let origins = pp.origins;
+        let (tokens, token_lines) = lex(&preprocessed_src);
+        let mut files: Vec<String> = Vec::new();
+        for (f, _) in &origins { if !files.contains(f) { files.push(f.clone()); } }
         Self { 
-            tokens: lex(&preprocessed_src), pos: 0, 
+            tokens, pos: 0, 
             locals: HashMap::new(), local_offset: 0, 
-            globals: HashMap::new(), global_offset: 2048, 
+            globals: HashMap::new(), 
             structs: HashMap::new(), label_count: 0, 
-            data: Vec::new(), out: String::new() 
+            data: Vec::new(), out: String::new(), strings: HashMap::new(), variadic: HashMap::new(),
+            token_lines, origins, files, last_loc: None, func_name: String::new(), main_arity: 0, has_exit: false,
         } 
     }
+
+    // Emits a `.loc` for the current token's source line when it differs from the last one emitted.
+    fn mark_line(&mut self) {
+        let Some(&idx) = self.token_lines.get(self.pos) else { return };
+        if self.last_loc == Some(idx) { return; }
+        if let Some((file, line)) = self.origins.get(idx) {
+            let id = self.files.iter().position(|f| f == file).unwrap_or(0);
+            self.out.push_str(&format!(".loc {} {}\n", id, line));
+            self.last_loc = Some(idx);
+        }
+    }
+
+    fn declare_local(&mut self, name: &str, offset: usize, ty: String) { self.out.push_str(&format!(".local {} {} {} {}\n", self.func_name, name, offset, ty)); }
     
-    fn peek(&self) -> Token { if self.pos < self.tokens.len() { self.tokens[self.pos].clone() } else { Token::EOF } }
-    fn consume(&mut self) -> Token { let t = self.peek(); if t != Token::EOF { self.pos += 1; } t }
+    fn peek(&self) -> Token { if self.pos < self.tokens.len() { self.tokens[self.pos].clone() } else { Token::Eof } }
+    fn consume(&mut self) -> Token { let t = self.peek(); if t != Token::Eof { self.pos += 1; } t }
     fn new_label(&mut self) -> String { self.label_count += 1; format!("L{}", self.label_count) }
 
     fn parse_expr(&mut self) -> Expr { self.parse_eq() }
-    fn parse_eq(&mut self) -> Expr { let mut left = self.parse_rel(); if self.peek() == Token::Eq { self.consume(); left = Expr::Binary(Box::new(left), Token::Eq, Box::new(self.parse_rel())); } left }
-    fn parse_rel(&mut self) -> Expr { let mut left = self.parse_sum(); loop { match self.peek() { Token::Lt | Token::Gt => { let op = self.consume(); left = Expr::Binary(Box::new(left), op, Box::new(self.parse_sum())); } _ => break, } } left }
-    fn parse_sum(&mut self) -> Expr { let mut left = self.parse_term(); loop { match self.peek() { Token::Plus | Token::Minus => { let op = self.consume(); left = Expr::Binary(Box::new(left), op, Box::new(self.parse_term())); } _ => break, } } left }
-    fn parse_term(&mut self) -> Expr { let mut left = self.parse_unary(); loop { match self.peek() { Token::Mul | Token::Div => { let op = self.consume(); left = Expr::Binary(Box::new(left), op, Box::new(self.parse_unary())); } _ => break, } } left }
+    fn parse_eq(&mut self) -> Expr { let mut left = self.parse_rel(); if let Token::Eq | Token::Ne = self.peek() { let op = self.consume(); left = Expr::Binary(Box::new(left), op, Box::new(self.parse_rel())); } left }
+    fn parse_rel(&mut self) -> Expr { let mut left = self.parse_sum(); while let Token::Lt | Token::Gt = self.peek() { let op = self.consume(); left = Expr::Binary(Box::new(left), op, Box::new(self.parse_sum())); } left }
+    fn parse_sum(&mut self) -> Expr { let mut left = self.parse_term(); while let Token::Plus | Token::Minus = self.peek() { let op = self.consume(); left = Expr::Binary(Box::new(left), op, Box::new(self.parse_term())); } left }
+    fn parse_term(&mut self) -> Expr { let mut left = self.parse_unary(); while let Token::Mul | Token::Div | Token::Mod = self.peek() { let op = self.consume(); left = Expr::Binary(Box::new(left), op, Box::new(self.parse_unary())); } left }
     fn parse_unary(&mut self) -> Expr { 
         match self.peek() { 
             Token::Mul => { self.consume(); Expr::Deref(Box::new(self.parse_unary())) } 
+            Token::Minus => { self.consume(); Expr::Binary(Box::new(Expr::Number(0)), Token::Minus, Box::new(self.parse_unary())) }
             Token::Ampersand => { self.consume(); if let Token::Ident(name) = self.consume() { Expr::AddrOf(name) } else { panic!(); } } 
             _ => self.parse_postfix(), 
         } 
@@ -160,7 +313,7 @@ impl MiniCC {
                     self.consume(); 
                     if let Token::Ident(field) = self.consume() { 
                         let mut found_offset = None; 
-                        for (_, def) in &self.structs { if let Some(f) = def.fields.get(&field) { found_offset = Some(f.offset); break; } } 
+                        for def in self.structs.values() { if let Some(f) = def.fields.get(&field) { found_offset = Some(f.offset); break; } } 
                         if let Some(off) = found_offset { left = Expr::MemberAccess(Box::new(left), off); } else { panic!(); } 
                     } else { panic!(); } 
                 },
@@ -201,13 +354,23 @@ impl MiniCC {
     }
 
     pub fn compile(&mut self) -> String {
-        self.out.push_str("CALL main\nHALT\n");
+        for (i, f) in self.files.iter().enumerate() { self.out.push_str(&format!(".file {} {}\n", i, f)); }
         let saved_pos = self.pos;
-        while self.peek() != Token::EOF { if self.peek() == Token::Struct { self.compile_struct_def(); } else { self.consume(); } }
+        while self.peek() != Token::Eof { if self.peek() == Token::Struct { self.compile_struct_def(); } else { self.consume(); } }
         self.pos = saved_pos;
-        while self.peek() != Token::EOF {
+        // String literals go first, once each, in pages of their own that `load` maps read-only; globals start on the next page.
+        for t in &self.tokens { if let Token::StrLit(s) = t { if !self.strings.contains_key(s) { self.strings.insert(s.clone(), DATA_BASE + self.data.len()); self.data.extend_from_slice(s.as_bytes()); self.data.push(0); } } }
+        if !self.data.is_empty() { self.out.push_str(&format!(".rodata {}\n", self.data.len())); self.data.resize(self.data.len().next_multiple_of(PAGE_SIZE), 0); }
+        // Callers must know which functions are variadic wherever they are defined.
+        for (i, _) in self.tokens.iter().enumerate().filter(|(_, t)| **t == Token::Ellipsis) {
+            let open = (0..i).rev().find(|j| self.tokens[*j] == Token::LParen).unwrap_or(0);
+            if let Some(Token::Ident(name)) = open.checked_sub(1).map(|j| &self.tokens[j]) {
+                self.variadic.insert(name.clone(), self.tokens[open..i].iter().filter(|t| **t == Token::Comma).count());
+            }
+        }
+        while self.peek() != Token::Eof {
             match self.peek() {
-                Token::Struct => { self.consume(); self.consume(); self.consume(); while self.peek() != Token::RBrace && self.peek() != Token::EOF { self.consume(); } self.consume(); self.consume(); },
+                Token::Struct => { self.consume(); self.consume(); self.consume(); while self.peek() != Token::RBrace && self.peek() != Token::Eof { self.consume(); } self.consume(); self.consume(); },
                 Token::Int | Token::Char => {
                     let mut is_func = false; let mut temp_pos = self.pos + 1; 
                     while temp_pos < self.tokens.len() { match &self.tokens[temp_pos] { Token::Mul => temp_pos += 1, Token::Ident(_) => { if temp_pos + 1 < self.tokens.len() && self.tokens[temp_pos+1] == Token::LParen { is_func = true; } break; } _ => break, } }
@@ -216,6 +379,11 @@ impl MiniCC {
                 _ => { self.consume(); }
             }
         }
+        // Startup: fetch as many of argc, argv, envp as main takes (syscall 27), call it, and halt with its result,
+        // passing it through exit first when the program has one so atexit handlers run.
+        let mut start: String = (0..self.main_arity.min(3)).map(|i| format!("PUSH {}\nPUSH 27\nSYSCALL\n", i)).collect();
+        start.push_str(if self.has_exit { "CALL main\nCALL exit\nHALT\n" } else { "CALL main\nHALT\n" });
+        self.out.insert_str(0, &start);
         self.out.clone()
     }
 
@@ -228,7 +396,7 @@ impl MiniCC {
             while self.peek() == Token::Mul { self.consume(); sz = 8; }
             let fname = if let Token::Ident(s) = self.consume() { s } else { panic!() };
             if self.peek() == Token::LBracket { self.consume(); if let Token::Num(n) = self.consume() { sz *= n as usize; } self.consume(); }
-            self.consume(); fields.insert(fname, StructField { offset: current_offset, size: sz }); current_offset += sz;
+            self.consume(); fields.insert(fname, StructField { offset: current_offset }); current_offset += sz;
         }
         self.consume(); self.consume(); self.structs.insert(name, StructDef { size: current_offset, fields });
     }
@@ -236,58 +404,67 @@ impl MiniCC {
     fn compile_global(&mut self) {
         let type_token = self.consume(); 
         let mut stride = 8; if type_token == Token::Char { stride = 1; }
-        while self.peek() == Token::Mul { self.consume(); stride = 1; }
-        if type_token == Token::Int { stride = 8; }
+        let mut ptrs = 0; while self.peek() == Token::Mul { self.consume(); stride = 1; ptrs += 1; }
+        if type_token == Token::Int || ptrs > 1 { stride = 8; }
         let name = if let Token::Ident(s) = self.consume() { s } else { panic!() };
         let mut size = 8; let mut is_arr = false;
         if self.peek() == Token::LBracket { self.consume(); if let Token::Num(n) = self.consume() { size = n as usize * stride; } self.consume(); is_arr = true; }
-        self.globals.insert(name, GlobalInfo { offset: self.global_offset, is_array: is_arr, stride }); self.global_offset += size; self.consume();
+        // Globals live zero-initialised in the data segment, so they never collide with code however large it grows.
+        self.data.resize(self.data.len().next_multiple_of(8), 0);
+        self.globals.insert(name, GlobalInfo { offset: DATA_BASE + self.data.len(), is_array: is_arr, stride }); self.data.resize(self.data.len() + size, 0); self.consume();
     }
 
     fn compile_func(&mut self) {
         self.consume(); while self.peek() == Token::Mul { self.consume(); }
         let name = if let Token::Ident(s) = self.consume() { s } else { panic!() };
         self.consume(); self.out.push_str(&format!("{}:\n", name)); self.locals.clear(); self.local_offset = 0;
+        self.func_name = name; self.last_loc = None; self.mark_line();
         let mut param_offsets = Vec::new();
         if self.peek() != Token::RParen { 
             loop { 
+                if self.peek() == Token::Ellipsis { self.consume(); for _ in 0..VA_MAX { param_offsets.push(self.local_offset); self.local_offset += 8; } break; }
                 let type_token = self.consume(); let mut stride = 8; if type_token == Token::Char { stride = 1; }
-                while self.peek() == Token::Mul { self.consume(); stride = 1; }
-                if type_token == Token::Int { stride = 8; }
+                let mut ptrs = 0; while self.peek() == Token::Mul { self.consume(); stride = 1; ptrs += 1; }
+                if type_token == Token::Int || ptrs > 1 { stride = 8; }
                 let pname = if let Token::Ident(s) = self.consume() { s } else { panic!() }; 
+                self.declare_local(&pname, self.local_offset, type_name(&type_token, None, ptrs, None));
                 self.locals.insert(pname.clone(), VarInfo { offset: self.local_offset, is_array: false, stride }); 
                 param_offsets.push(self.local_offset); self.local_offset += 8; 
                 if self.peek() == Token::Comma { self.consume(); } else { break; } 
             } 
         }
         self.consume(); self.consume(); 
+        if self.func_name == "main" { self.main_arity = param_offsets.len(); }
+        if self.func_name == "exit" { self.has_exit = true; }
         for off in param_offsets.into_iter().rev() { self.out.push_str(&format!("LSTORE {}\n", off)); }
-        while self.peek() != Token::RBrace && self.peek() != Token::EOF { self.compile_stmt(); } 
-        self.consume(); self.out.push_str("PUSH 0\nRET\n");
+        while self.peek() != Token::RBrace && self.peek() != Token::Eof { self.compile_stmt(); } 
+        self.mark_line(); self.consume(); self.out.push_str("PUSH 0\nRET\n");
     }
 
     fn compile_stmt(&mut self) {
+        self.mark_line();
         match self.peek() {
             Token::Int | Token::Char | Token::Struct => {
                 let type_token = self.consume(); let mut stride = 8; if type_token == Token::Char { stride = 1; }
-                if type_token == Token::Struct { self.consume(); } 
-                while self.peek() == Token::Mul { self.consume(); stride = 1; }
-                if type_token == Token::Int { stride = 8; }
+                let mut struct_name = None; if type_token == Token::Struct { if let Token::Ident(n) = self.consume() { struct_name = Some(n); } } 
+                let mut ptrs = 0; while self.peek() == Token::Mul { self.consume(); stride = 1; ptrs += 1; }
+                if type_token == Token::Int || ptrs > 1 { stride = 8; }
                 let name = if let Token::Ident(s) = self.consume() { s } else { panic!() };
-                let mut sz = 8; let mut is_arr = false;
-                if self.peek() == Token::LBracket { self.consume(); if let Token::Num(n) = self.consume() { sz = n as usize * stride; } self.consume(); is_arr = true; }
+                let mut sz = 8; let mut is_arr = false; let mut count = None;
+                if self.peek() == Token::LBracket { self.consume(); if let Token::Num(n) = self.consume() { sz = n as usize * stride; count = Some(n as usize); } self.consume(); is_arr = true; }
+                self.declare_local(&name, self.local_offset, type_name(&type_token, struct_name.as_deref(), ptrs, count));
                 self.locals.insert(name.clone(), VarInfo { offset: self.local_offset, is_array: is_arr, stride });
                 if self.peek() == Token::Assign { self.consume(); let expr = self.parse_expr(); self.gen_expr(expr); self.out.push_str(&format!("LSTORE {}\n", self.local_offset)); }
                 self.local_offset += sz; self.consume();
             }
             Token::Return => { self.consume(); let expr = self.parse_expr(); self.gen_expr(expr); self.out.push_str("RET\n"); self.consume(); }
-            Token::If => { self.consume(); self.consume(); let cond = self.parse_expr(); self.consume(); let l_false = self.new_label(); self.gen_expr(cond); self.out.push_str(&format!("JZ {}\n", l_false)); self.consume(); while self.peek() != Token::RBrace && self.peek() != Token::EOF { self.compile_stmt(); } self.consume(); if self.peek() == Token::Else { self.consume(); let l_end = self.new_label(); self.out.push_str(&format!("JMP {}\n{}:\n", l_end, l_false)); self.consume(); while self.peek() != Token::RBrace && self.peek() != Token::EOF { self.compile_stmt(); } self.consume(); self.out.push_str(&format!("{}:\n", l_end)); } else { self.out.push_str(&format!("{}:\n", l_false)); } }
-            Token::While => { self.consume(); self.consume(); let cond = self.parse_expr(); self.consume(); let l_start = self.new_label(); let l_end = self.new_label(); self.out.push_str(&format!("{}:\n", l_start)); self.gen_expr(cond); self.out.push_str(&format!("JZ {}\n", l_end)); self.consume(); while self.peek() != Token::RBrace && self.peek() != Token::EOF { self.compile_stmt(); } self.consume(); self.out.push_str(&format!("JMP {}\n{}:\n", l_start, l_end)); }
+            Token::If => { self.consume(); self.consume(); let cond = self.parse_expr(); self.consume(); let l_false = self.new_label(); self.gen_expr(cond); self.out.push_str(&format!("JZ {}\n", l_false)); self.consume(); while self.peek() != Token::RBrace && self.peek() != Token::Eof { self.compile_stmt(); } self.consume(); if self.peek() == Token::Else { self.consume(); let l_end = self.new_label(); self.out.push_str(&format!("JMP {}\n{}:\n", l_end, l_false)); self.consume(); while self.peek() != Token::RBrace && self.peek() != Token::Eof { self.compile_stmt(); } self.consume(); self.out.push_str(&format!("{}:\n", l_end)); } else { self.out.push_str(&format!("{}:\n", l_false)); } }
+            Token::While => { self.consume(); self.consume(); let cond = self.parse_expr(); self.consume(); let l_start = self.new_label(); let l_end = self.new_label(); self.out.push_str(&format!("{}:\n", l_start)); self.gen_expr(cond); self.out.push_str(&format!("JZ {}\n", l_end)); self.consume(); while self.peek() != Token::RBrace && self.peek() != Token::Eof { self.compile_stmt(); } self.consume(); self.out.push_str(&format!("JMP {}\n{}:\n", l_start, l_end)); }
             Token::Syscall => { let expr = self.parse_expr(); self.gen_expr(expr); self.out.push_str("POP\n"); self.consume(); }
             // Handles: Identifier assignments, direct calls, array access assignments
             Token::Ident(s) => {
                 self.consume(); let mut lhs = None;
-                if self.peek() == Token::Arrow { self.consume(); let field = if let Token::Ident(f) = self.consume() { f } else { panic!() }; let mut off = 0; for (_, d) in &self.structs { if let Some(f) = d.fields.get(&field) { off = f.offset; break; } } lhs = Some(Expr::MemberAccess(Box::new(Expr::Variable(s.clone())), off)); }
+                if self.peek() == Token::Arrow { self.consume(); let field = if let Token::Ident(f) = self.consume() { f } else { panic!() }; let mut off = 0; for d in self.structs.values() { if let Some(f) = d.fields.get(&field) { off = f.offset; break; } } lhs = Some(Expr::MemberAccess(Box::new(Expr::Variable(s.clone())), off)); }
                 else if self.peek() == Token::LBracket { self.consume(); let idx = self.parse_expr(); let mut stride = 8; if let Some(l) = self.locals.get(&s) { stride = l.stride; } else if let Some(g) = self.globals.get(&s) { stride = g.stride; } lhs = Some(Expr::ArrayAccess(Box::new(Expr::Variable(s.clone())), Box::new(idx), stride)); self.consume(); }
                 
                 if let Some(l) = lhs { 
@@ -311,7 +488,7 @@ impl MiniCC {
                     // Inline parse_postfix logic
                     loop {
                         match self.peek() {
-                            Token::Arrow => { self.consume(); if let Token::Ident(field) = self.consume() { let mut found_offset = None; for (_, def) in &self.structs { if let Some(f) = def.fields.get(&field) { found_offset = Some(f.offset); break; } } if let Some(off) = found_offset { left = Expr::MemberAccess(Box::new(left), off); } } },
+                            Token::Arrow => { self.consume(); if let Token::Ident(field) = self.consume() { let mut found_offset = None; for def in self.structs.values() { if let Some(f) = def.fields.get(&field) { found_offset = Some(f.offset); break; } } if let Some(off) = found_offset { left = Expr::MemberAccess(Box::new(left), off); } } },
                             Token::LBracket => { self.consume(); let index = self.parse_expr(); left = Expr::ArrayAccess(Box::new(left), Box::new(index), 8); self.consume(); },
                             Token::LParen => { self.consume(); let mut args = Vec::new(); if self.peek() != Token::RParen { loop { args.push(self.parse_expr()); if self.peek() == Token::Comma { self.consume(); } else { break; } } } self.consume(); left = Expr::Call(Box::new(left), args); },
                             _ => break,
@@ -348,14 +525,17 @@ impl MiniCC {
     fn gen_expr(&mut self, expr: Expr) {
         match expr {
             Expr::Number(n) => self.out.push_str(&format!("PUSH {}\n", n)),
-            Expr::StringLit(s) => { let addr = 8192 + self.data.len(); self.data.extend_from_slice(s.as_bytes()); self.data.push(0); self.out.push_str(&format!("PUSH {}\n", addr)); }
+            Expr::StringLit(s) => { let addr = self.strings[&s]; self.out.push_str(&format!("PUSH {}\n", addr)); }
             Expr::Variable(s) => { if let Some(i) = self.locals.get(&s) { if i.is_array { self.out.push_str("GETBP\n"); self.out.push_str(&format!("PUSH {}\nADD\n", i.offset)); } else { self.out.push_str(&format!("LLOAD {}\n", i.offset)); } } else if let Some(i) = self.globals.get(&s) { if i.is_array { self.out.push_str(&format!("PUSH {}\n", i.offset)); } else { self.out.push_str(&format!("PUSH {}\nMLOAD\n", i.offset)); } } }
             Expr::MemberAccess(base, off) => { self.gen_expr(*base); self.out.push_str(&format!("PUSH {}\nADD\nMLOAD\n", off)); }
             Expr::ArrayAccess(base, idx, strd) => { self.gen_expr(*base); self.gen_expr(*idx); self.out.push_str(&format!("PUSH {}\nMUL\nADD\n", strd)); if strd == 1 { self.out.push_str("MLOAD8\n"); } else { self.out.push_str("MLOAD\n"); } }
             Expr::AddrOf(s) => { if let Some(i) = self.locals.get(&s) { self.out.push_str("GETBP\n"); self.out.push_str(&format!("PUSH {}\nADD\n", i.offset)); } else if let Some(i) = self.globals.get(&s) { self.out.push_str(&format!("PUSH {}\n", i.offset)); } else { self.out.push_str(&format!("PUSH {}\n", s)); } }
             Expr::Deref(e) => { self.gen_expr(*e); self.out.push_str("MLOAD\n"); }
             Expr::Call(func, args) => { 
+                let named = if let Expr::Variable(ref name) = *func { self.variadic.get(name).copied() } else { None };
+                let n = args.len();
                 for arg in args { self.gen_expr(arg); }
+                if let Some(named) = named { assert!(n <= named + VA_MAX, "too many arguments to a variadic function"); for _ in n..named + VA_MAX { self.out.push_str("PUSH 0\n"); } }
                 let mut is_direct = false;
                 if let Expr::Variable(ref name) = *func {
                     if !self.locals.contains_key(name) && !self.globals.contains_key(name) {
@@ -370,22 +550,69 @@ impl MiniCC {
                 }
             }
             Expr::Syscall(args) => { for arg in args.into_iter().rev() { self.gen_expr(arg); } self.out.push_str("SYSCALL\n"); }
-            Expr::Binary(l, op, r) => { self.gen_expr(*l); self.gen_expr(*r); match op { Token::Plus => self.out.push_str("ADD\n"), Token::Minus => self.out.push_str("SUB\n"), Token::Eq => { self.out.push_str("SUB\nNOT\n"); } Token::Lt => self.out.push_str("LT\n"), Token::Gt => self.out.push_str("GT\n"), _ => {} } }
+            Expr::Binary(l, op, r) => { self.gen_expr(*l); self.gen_expr(*r); match op { Token::Plus => self.out.push_str("ADD\n"), Token::Minus => self.out.push_str("SUB\n"), Token::Mul => self.out.push_str("MUL\n"), Token::Div => self.out.push_str("DIV\n"), Token::Mod => self.out.push_str("MOD\n"), Token::Eq => { self.out.push_str("SUB\nNOT\n"); } Token::Ne => { self.out.push_str("SUB\nNOT\nNOT\n"); } Token::Lt => self.out.push_str("LT\n"), Token::Gt => self.out.push_str("GT\n"), _ => {} } }
         }
     }
 }
 
 // --- ASSEMBLER & VM ---
+// Mnemonic and whether a u64 immediate follows, for every opcode the VM executes.
+pub fn opcode_info(op: u8) -> Option<(&'static str, bool)> {
+    Some(match op {
+        0x00 => ("HALT", false), 0x10 => ("PUSH", true), 0x11 => ("POP", false),
+        0x20 => ("ADD", false), 0x21 => ("SUB", false), 0x22 => ("MUL", false), 0x23 => ("DIV", false), 0x24 => ("NOT", false), 0x25 => ("LT", false), 0x26 => ("GT", false), 0x27 => ("MOD", false),
+        0x30 => ("JMP", true), 0x31 => ("JZ", true), 0x40 => ("CALL", true), 0x41 => ("ICALL", false), 0x42 => ("RET", false), 0x50 => ("GETBP", false),
+        0x60 => ("LLOAD", true), 0x61 => ("LSTORE", true), 0x62 => ("MLOAD", false), 0x63 => ("MSTORE", false),
+        0x70 => ("MLOAD8", false), 0x71 => ("MSTORE8", false), 0x80 => ("SYSCALL", false),
+        _ => return None,
+    })
+}
+
+// Label addresses from an assembly listing. Compiler-generated `L<n>` jump targets are kept in `labels`
+// but excluded from `functions`, which is sorted by address for `function_at` lookups.
+#[derive(Clone, Default)]
+pub struct SymbolMap { pub labels: BTreeMap<String, usize>, pub functions: Vec<(usize, String)> }
+
+impl SymbolMap {
+    pub fn addr(&self, name: &str) -> Option<usize> { self.labels.get(name).copied() }
+    pub fn function_at(&self, addr: usize) -> Option<(&str, usize)> {
+        let i = self.functions.partition_point(|(a, _)| *a <= addr);
+        if i == 0 { None } else { let (a, n) = &self.functions[i - 1]; Some((n.as_str(), *a)) }
+    }
+    pub fn describe(&self, addr: usize) -> String {
+        match self.function_at(addr) { Some((n, a)) if addr == a => n.to_string(), Some((n, a)) => format!("{}+{}", n, addr - a), None => format!("{}", addr) }
+    }
+}
+
 pub struct Assembler;
 impl Assembler {
-    pub fn compile_bef(source: &str, data: &[u8]) -> Vec<u8> {
-        let tokens: Vec<&str> = source.split_whitespace().collect();
-        let mut labels = HashMap::new(); let mut addr = 0;
-        for t in tokens.iter() { 
+    // Operand count of the debug-info directives, which emit no code.
+    fn directive_args(t: &str) -> usize { match t { ".rodata" => 1, ".file" | ".loc" => 2, ".local" => 4, _ => 0 } }
+
+    fn layout(tokens: &[&str]) -> HashMap<String, usize> {
+        let mut labels = HashMap::new(); let mut addr = CODE_BASE; let mut i = 0;
+        while i < tokens.len() { 
+            let t = tokens[i];
             if t.ends_with(':') { labels.insert(t.trim_end_matches(':').to_string(), addr); } 
-            else { addr += match *t { "PUSH"|"JMP"|"JZ"|"LLOAD"|"LSTORE"|"CALL" => 9, "ICALL"|"HALT"|"ADD"|"SUB"|"MUL"|"DIV"|"LT"|"GT"|"RET"|"GETBP"|"MLOAD"|"MSTORE"|"MLOAD8"|"MSTORE8"|"NOT"|"SYSCALL"|"POP" => 1, _ => 0 }; } 
+            else { addr += match t { "PUSH"|"JMP"|"JZ"|"LLOAD"|"LSTORE"|"CALL" => 9, "ICALL"|"HALT"|"ADD"|"SUB"|"MUL"|"DIV"|"MOD"|"LT"|"GT"|"RET"|"GETBP"|"MLOAD"|"MSTORE"|"MLOAD8"|"MSTORE8"|"NOT"|"SYSCALL"|"POP" => 1, _ => 0 }; } 
+            i += 1 + Self::directive_args(t);
         }
-        let mut code = Vec::new(); let mut i = 0;
+        labels
+    }
+
+    pub fn symbols(source: &str) -> SymbolMap {
+        let tokens: Vec<&str> = source.split_whitespace().collect();
+        let labels: BTreeMap<String, usize> = Self::layout(&tokens).into_iter().collect();
+        let is_local = |n: &str| n.len() > 1 && n.starts_with('L') && n[1..].bytes().all(|b| b.is_ascii_digit());
+        let mut functions: Vec<(usize, String)> = labels.iter().filter(|(n, _)| !is_local(n)).map(|(n, a)| (*a, n.clone())).collect();
+        functions.sort();
+        SymbolMap { labels, functions }
+    }
+
+    pub fn compile_bef(source: &str, data: &[u8]) -> Vec<u8> {
+        let tokens: Vec<&str> = source.split_whitespace().collect();
+        let labels = Self::layout(&tokens);
+        let mut code = Vec::new(); let mut i = 0; let mut dbg = debuginfo::DebugInfo::default(); let mut rodata = 0u32;
         while i < tokens.len() {
             match tokens[i] {
                 "HALT" => code.push(0x00), "ICALL" => code.push(0x41),
@@ -394,7 +621,7 @@ impl Assembler {
                     let val = tokens[i].parse::<u64>().unwrap_or_else(|_| *labels.get(tokens[i]).unwrap_or(&0) as u64);
                     code.extend_from_slice(&val.to_le_bytes()); 
                 }
-                "POP" => code.push(0x11), "ADD" => code.push(0x20), "SUB" => code.push(0x21), "MUL" => code.push(0x22), "NOT" => code.push(0x24), "LT" => code.push(0x25), "GT" => code.push(0x26), 
+                "POP" => code.push(0x11), "ADD" => code.push(0x20), "SUB" => code.push(0x21), "MUL" => code.push(0x22), "DIV" => code.push(0x23), "MOD" => code.push(0x27), "NOT" => code.push(0x24), "LT" => code.push(0x25), "GT" => code.push(0x26), 
                 "JMP" => { code.push(0x30); i+=1; code.extend_from_slice(&(labels[tokens[i]] as u64).to_le_bytes()); } 
                 "JZ" => { code.push(0x31); i+=1; code.extend_from_slice(&(labels[tokens[i]] as u64).to_le_bytes()); } 
                 "CALL" => { code.push(0x40); i+=1; code.extend_from_slice(&(labels[tokens[i]] as u64).to_le_bytes()); } 
@@ -402,133 +629,1014 @@ impl Assembler {
                 "LLOAD" => { code.push(0x60); i+=1; code.extend_from_slice(&tokens[i].parse::<u64>().unwrap().to_le_bytes()); } 
                 "LSTORE" => { code.push(0x61); i+=1; code.extend_from_slice(&tokens[i].parse::<u64>().unwrap().to_le_bytes()); } 
                 "MLOAD" => code.push(0x62), "MSTORE" => code.push(0x63), "MLOAD8" => code.push(0x70), "MSTORE8" => code.push(0x71), "SYSCALL" => code.push(0x80), 
+                ".file" => { let id: usize = tokens[i+1].parse().unwrap(); if dbg.files.len() <= id { dbg.files.resize(id + 1, String::new()); } dbg.files[id] = tokens[i+2].to_string(); }
+                ".rodata" => rodata = tokens[i+1].parse().unwrap(),
+                ".loc" => dbg.lines.push((CODE_BASE + code.len(), tokens[i+1].parse().unwrap(), tokens[i+2].parse().unwrap())),
+                ".local" => dbg.locals.push(debuginfo::LocalVar { func: tokens[i+1].to_string(), name: tokens[i+2].to_string(), offset: tokens[i+3].parse().unwrap(), ty: tokens[i+4].replace(':', " ") }),
                 _ => {}
             }
-            i += 1;
+            i += 1 + Self::directive_args(tokens[i]);
         }
         let mut bin = vec![0u8; 16]; 
         bin[0..4].copy_from_slice(&0xB111E7u32.to_le_bytes()); 
+        bin[4..8].copy_from_slice(&rodata.to_le_bytes());
         bin[8..12].copy_from_slice(&(code.len() as u32).to_le_bytes()); 
         bin.extend(code); 
         while bin.len() < 8192 { bin.push(0); } 
         bin.extend(data); 
+        if !dbg.is_empty() { let section = dbg.encode(); bin[12..16].copy_from_slice(&(section.len() as u32).to_le_bytes()); bin.extend(section); }
         bin
     }
 }
 
+// --- TRAPS & GAS ---
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrapKind { InvalidOpcode(u8), StackUnderflow, StackOverflow, CallDepthExceeded, FrameOverflow(usize), DivideByZero, MemoryFault(usize), ProtectionFault(usize, Access), BadSyscall(u64), Deadlock }
+
+// The kind of access a page's permissions refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access { Read, Write, Execute }
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self { Access::Read => "read", Access::Write => "write", Access::Execute => "execute" })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trap { pub ip: usize, pub kind: TrapKind }
+
+impl std::fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TrapKind::InvalidOpcode(op) => write!(f, "invalid opcode 0x{:02X}", op),
+            TrapKind::StackUnderflow => write!(f, "operand stack underflow"),
+            TrapKind::StackOverflow => write!(f, "operand stack overflow"),
+            TrapKind::CallDepthExceeded => write!(f, "call depth limit exceeded"),
+            TrapKind::FrameOverflow(a) => write!(f, "frame region overflow at {}", a),
+            TrapKind::DivideByZero => write!(f, "division by zero"),
+            TrapKind::MemoryFault(a) => write!(f, "memory fault at {}", a),
+            TrapKind::ProtectionFault(a, access) if *a < PAGE_SIZE => write!(f, "protection fault: {} at {} (null pointer)", access, a),
+            TrapKind::ProtectionFault(a, access) => write!(f, "protection fault: {} at {}", access, a),
+            TrapKind::BadSyscall(n) => write!(f, "unknown syscall {}", n),
+            TrapKind::Deadlock => write!(f, "deadlock: every process is blocked on another process"),
+        }
+    }
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, "TRAP @ {}: {}", self.ip, self.kind) }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome { Halted(u64), OutOfGas, Trapped(Trap), WaitingForInput(u64) }
+
+// Per-opcode cost table. Unknown opcodes still cost 1 so a trap is charged like any other step.
+#[derive(Clone)]
+pub struct GasTable { pub costs: [u64; 256] }
+
+impl Default for GasTable {
+    fn default() -> Self {
+        let mut costs = [1u64; 256];
+        costs[0x00] = 0; // HALT
+        for op in [0x22, 0x23, 0x27] { costs[op] = 3; } // MUL DIV MOD
+        for op in [0x40, 0x41, 0x42] { costs[op] = 4; } // CALL ICALL RET
+        for op in [0x60, 0x61, 0x62, 0x63, 0x70, 0x71] { costs[op] = 2; } // LLOAD LSTORE MLOAD MSTORE MLOAD8 MSTORE8
+        costs[0x80] = 20; // SYSCALL
+        Self { costs }
+    }
+}
+
+impl GasTable {
+    pub fn cost(&self, op: u8) -> u64 { self.costs[op as usize] }
+}
+
+// Bounds on a program's stacks, each enforced with its own trap: operand stack entries (StackOverflow), nested
+// calls (CallDepthExceeded), and bytes of frames above where `load` starts them (FrameOverflow). Frames also
+// stop at the argv block below the heap, whatever `frame_bytes` says. `file_bytes` caps how far write, pwrite
+// and ftruncate may grow a regular file; past it they fail with EFBIG.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits { pub stack_depth: usize, pub call_depth: usize, pub frame_bytes: usize, pub file_bytes: usize }
+
+impl Default for Limits {
+    fn default() -> Self { Self { stack_depth: 64 * 1024, call_depth: 8 * 1024, frame_bytes: 256 * 1024, file_bytes: 64 << 20 } }
+}
+
+// The most each limit has been used, across every process since the machine was created; for tuning `Limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HighWater { pub stack_depth: usize, pub call_depth: usize, pub frame_bytes: usize }
+
+// An open file description. Descriptors duplicated with dup/dup2 point at the same one and share its offset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenFile { pub ino: u64, pub pos: usize, pub flags: u64, pub refs: usize }
+
+// Bytes `set_args` may use for argv and envp, pointers included.
+pub const ARG_MAX: usize = 64 * 1024;
+
+// --- ADDRESS SPACE ---
+// A program's memory, page by page: the zero page, mapped with no access so NULL dereferences and calls trap;
+// code at CODE_BASE, execute-only; at DATA_BASE the string literals, read-only, then globals; frames from the
+// first page after the data; argv and envp just below HEAP_BASE; and the heap, which sbrk grows (and memory
+// with it) up to MEMORY_MAX. A BEF file keeps its own layout, code at offset 16 and data at `bef_data_offset`;
+// `load` maps it.
+pub const PAGE_SIZE: usize = 4096;
+pub const CODE_BASE: usize = PAGE_SIZE;
+pub const DATA_BASE: usize = 64 * 1024;
+pub const CODE_MAX: usize = DATA_BASE - CODE_BASE;
+pub const HEAP_BASE: usize = 512 * 1024;
+pub const MEMORY_MAX: usize = 64 * 1024 * 1024;
+// Page permission bits, one byte per page in `Machine::perms`.
+pub const PROT_READ: u8 = 1;
+pub const PROT_WRITE: u8 = 2;
+pub const PROT_EXEC: u8 = 4;
+
+// The bytes `set_args` needs for these strings and their pointer arrays.
+pub fn args_size(argv: &[&str], envp: &[&str]) -> usize {
+    let strings: usize = argv.iter().chain(envp).map(|a| a.len() + 1).sum();
+    (strings.div_ceil(8) + argv.len() + envp.len() + 2) * 8
+}
+
+// Data starts at file offset 8192, or straight after code too long to fit below it.
+pub fn bef_data_offset(code_len: usize) -> usize { (16 + code_len).max(8192) }
+
+// Header checks for a BEF image: magic, code and debug sections that fit the file, and data that fits below the heap.
+pub fn is_bef(bef: &[u8]) -> bool {
+    let field = |at: usize| bef.get(at..at + 4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()) as usize);
+    let (code, debug) = (field(8), field(12));
+    field(0) == 0xB111E7 && code <= CODE_MAX && 16 + code <= bef.len() && debug <= bef.len()
+        && (bef.len() - debug).saturating_sub(bef_data_offset(code)) <= HEAP_BASE - DATA_BASE
+}
+
+// Cloning shares file contents with the original (see chunk.rs); only memory and metadata are copied.
+#[derive(Clone)]
 pub struct Machine {
     pub memory: Vec<u8>, pub stack: Vec<u64>, pub call_stack: Vec<(usize, usize)>, 
+    // PROT_* bits for each page of `memory`.
+    pub perms: Vec<u8>,
     pub ip: usize, pub bp: usize, pub sp: usize, 
-    pub vfs: HashMap<String, Vec<u8>>, pub fds: HashMap<u64, (String, usize)>, pub next_fd: u64, 
+    // Where `load` started the frames; `Limits::frame_bytes` counts from here.
+    pub frame_base: usize,
+    pub limits: Limits, pub high_water: HighWater,
+    // fd -> open file description id -> OpenFile
+    pub vfs: vfs::Vfs, pub fds: BTreeMap<u64, u64>, pub open_files: BTreeMap<u64, OpenFile>, 
     pub brk: usize,
+    pub gas: u64, pub gas_used: u64, pub gas_table: GasTable, pub halted: bool,
+    // Set by the exit syscall; `clock` is the deterministic wall time returned by the time syscall.
+    pub exit_status: Option<u64>, pub clock: u64,
+    // argc and the argv and envp pointers laid out by `set_args`, handed to the startup code by syscall 27.
+    pub startup: [u64; 3],
+    // The running process and the parked ones; see process.rs. `slice` counts instructions since the last switch.
+    pub pid: u64, pub ppid: u64, pub procs: BTreeMap<u64, process::Process>, pub next_pid: u64,
+    pub quantum: u64, pub slice: u64,
+    // Why the last step's syscall blocked (a read on an fd, or waitpid); `run` parks the process or returns
+    // `RunOutcome::WaitingForInput`.
+    pub(crate) blocked: Option<process::ProcState>,
+    pub(crate) trace_buf: Option<trace::StepEvent>,
+}
+
+impl Default for Machine {
+    fn default() -> Self { Self::new() }
 }
 
 impl Machine {
     pub fn new() -> Self { 
-        let mut vfs = HashMap::new(); vfs.insert("/dev/stdin".to_string(), Vec::new()); vfs.insert("/dev/stdout".to_string(), Vec::new()); 
-        let mut fds = HashMap::new(); fds.insert(0, ("/dev/stdin".to_string(), 0)); fds.insert(1, ("/dev/stdout".to_string(), 0)); 
-        Self { memory: vec![0; 1024 * 1024], stack: vec![], call_stack: vec![], ip: 0, bp: 4096, sp: 4096, vfs, fds, next_fd: 3, brk: 512 * 1024 } 
+        let mut vfs = vfs::Vfs::new();
+        let _ = vfs.mkdir_all("/tmp"); let _ = vfs.mkdir_all("/dev");
+        let mut dev = |path: &str, d: Box<dyn device::Device>, mode: u64| vfs.create(path, vfs::Kind::CharDev(d), mode).unwrap();
+        let stdin = dev("/dev/stdin", Box::<device::Stream>::default(), 0o444);
+        let stdout = dev("/dev/stdout", Box::<device::Stream>::default(), 0o222);
+        let stderr = dev("/dev/stderr", Box::<device::Stream>::default(), 0o222);
+        dev("/dev/tty", Box::new(device::Tty { input: stdin, output: stdout }), 0o666);
+        dev("/dev/null", Box::new(device::Null), 0o666);
+        dev("/dev/zero", Box::new(device::Zero), 0o666);
+        dev("/dev/urandom", Box::new(device::Urandom { state: 0 }), 0o444);
+        let fds = BTreeMap::from([(0, 0), (1, 1), (2, 2)]);
+        let open_files = BTreeMap::from([
+            (0, OpenFile { ino: stdin, pos: 0, flags: syscall::O_RDONLY, refs: 1 }),
+            (1, OpenFile { ino: stdout, pos: 0, flags: syscall::O_WRONLY, refs: 1 }),
+            (2, OpenFile { ino: stderr, pos: 0, flags: syscall::O_WRONLY, refs: 1 }),
+        ]);
+        let mut m = Self { memory: vec![0; HEAP_BASE], stack: vec![], call_stack: vec![], perms: Self::default_perms(HEAP_BASE), ip: CODE_BASE, bp: DATA_BASE, sp: DATA_BASE, frame_base: DATA_BASE, limits: Limits::default(), high_water: HighWater::default(), vfs, fds, open_files, brk: HEAP_BASE, gas: 0, gas_used: 0, gas_table: GasTable::default(), halted: false, exit_status: None, clock: 0, startup: [0; 3], pid: process::INIT_PID, ppid: 0, procs: BTreeMap::new(), next_pid: process::INIT_PID + 1, quantum: process::DEFAULT_QUANTUM, slice: 0, blocked: None, trace_buf: None };
+        let _ = m.set_args(&[], &[]);
+        m
     }
+    // Before `load`, every page but the zero page is readable, writable and executable, so hand-assembled code
+    // poked into memory runs as it is.
+    pub(crate) fn default_perms(len: usize) -> Vec<u8> {
+        let mut perms = vec![PROT_READ | PROT_WRITE | PROT_EXEC; len / PAGE_SIZE]; perms[0] = 0; perms
+    }
+
     pub fn load(&mut self, d: &[u8]) { 
-        let sz = u32::from_le_bytes(d[8..12].try_into().unwrap()) as usize; self.memory[0..sz].copy_from_slice(&d[16..16+sz]); 
-        if d.len() > 8192 { self.memory[8192..8192+(d.len()-8192)].copy_from_slice(&d[8192..]); } 
+        let field = |at: usize| u32::from_le_bytes(d[at..at + 4].try_into().unwrap()) as usize;
+        let sz = field(8); self.memory[CODE_BASE..CODE_BASE + sz].copy_from_slice(&d[16..16+sz]); 
+        let end = d.len() - field(12); // strip the debug section
+        let at = bef_data_offset(sz); let data = end.saturating_sub(at);
+        if data > 0 { self.memory[DATA_BASE..DATA_BASE + data].copy_from_slice(&d[at..end]); } 
+        let rodata = DATA_BASE + field(4).next_multiple_of(PAGE_SIZE);
+        for (p, perm) in self.perms.iter_mut().enumerate() {
+            let a = p * PAGE_SIZE;
+            *perm = if a < CODE_BASE { 0 } else if a < DATA_BASE { PROT_EXEC } else if a < rodata { PROT_READ } else { PROT_READ | PROT_WRITE };
+        }
+        // Frames start on the page after the data, so they never overwrite it.
+        self.bp = (DATA_BASE + data).next_multiple_of(PAGE_SIZE); self.sp = self.bp; self.frame_base = self.bp;
+    }
+
+    // Grows or shrinks memory to `len` bytes (a multiple of PAGE_SIZE); new pages are zeroed and read-write.
+    pub(crate) fn resize_memory(&mut self, len: usize) {
+        self.memory.resize(len, 0); self.perms.resize(len / PAGE_SIZE, PROT_READ | PROT_WRITE);
+    }
+
+    // Loads a BEF image from the machine's own VFS, e.g. one that `build_file` or a guest program wrote there.
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let bef = self.vfs.get(path).ok_or_else(|| format!("{}: no such file", path))?;
+        if !is_bef(&bef) { return Err(format!("{}: not a BEF image", path)); }
+        self.load(&bef);
+        Ok(())
+    }
+
+    // Adds fuel without running; the next `run` continues from the exact instruction that ran dry.
+    pub fn refuel(&mut self, amount: u64) { self.gas = self.gas.saturating_add(amount); }
+
+    // --- HOST I/O ---
+    // Queues bytes for /dev/stdin; a run that stopped with `WaitingForInput` picks them up when resumed.
+    pub fn push_stdin(&mut self, bytes: &[u8]) {
+        if let Ok(d) = self.vfs.resolve("/dev/stdin").and_then(|ino| self.vfs.device(ino, true)) { let _ = d.write(bytes); }
+    }
+
+    // Ends stdin: once the queued bytes are consumed, reads return 0.
+    pub fn close_stdin(&mut self) {
+        if let Ok(d) = self.vfs.resolve("/dev/stdin").and_then(|ino| self.vfs.device(ino, true)) { d.hangup(); }
+    }
+
+    // Takes whatever the program has written to the device behind `fd` (1 stdout, 2 stderr) since the last
+    // drain. Empty when `fd` is closed or refers to a regular file.
+    pub fn drain_output(&mut self, fd: u64) -> Vec<u8> {
+        let Some(ino) = self.fds.get(&fd).and_then(|id| self.open_files.get(id)).map(|f| f.ino) else { return Vec::new() };
+        self.vfs.device(ino, true).map(|d| d.drain()).unwrap_or_default()
     }
-    pub fn step(&mut self) -> Result<bool, String> {
-        let op = self.memory[self.ip]; self.ip += 1;
+
+    // Restarts /dev/urandom from `seed`; the same seed always yields the same bytes.
+    pub fn seed_random(&mut self, seed: u64) {
+        if let Ok(ino) = self.vfs.resolve("/dev/urandom") { if let Ok(n) = self.vfs.node_mut(ino) { n.kind = vfs::Kind::CharDev(Box::new(device::Urandom { state: seed })); } }
+    }
+
+    // --- PROCESS STARTUP ---
+    // Lays out argv and envp just below the heap the way a Unix kernel lays out the initial stack: the strings,
+    // NUL-terminated, in the last bytes, and below them the NULL-terminated argv and envp pointer arrays (8-byte
+    // aligned). A `main(int argc, char** argv, char** envp)` receives them through syscall 27; main may declare
+    // fewer parameters. Call after `load` and before the first `run`.
+    pub fn set_args(&mut self, argv: &[&str], envp: &[&str]) -> Result<(), String> {
+        let size = args_size(argv, envp);
+        if size > ARG_MAX { return Err(format!("arguments and environment take {} bytes, more than ARG_MAX ({})", size, ARG_MAX)); }
+        let base = HEAP_BASE - size;
+        let (mut ptr, mut text) = (base, base + (argv.len() + envp.len() + 2) * 8);
+        for list in [argv, envp] {
+            for a in list {
+                self.memory[text..text + a.len()].copy_from_slice(a.as_bytes()); self.memory[text + a.len()] = 0;
+                self.memory[ptr..ptr + 8].copy_from_slice(&(text as u64).to_le_bytes());
+                text += a.len() + 1; ptr += 8;
+            }
+            self.memory[ptr..ptr + 8].fill(0); ptr += 8;
+        }
+        self.startup = [argv.len() as u64, base as u64, (base + (argv.len() + 1) * 8) as u64];
+        Ok(())
+    }
+
+    // The status the program exited with, truncated to 8 bits as a Unix parent would see it: the argument to
+    // exit() or main's return value. None until the run halts.
+    pub fn exit_code(&self) -> Option<u8> { self.halted.then(|| self.exit_status.unwrap_or(0) as u8) }
+
+    // Runs until the program halts, traps, or the next instruction costs more than the remaining gas.
+    // Gas is checked before an instruction executes, so an OutOfGas machine is never mid-instruction. Child
+    // processes share the budget; their exits and traps are reported to waitpid, not returned here.
+    pub fn run(&mut self, budget: u64) -> RunOutcome { self.run_traced(budget, None) }
+
+    // `run` with every executed instruction reported to `tracer`.
+    pub fn run_traced(&mut self, budget: u64, mut tracer: Option<&mut dyn trace::Tracer>) -> RunOutcome {
+        self.refuel(budget); self.blocked = None; self.wake_readers();
+        loop {
+            if self.halted { return RunOutcome::Halted(self.exit_status.unwrap_or(0)); }
+            let op = match self.fetch_u8(self.ip) { Ok(op) => op, Err(kind) => match self.schedule(Err(Trap { ip: self.ip, kind })) { Some(o) => return o, None => continue } };
+            let cost = self.gas_table.cost(op);
+            if cost > self.gas { return RunOutcome::OutOfGas; }
+            self.gas -= cost; self.gas_used += cost;
+            let r = match tracer.as_deref_mut() { Some(t) => self.step_traced(t), None => self.step() };
+            // A blocked syscall is rewound to its SYSCALL and refunded, so gas does not depend on how input arrives.
+            if self.blocked.is_some() { self.gas += cost; self.gas_used -= cost; }
+            if let Some(o) = self.schedule(r) { return o; }
+        }
+    }
+
+    // Returning from main halts with its result, as if it had called exit.
+    pub(crate) fn halt(&mut self) {
+        self.halted = true;
+        if self.exit_status.is_none() { self.exit_status = Some(self.stack.last().copied().unwrap_or(0)); }
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<u64, TrapKind> { self.stack.pop().ok_or(TrapKind::StackUnderflow) }
+    pub(crate) fn push(&mut self, v: u64) -> Result<(), TrapKind> {
+        if self.stack.len() >= self.limits.stack_depth { return Err(TrapKind::StackOverflow); }
+        self.stack.push(v); self.high_water.stack_depth = self.high_water.stack_depth.max(self.stack.len()); Ok(())
+    }
+    fn call(&mut self, dest: usize) -> Result<(), TrapKind> {
+        if self.call_stack.len() >= self.limits.call_depth { return Err(TrapKind::CallDepthExceeded); }
+        self.call_stack.push((self.ip, self.bp)); self.bp = self.sp; self.ip = dest;
+        self.high_water.call_depth = self.high_water.call_depth.max(self.call_stack.len()); Ok(())
+    }
+    // Stores a local at bp+off, growing the frame region up to its limit.
+    fn store_local(&mut self, off: usize, v: u64) -> Result<(), TrapKind> {
+        let target = self.bp.wrapping_add(off); let end = target.saturating_add(8);
+        if end > self.sp && (end > self.frame_base.saturating_add(self.limits.frame_bytes) || end > self.startup[1] as usize) { return Err(TrapKind::FrameOverflow(target)); }
+        self.write_u64(target, v)?;
+        if end > self.sp { self.sp = end; self.high_water.frame_bytes = self.high_water.frame_bytes.max(end.saturating_sub(self.frame_base)); }
+        Ok(())
+    }
+    // The range `a..a+n` if it lies in memory and every page it touches allows `access`.
+    pub(crate) fn check_access(&self, a: usize, n: usize, access: Access) -> Result<std::ops::Range<usize>, TrapKind> {
+        let e = a.checked_add(n).filter(|e| *e <= self.memory.len()).ok_or(TrapKind::MemoryFault(a))?;
+        let bit = match access { Access::Read => PROT_READ, Access::Write => PROT_WRITE, Access::Execute => PROT_EXEC };
+        match (a / PAGE_SIZE..e.max(a + 1).div_ceil(PAGE_SIZE)).find(|p| self.perms[*p] & bit == 0) {
+            Some(p) => Err(TrapKind::ProtectionFault(a.max(p * PAGE_SIZE), access)),
+            None => Ok(a..e),
+        }
+    }
+    pub(crate) fn read_u64(&self, a: usize) -> Result<u64, TrapKind> {
+        let r = self.check_access(a, 8, Access::Read)?; Ok(u64::from_le_bytes(self.memory[r].try_into().unwrap()))
+    }
+    pub(crate) fn write_u64(&mut self, a: usize, v: u64) -> Result<(), TrapKind> {
+        let r = self.check_access(a, 8, Access::Write)?; self.memory[r].copy_from_slice(&v.to_le_bytes()); self.record_write(a, 8); Ok(())
+    }
+    pub(crate) fn read_u8(&self, a: usize) -> Result<u8, TrapKind> { let r = self.check_access(a, 1, Access::Read)?; Ok(self.memory[r.start]) }
+    pub(crate) fn write_u8(&mut self, a: usize, v: u8) -> Result<(), TrapKind> { let r = self.check_access(a, 1, Access::Write)?; self.memory[r.start] = v; self.record_write(a, 1); Ok(()) }
+    // Instruction fetches need execute permission rather than read.
+    fn fetch_u8(&self, a: usize) -> Result<u8, TrapKind> { let r = self.check_access(a, 1, Access::Execute)?; Ok(self.memory[r.start]) }
+    fn imm(&mut self) -> Result<u64, TrapKind> { let r = self.check_access(self.ip, 8, Access::Execute)?; let v = u64::from_le_bytes(self.memory[r].try_into().unwrap()); self.ip += 8; Ok(v) }
+
+    // Executes one instruction. On a trap `ip` is left at the faulting instruction.
+    pub fn step(&mut self) -> Result<bool, Trap> {
+        let at = self.ip;
+        self.exec().map_err(|kind| { self.ip = at; Trap { ip: at, kind } })
+    }
+
+    fn exec(&mut self) -> Result<bool, TrapKind> {
+        let op = self.fetch_u8(self.ip)?; self.ip += 1;
         match op {
             0x00 => return Ok(false), 
-            0x10 => { self.stack.push(u64::from_le_bytes(self.memory[self.ip..self.ip+8].try_into().unwrap())); self.ip += 8; } 
-            0x11 => { self.stack.pop(); } 
-            0x20 => { let b = self.stack.pop().unwrap(); let a = self.stack.pop().unwrap(); self.stack.push(a.wrapping_add(b)); } 
-            0x21 => { let b = self.stack.pop().unwrap(); let a = self.stack.pop().unwrap(); self.stack.push(a.wrapping_sub(b)); } 
-            0x22 => { let b = self.stack.pop().unwrap(); let a = self.stack.pop().unwrap(); self.stack.push(a.wrapping_mul(b)); } 
-            0x24 => { let a = self.stack.pop().unwrap(); self.stack.push(if a == 0 { 1 } else { 0 }); } 
-            0x25 => { let b = self.stack.pop().unwrap(); let a = self.stack.pop().unwrap(); self.stack.push(if a < b { 1 } else { 0 }); } 
-            0x26 => { let b = self.stack.pop().unwrap(); let a = self.stack.pop().unwrap(); self.stack.push(if a > b { 1 } else { 0 }); } 
-            0x30 => { self.ip = u64::from_le_bytes(self.memory[self.ip..self.ip+8].try_into().unwrap()) as usize; } 
-            0x31 => { let dest = u64::from_le_bytes(self.memory[self.ip..self.ip+8].try_into().unwrap()) as usize; self.ip += 8; if self.stack.pop().unwrap() == 0 { self.ip = dest; } } 
-            0x40 => { let d = u64::from_le_bytes(self.memory[self.ip..self.ip+8].try_into().unwrap()) as usize; self.call_stack.push((self.ip + 8, self.bp)); self.bp = self.sp; self.ip = d; } 
-            0x41 => { let d = self.stack.pop().unwrap() as usize; self.call_stack.push((self.ip, self.bp)); self.bp = self.sp; self.ip = d; }
+            0x10 => { let v = self.imm()?; self.push(v)?; } 
+            0x11 => { self.pop()?; } 
+            0x20 => { let b = self.pop()?; let a = self.pop()?; self.push(a.wrapping_add(b))?; } 
+            0x21 => { let b = self.pop()?; let a = self.pop()?; self.push(a.wrapping_sub(b))?; } 
+            0x22 => { let b = self.pop()?; let a = self.pop()?; self.push(a.wrapping_mul(b))?; } 
+            // Division is unsigned, like the comparisons.
+            0x23 => { let b = self.pop()?; let a = self.pop()?; if b == 0 { return Err(TrapKind::DivideByZero); } self.push(a / b)?; } 
+            0x27 => { let b = self.pop()?; let a = self.pop()?; if b == 0 { return Err(TrapKind::DivideByZero); } self.push(a % b)?; } 
+            0x24 => { let a = self.pop()?; self.push(if a == 0 { 1 } else { 0 })?; } 
+            0x25 => { let b = self.pop()?; let a = self.pop()?; self.push(if a < b { 1 } else { 0 })?; } 
+            0x26 => { let b = self.pop()?; let a = self.pop()?; self.push(if a > b { 1 } else { 0 })?; } 
+            0x30 => { self.ip = self.imm()? as usize; } 
+            0x31 => { let dest = self.imm()? as usize; if self.pop()? == 0 { self.ip = dest; } } 
+            0x40 => { let d = self.imm()? as usize; self.call(d)?; } 
+            0x41 => { let d = self.pop()? as usize; self.call(d)?; }
             0x42 => { if let Some((ri, ob)) = self.call_stack.pop() { self.sp = self.bp; self.bp = ob; self.ip = ri; } else { return Ok(false); } } 
-            0x50 => { self.stack.push(self.bp as u64); } 
-            0x60 => { let off = u64::from_le_bytes(self.memory[self.ip..self.ip+8].try_into().unwrap()) as usize; self.ip += 8; self.stack.push(u64::from_le_bytes(self.memory[self.bp+off..self.bp+off+8].try_into().unwrap())); } 
-            0x61 => { let off = u64::from_le_bytes(self.memory[self.ip..self.ip+8].try_into().unwrap()) as usize; self.ip += 8; let v = self.stack.pop().unwrap(); let target = self.bp + off; self.memory[target..target+8].copy_from_slice(&v.to_le_bytes()); if target + 8 > self.sp { self.sp = target + 8; } } 
-            0x62 => { let a = self.stack.pop().unwrap() as usize; self.stack.push(u64::from_le_bytes(self.memory[a..a+8].try_into().unwrap())); } 
-            0x63 => { let a = self.stack.pop().unwrap() as usize; let v = self.stack.pop().unwrap(); self.memory[a..a+8].copy_from_slice(&v.to_le_bytes()); } 
-            0x70 => { let a = self.stack.pop().unwrap() as usize; self.stack.push(self.memory[a] as u64); } 
-            0x71 => { let a = self.stack.pop().unwrap() as usize; let v = self.stack.pop().unwrap(); self.memory[a] = v as u8; }
-            0x80 => { // SYSCALL
-                let sn = self.stack.pop().unwrap(); 
-                match sn { 
-                    1 => { let a = self.stack.pop().unwrap() as usize; let mut n = String::new(); let mut i = a; while i < self.memory.len() && self.memory[i] != 0 { n.push(self.memory[i] as char); i += 1; } let fd = self.next_fd; self.next_fd += 1; if !self.vfs.contains_key(&n) { self.vfs.insert(n.clone(), Vec::new()); } self.fds.insert(fd, (n, 0)); self.stack.push(fd); } 
-                    2 => { let fd = self.stack.pop().unwrap(); let buf = self.stack.pop().unwrap() as usize; let len = self.stack.pop().unwrap() as usize; if let Some((n, p)) = self.fds.get_mut(&fd) { let f = self.vfs.get(n).unwrap(); let mut rb = 0; for i in 0..len { if *p + i < f.len() && buf + i < self.memory.len() { self.memory[buf+i] = f[*p+i]; rb += 1; } else { break; } } *p += rb; self.stack.push(rb as u64); } else { self.stack.push(0); } } 
-                    3 => { let fd = self.stack.pop().unwrap(); let buf = self.stack.pop().unwrap() as usize; let len = self.stack.pop().unwrap() as usize; if let Some((n, p)) = self.fds.get_mut(&fd) { let f = self.vfs.get_mut(n).unwrap(); for i in 0..len { if buf+i < self.memory.len() { if n == "/dev/stdout" { f.push(self.memory[buf+i]); } else { if *p+i < f.len() { f[*p+i] = self.memory[buf+i]; } else { f.push(self.memory[buf+i]); } } } } if n != "/dev/stdout" { *p += len; } self.stack.push(len as u64); } else { self.stack.push(0); } } 
-                    4 => { let inc = self.stack.pop().unwrap() as i64; let ob = self.brk; if inc > 0 { self.brk += inc as usize; } else if inc < 0 { self.brk -= (-inc) as usize; } self.stack.push(ob as u64); } 
-                    _ => self.stack.push(0), 
-                } 
-            } 
-            _ => return Err("Err".into()),
+            0x50 => { self.push(self.bp as u64)?; } 
+            0x60 => { let off = self.imm()? as usize; let v = self.read_u64(self.bp.wrapping_add(off))?; self.push(v)?; } 
+            0x61 => { let off = self.imm()? as usize; let v = self.pop()?; self.store_local(off, v)?; } 
+            0x62 => { let a = self.pop()? as usize; let v = self.read_u64(a)?; self.push(v)?; } 
+            0x63 => { let a = self.pop()? as usize; let v = self.pop()?; self.write_u64(a, v)?; } 
+            0x70 => { let a = self.pop()? as usize; let v = self.read_u8(a)?; self.push(v as u64)?; } 
+            0x71 => { let a = self.pop()? as usize; let v = self.pop()?; self.write_u8(a, v as u8)?; }
+            0x80 => return self.syscall(),
+            _ => return Err(TrapKind::InvalidOpcode(op)),
         }
         Ok(true)
     }
 }
 
+// The libc shim headers MiniCC programs can #include.
+pub fn libc_headers() -> HashMap<String, String> {
+    let mut std_vfs = HashMap::new();
+    // The heap is an address-ordered list of free blocks. A block is a 16-byte header { size, next free } and its
+    // payload, sizes rounded up to 16; malloc takes the first block that fits, splitting off the rest, free puts
+    // a block back and merges it with free neighbours, and only when nothing fits does the break move.
+    // exit runs the atexit handlers, last registered first, before ending the process.
+    std_vfs.insert("stdlib.h".to_string(), "#include <errno.h>
+#define NULL 0
+int __heap_free;
+int __heap_word;
+int __heap_round(int n) { __heap_word = n + 15; char* low = &__heap_word; int r = low[0]; while (r > 15) { r = r - 16; } return n + 15 - r; }
+int* malloc(int size) {
+    if (size > 1099511627776) { errno = ENOMEM; return 0; }
+    int need = __heap_round(size) + 16;
+    int prev = 0;
+    int cur = __heap_free;
+    while (cur) {
+        int* b = cur;
+        if (b[0] > need - 1) {
+            int next = b[1];
+            if (b[0] > need + 31) { int* rest = cur + need; rest[0] = b[0] - need; rest[1] = next; next = cur + need; b[0] = need; }
+            if (prev) { int* pb = prev; pb[1] = next; } else { __heap_free = next; }
+            return cur + 16;
+        }
+        prev = cur;
+        cur = b[1];
+    }
+    int p = syscall(4, need);
+    if (p > 18446744073709547520) { errno = ENOMEM; return 0; }
+    int* nb = p;
+    nb[0] = need;
+    return p + 16;
+}
+int free(int* ptr) {
+    if (ptr == 0) { return 0; }
+    int blk = ptr - 16;
+    int* b = blk;
+    int prev = 0;
+    int cur = __heap_free;
+    int walking = 1;
+    while (walking) { if (cur == 0) { walking = 0; } else { if (cur < blk) { int* cb = cur; prev = cur; cur = cb[1]; } else { walking = 0; } } }
+    b[1] = cur;
+    if (cur == blk + b[0]) { int* nb = cur; b[0] = b[0] + nb[0]; b[1] = nb[1]; }
+    if (prev) { int* pb = prev; pb[1] = blk; if (prev + pb[0] == blk) { pb[0] = pb[0] + b[0]; pb[1] = b[1]; } } else { __heap_free = blk; }
+    return 0;
+}
+int* calloc(int n, int size) {
+    if (n > 16777216) { errno = ENOMEM; return 0; }
+    if (size > 16777216) { errno = ENOMEM; return 0; }
+    int total = n * size;
+    int* p = malloc(total);
+    int* w = p;
+    int i = 0;
+    if (p) { while (i < total) { w[0] = 0; w = w + 8; i = i + 8; } }
+    return p;
+}
+int* realloc(int* ptr, int size) {
+    if (ptr == 0) { return malloc(size); }
+    int* b = ptr - 16;
+    int have = b[0] - 16;
+    if (size < have + 1) { return ptr; }
+    int* q = malloc(size);
+    int* s = ptr;
+    int* d = q;
+    int i = 0;
+    if (q) { while (i < have) { d[0] = s[0]; d = d + 8; s = s + 8; i = i + 8; } free(ptr); }
+    return q;
+}
+int __atexit_fns[32];
+int __atexit_n;
+int atexit(int fn) { if (__atexit_n == 32) { return 0 - 1; } __atexit_fns[__atexit_n] = fn; __atexit_n = __atexit_n + 1; return 0; }
+int exit(int status) {
+    while (__atexit_n) { __atexit_n = __atexit_n - 1; int fn = __atexit_fns[__atexit_n]; (*fn)(); }
+    return syscall(13, status);
+}".to_string());
+    // A FILE is 12 words (the __F_* indices) ahead of its buffer: the fd, whether it reads and writes, the
+    // buffer, then where the buffer stands. In read mode pos..end is read-ahead the fd has already passed; in
+    // write mode 0..pos waits to be written. fflush drops read-ahead by seeking the fd back. One byte of ungetc
+    // pushback sits outside the buffer. stdin and stdout are line buffered on a terminal and fully buffered
+    // otherwise, stderr is unbuffered; whatever is left is flushed at exit through atexit.
+    // printf and friends format through one engine, `__vformat`, into a sink: a 256-byte chunk flushed to a
+    // FILE or an fd, or a caller's buffer that snprintf truncates. `__fs_*` hold the conversion being formatted.
+    // Integers are 64-bit, so l, ll, j, z and t change nothing; h and hh narrow the argument as C does.
+    std_vfs.insert("stdio.h".to_string(), "#define EOF -1
+#define FILE int
+int fputs(char* s, FILE* f) { int len = 0; while (s[len] != 0) { len = len + 1; } if (fwrite(s, 1, len, f) < len) { return EOF; } return len; }
+#include <errno.h>
+#include <fcntl.h>
+#include <stdlib.h>
+#include <stdarg.h>
+#define BUFSIZ 1024
+#define FOPEN_MAX 64
+#define SEEK_SET 0
+#define SEEK_CUR 1
+#define SEEK_END 2
+#define _IOFBF 0
+#define _IOLBF 1
+#define _IONBF 2
+#define __F_FD 0
+#define __F_READ 1
+#define __F_WRITE 2
+#define __F_BUF 3
+#define __F_SIZE 4
+#define __F_POS 5
+#define __F_END 6
+#define __F_MODE 7
+#define __F_EOF 8
+#define __F_ERR 9
+#define __F_UNGET 10
+#define __F_LINE 11
+#define __F_HEADER 96
+#define stdin __stdio_std(0)
+#define stdout __stdio_std(1)
+#define stderr __stdio_std(2)
+int __stdio_files[64];
+int __stdio_std_files[36];
+char __stdio_std_bufs[2048];
+char __stdio_byte[8];
+int __stdio_hooked;
+int __stdio_exit() { fflush(0); return 0; }
+int __stdio_init(FILE* f, int fd, int rd, int wr, int line, char* buf, int size) {
+    int i = 0;
+    while (i < 12) { f[i] = 0; i = i + 1; }
+    f[__F_FD] = fd; f[__F_READ] = rd; f[__F_WRITE] = wr; f[__F_LINE] = line; f[__F_BUF] = buf; f[__F_SIZE] = size;
+    if (__stdio_hooked == 0) { __stdio_hooked = 1; atexit(&__stdio_exit); }
+    i = 0;
+    while (i < FOPEN_MAX) { if (__stdio_files[i] == 0) { __stdio_files[i] = f; return 1; } i = i + 1; }
+    return 0;
+}
+FILE* __stdio_std(int n) {
+    FILE* f = __stdio_std_files + n * __F_HEADER;
+    if (f[__F_READ] + f[__F_WRITE] == 0) {
+        int line = _IOFBF;
+        if (syscall(26, n, 21505, 0) == 0) { line = _IOLBF; }
+        if (n == 0) { __stdio_init(f, 0, 1, 0, line, __stdio_std_bufs, BUFSIZ); }
+        if (n == 1) { __stdio_init(f, 1, 0, 1, line, __stdio_std_bufs + BUFSIZ, BUFSIZ); }
+        if (n == 2) { __stdio_init(f, 2, 0, 1, _IONBF, __stdio_byte, 0); }
+    }
+    return f;
+}
+int __stdio_write(int fd, char* p, int n) {
+    while (n) {
+        int w = syscall(3, fd, p, n);
+        if (w > 9223372036854775807) { errno = 0 - w; return EOF; }
+        p = p + w; n = n - w;
+    }
+    return 0;
+}
+int fflush(FILE* f) {
+    if (f == 0) {
+        int r = 0;
+        int i = 0;
+        while (i < FOPEN_MAX) { if (__stdio_files[i]) { if (fflush(__stdio_files[i])) { r = EOF; } } i = i + 1; }
+        return r;
+    }
+    if (f[__F_MODE] == 2) {
+        int n = f[__F_POS];
+        f[__F_POS] = 0; f[__F_MODE] = 0;
+        if (__stdio_write(f[__F_FD], f[__F_BUF], n)) { f[__F_ERR] = 1; return EOF; }
+    }
+    if (f[__F_MODE] == 1) {
+        int back = f[__F_END] - f[__F_POS];
+        if (f[__F_UNGET]) { back = back + 1; }
+        int moved = 1;
+        if (back) { if (syscall(6, f[__F_FD], 0 - back, SEEK_CUR) > 9223372036854775807) { moved = 0; } }
+        if (moved) { f[__F_POS] = 0; f[__F_END] = 0; f[__F_UNGET] = 0; f[__F_MODE] = 0; }
+    }
+    return 0;
+}
+int __stdio_flush_lines() {
+    int i = 0;
+    while (i < FOPEN_MAX) {
+        FILE* f = __stdio_files[i];
+        if (f) { if (f[__F_LINE] == _IOLBF) { if (f[__F_MODE] == 2) { fflush(f); } } }
+        i = i + 1;
+    }
+    return 0;
+}
+int __stdio_fill(FILE* f) {
+    if (f[__F_READ] == 0) { errno = EBADF; f[__F_ERR] = 1; return 0; }
+    if (f[__F_MODE] == 2) { if (fflush(f)) { return 0; } }
+    if (f[__F_LINE] != _IOFBF) { __stdio_flush_lines(); }
+    f[__F_MODE] = 1; f[__F_POS] = 0; f[__F_END] = 0;
+    int n = syscall(2, f[__F_FD], f[__F_BUF], f[__F_SIZE]);
+    if (n > 9223372036854775807) { errno = 0 - n; f[__F_ERR] = 1; return 0; }
+    if (n == 0) { f[__F_EOF] = 1; }
+    f[__F_END] = n;
+    return n;
+}
+int __stdio_avail(FILE* f) {
+    if (f[__F_MODE] == 1) { if (f[__F_POS] < f[__F_END]) { return f[__F_END] - f[__F_POS]; } }
+    if (f[__F_EOF]) { return 0; }
+    return __stdio_fill(f);
+}
+int fgetc(FILE* f) {
+    if (f[__F_UNGET]) { int u = f[__F_UNGET] - 1; f[__F_UNGET] = 0; return u; }
+    if (__stdio_avail(f) == 0) { return EOF; }
+    char* b = f[__F_BUF];
+    int c = b[f[__F_POS]];
+    f[__F_POS] = f[__F_POS] + 1;
+    return c;
+}
+int getc(FILE* f) { return fgetc(f); }
+int getchar() { return fgetc(stdin); }
+int ungetc(int c, FILE* f) {
+    if (c == EOF) { return EOF; }
+    if (f[__F_UNGET]) { return EOF; }
+    if (f[__F_MODE] == 2) { if (fflush(f)) { return EOF; } }
+    c = c % 256;
+    f[__F_UNGET] = c + 1; f[__F_EOF] = 0; f[__F_MODE] = 1;
+    return c;
+}
+int fread(char* p, int size, int n, FILE* f) {
+    int want = size * n;
+    if (want == 0) { return 0; }
+    int got = 0;
+    if (f[__F_UNGET]) { p[0] = f[__F_UNGET] - 1; f[__F_UNGET] = 0; got = 1; }
+    while (got < want) {
+        int k = __stdio_avail(f);
+        if (k == 0) { return got / size; }
+        char* b = f[__F_BUF] + f[__F_POS];
+        if (k > want - got) { k = want - got; }
+        int i = 0;
+        while (i < k) { p[got + i] = b[i]; i = i + 1; }
+        f[__F_POS] = f[__F_POS] + k;
+        got = got + k;
+    }
+    return n;
+}
+int fwrite(char* p, int size, int n, FILE* f) {
+    int want = size * n;
+    if (want == 0) { return 0; }
+    if (f[__F_WRITE] == 0) { errno = EBADF; f[__F_ERR] = 1; return 0; }
+    if (f[__F_MODE] == 1) { fflush(f); f[__F_POS] = 0; f[__F_END] = 0; f[__F_UNGET] = 0; f[__F_MODE] = 0; }
+    int direct = 0;
+    if (f[__F_LINE] == _IONBF) { direct = 1; }
+    if (want > f[__F_SIZE] - 1) { direct = 1; }
+    if (direct) {
+        if (fflush(f)) { return 0; }
+        if (__stdio_write(f[__F_FD], p, want)) { f[__F_ERR] = 1; return 0; }
+        return n;
+    }
+    char* b = f[__F_BUF];
+    int i = 0;
+    while (i < want) {
+        f[__F_MODE] = 2;
+        int c = p[i];
+        b[f[__F_POS]] = c;
+        f[__F_POS] = f[__F_POS] + 1;
+        i = i + 1;
+        int full = 0;
+        if (f[__F_POS] == f[__F_SIZE]) { full = 1; }
+        if (c == 10) { if (f[__F_LINE] == _IOLBF) { full = 1; } }
+        if (full) { if (fflush(f)) { return i / size; } }
+    }
+    return n;
+}
+int fputc(int c, FILE* f) { __stdio_byte[0] = c; if (fwrite(__stdio_byte, 1, 1, f) == 1) { return c % 256; } return EOF; }
+int putc(int c, FILE* f) { return fputc(c, f); }
+int putchar(int c) { return fputc(c, stdout); }
+int puts(char* s) { if (fputs(s, stdout) == EOF) { return EOF; } return fputc(10, stdout); }
+char* fgets(char* s, int size, FILE* f) {
+    int i = 0;
+    while (i + 1 < size) {
+        int c = fgetc(f);
+        if (c == EOF) { if (i == 0) { return 0; } s[i] = 0; return s; }
+        s[i] = c;
+        i = i + 1;
+        if (c == 10) { s[i] = 0; return s; }
+    }
+    if (size) { s[i] = 0; }
+    return s;
+}
+FILE* fdopen(int fd, char* mode) {
+    int rd = 0;
+    int wr = 0;
+    if (mode[0] == 'r') { rd = 1; }
+    if (mode[0] != 'r') { wr = 1; }
+    int i = 1;
+    while (mode[i]) { if (mode[i] == '+') { rd = 1; wr = 1; } i = i + 1; }
+    FILE* f = malloc(__F_HEADER + BUFSIZ);
+    if (f == 0) { return 0; }
+    if (__stdio_init(f, fd, rd, wr, _IOFBF, f + __F_HEADER, BUFSIZ) == 0) { free(f); errno = EMFILE; return 0; }
+    return f;
+}
+FILE* fopen(char* path, char* mode) {
+    int rd = 0;
+    int wr = 0;
+    int flags = 0;
+    int excl = 0;
+    if (mode[0] == 'r') { rd = 1; }
+    if (mode[0] == 'w') { wr = 1; flags = O_CREAT + O_TRUNC; }
+    if (mode[0] == 'a') { wr = 1; flags = O_CREAT + O_APPEND; }
+    if (rd + wr == 0) { errno = EINVAL; return 0; }
+    int i = 1;
+    while (mode[i]) { if (mode[i] == '+') { rd = 1; wr = 1; } if (mode[i] == 'x') { excl = O_EXCL; } i = i + 1; }
+    int access = O_RDONLY;
+    if (wr) { access = O_WRONLY; if (rd) { access = O_RDWR; } }
+    if (flags) { flags = flags + excl; }
+    int fd = syscall(1, path, access + flags, 438);
+    if (fd > 9223372036854775807) { errno = 0 - fd; return 0; }
+    FILE* f = malloc(__F_HEADER + BUFSIZ);
+    if (f) { if (__stdio_init(f, fd, rd, wr, _IOFBF, f + __F_HEADER, BUFSIZ) == 0) { free(f); f = 0; errno = EMFILE; } }
+    if (f == 0) { syscall(5, fd); }
+    return f;
+}
+int fclose(FILE* f) {
+    int r = fflush(f);
+    int c = syscall(5, f[__F_FD]);
+    if (c) { errno = 0 - c; r = EOF; }
+    int i = 0;
+    while (i < FOPEN_MAX) { if (__stdio_files[i] == f) { __stdio_files[i] = 0; } i = i + 1; }
+    if (f - __stdio_std_files > 3 * __F_HEADER - 1) { free(f); }
+    return r;
+}
+int fseek(FILE* f, int off, int whence) {
+    if (fflush(f)) { return EOF; }
+    if (f[__F_MODE] == 1) { errno = ESPIPE; return EOF; }
+    int r = syscall(6, f[__F_FD], off, whence);
+    if (r > 9223372036854775807) { errno = 0 - r; return EOF; }
+    f[__F_EOF] = 0;
+    return 0;
+}
+int ftell(FILE* f) {
+    if (f[__F_MODE] == 2) { if (fflush(f)) { return EOF; } }
+    int pos = syscall(6, f[__F_FD], 0, SEEK_CUR);
+    if (pos > 9223372036854775807) { errno = 0 - pos; return EOF; }
+    if (f[__F_MODE] == 1) { pos = pos - f[__F_END] + f[__F_POS]; if (f[__F_UNGET]) { pos = pos - 1; } }
+    return pos;
+}
+int rewind(FILE* f) { fseek(f, 0, SEEK_SET); f[__F_ERR] = 0; return 0; }
+int feof(FILE* f) { return f[__F_EOF]; }
+int ferror(FILE* f) { return f[__F_ERR]; }
+int clearerr(FILE* f) { f[__F_EOF] = 0; f[__F_ERR] = 0; return 0; }
+int fileno(FILE* f) { return f[__F_FD]; }
+int setvbuf(FILE* f, char* buf, int mode, int size) {
+    if (f[__F_MODE]) { return EOF; }
+    f[__F_LINE] = mode;
+    if (buf) { if (size) { f[__F_BUF] = buf; f[__F_SIZE] = size; } }
+    return 0;
+}
+int __fmt_fd;
+FILE* __fmt_file;
+char* __fmt_buf;
+int __fmt_cap;
+int __fmt_len;
+int __fmt_count;
+int __fmt_err;
+char __fmt_chunk[256];
+char __fmt_digits[72];
+int __fs_left;
+int __fs_plus;
+int __fs_space;
+int __fs_alt;
+int __fs_zero;
+int __fs_width;
+int __fs_prec;
+int __fs_len;
+int __fmt_flush() {
+    if (__fmt_file) { if (fwrite(__fmt_buf, 1, __fmt_len, __fmt_file) < __fmt_len) { __fmt_err = 1; } __fmt_len = 0; return 0; }
+    int off = 0;
+    while (off < __fmt_len) {
+        int n = syscall(3, __fmt_fd, __fmt_buf + off, __fmt_len - off);
+        if (n > 9223372036854775807) { errno = 0 - n; __fmt_err = 1; off = __fmt_len; } else { off = off + n; }
+    }
+    __fmt_len = 0;
+    return 0;
+}
+int __fmt_put(int c) {
+    if (__fmt_fd == EOF) {
+        if (__fmt_count + 1 < __fmt_cap) { __fmt_buf[__fmt_count] = c; }
+    } else {
+        __fmt_buf[__fmt_len] = c;
+        __fmt_len = __fmt_len + 1;
+        if (__fmt_len == __fmt_cap) { __fmt_flush(); }
+    }
+    __fmt_count = __fmt_count + 1;
+    return 0;
+}
+int __fmt_pad(int c, int want, int have) {
+    while (have < want) { __fmt_put(c); have = have + 1; }
+    return 0;
+}
+int __fmt_str(char* s, int n) {
+    if (__fs_left == 0) { __fmt_pad(' ', __fs_width, n); }
+    int i = 0;
+    while (i < n) { __fmt_put(s[i]); i = i + 1; }
+    if (__fs_left) { __fmt_pad(' ', __fs_width, n); }
+    return 0;
+}
+int __fmt_int(int v, int conv) {
+    int base = 10;
+    if (conv == 'o') { base = 8; }
+    if (conv == 'x') { base = 16; }
+    if (conv == 'X') { base = 16; }
+    if (conv == 'p') { base = 16; }
+    int dec = 0;
+    if (conv == 'd') { dec = 1; }
+    if (conv == 'i') { dec = 1; }
+    if (__fs_len == 1) { v = v % 65536; if (dec) { if (v > 32767) { v = v - 65536; } } }
+    if (__fs_len == 2) { v = v % 256; if (dec) { if (v > 127) { v = v - 256; } } }
+    int sign = 0;
+    if (dec) {
+        if (v > 9223372036854775807) { sign = '-'; v = 0 - v; } else { if (__fs_plus) { sign = '+'; } else { if (__fs_space) { sign = ' '; } } }
+    }
+    char* set = \"0123456789abcdef\";
+    if (conv == 'X') { set = \"0123456789ABCDEF\"; }
+    char* prefix = \"\";
+    if (__fs_alt) { if (v) { if (conv == 'x') { prefix = \"0x\"; } if (conv == 'X') { prefix = \"0X\"; } } }
+    if (conv == 'p') { prefix = \"0x\"; }
+    int n = 0;
+    while (v) { __fmt_digits[n] = set[v % base]; v = v / base; n = n + 1; }
+    int prec = __fs_prec;
+    int zero = __fs_zero;
+    if (prec == 0 - 1) { prec = 1; } else { zero = 0; }
+    if (__fs_left) { zero = 0; }
+    if (conv == 'o') { if (__fs_alt) { if (prec < n + 1) { prec = n + 1; } } }
+    int digits = n;
+    if (prec > n) { digits = prec; }
+    int p = 0;
+    while (prefix[p]) { p = p + 1; }
+    int total = digits + p;
+    if (sign) { total = total + 1; }
+    if (zero == 0) { if (__fs_left == 0) { __fmt_pad(' ', __fs_width, total); } }
+    if (sign) { __fmt_put(sign); }
+    p = 0;
+    while (prefix[p]) { __fmt_put(prefix[p]); p = p + 1; }
+    if (zero) { __fmt_pad('0', __fs_width, total); }
+    __fmt_pad('0', digits, n);
+    while (n) { n = n - 1; __fmt_put(__fmt_digits[n]); }
+    if (__fs_left) { __fmt_pad(' ', __fs_width, total); }
+    return 0;
+}
+int __fmt_digit(int c) { if (c > 47) { if (c < 58) { return 1; } } return 0; }
+int __fmt_more(char* s, int n) { if (n == __fs_prec) { return 0; } if (s[n]) { return 1; } return 0; }
+int __fmt_isint(int c) {
+    if (c == 'd') { return 1; } if (c == 'i') { return 1; } if (c == 'u') { return 1; }
+    if (c == 'x') { return 1; } if (c == 'X') { return 1; } if (c == 'o') { return 1; }
+    return 0;
+}
+int __vformat(char* f, va_list ap) {
+    int i = 0;
+    while (f[i]) {
+        int c = f[i];
+        i = i + 1;
+        if (c != '%') { __fmt_put(c); } else {
+            __fs_left = 0; __fs_plus = 0; __fs_space = 0; __fs_alt = 0; __fs_zero = 0; __fs_width = 0; __fs_prec = 0 - 1; __fs_len = 0;
+            int more = 1;
+            while (more) {
+                c = f[i];
+                more = 0;
+                if (c == '-') { __fs_left = 1; more = 1; }
+                if (c == '+') { __fs_plus = 1; more = 1; }
+                if (c == ' ') { __fs_space = 1; more = 1; }
+                if (c == '#') { __fs_alt = 1; more = 1; }
+                if (c == '0') { __fs_zero = 1; more = 1; }
+                if (more) { i = i + 1; }
+            }
+            if (f[i] == '*') {
+                __fs_width = va_arg(ap, int);
+                i = i + 1;
+                if (__fs_width > 9223372036854775807) { __fs_left = 1; __fs_width = 0 - __fs_width; }
+            } else {
+                while (__fmt_digit(f[i])) { __fs_width = __fs_width * 10 + f[i] - '0'; i = i + 1; }
+            }
+            if (f[i] == '.') {
+                i = i + 1;
+                __fs_prec = 0;
+                if (f[i] == '*') {
+                    __fs_prec = va_arg(ap, int);
+                    i = i + 1;
+                    if (__fs_prec > 9223372036854775807) { __fs_prec = 0 - 1; }
+                } else {
+                    while (__fmt_digit(f[i])) { __fs_prec = __fs_prec * 10 + f[i] - '0'; i = i + 1; }
+                }
+            }
+            more = 1;
+            while (more) {
+                c = f[i];
+                more = 0;
+                if (c == 'h') { __fs_len = __fs_len + 1; more = 1; }
+                if (c == 'l') { more = 1; }
+                if (c == 'L') { more = 1; }
+                if (c == 'j') { more = 1; }
+                if (c == 'z') { more = 1; }
+                if (c == 't') { more = 1; }
+                if (more) { i = i + 1; }
+            }
+            c = f[i];
+            if (c) { i = i + 1; }
+            if (c == '%') { __fmt_put('%'); }
+            if (c == 'c') { __fmt_digits[0] = va_arg(ap, int); __fmt_str(__fmt_digits, 1); }
+            if (c == 's') {
+                char* s = va_arg(ap, char*);
+                if (s == 0) { s = \"(null)\"; }
+                int n = 0;
+                while (__fmt_more(s, n)) { n = n + 1; }
+                __fmt_str(s, n);
+            }
+            if (c == 'p') {
+                int pv = va_arg(ap, void*);
+                if (pv) { __fmt_int(pv, 'p'); } else { __fmt_str(\"(nil)\", 5); }
+            }
+            if (__fmt_isint(c)) { __fmt_int(va_arg(ap, int), c); }
+        }
+    }
+    return __fmt_count;
+}
+int vsnprintf(char* buf, int size, char* fmt, va_list ap) {
+    __fmt_fd = EOF; __fmt_file = 0; __fmt_buf = buf; __fmt_cap = size; __fmt_count = 0;
+    __vformat(fmt, ap);
+    if (size) { if (__fmt_count < size) { buf[__fmt_count] = 0; } else { buf[size - 1] = 0; } }
+    return __fmt_count;
+}
+int vsprintf(char* buf, char* fmt, va_list ap) { return vsnprintf(buf, 9223372036854775807, fmt, ap); }
+int __vfdprintf(FILE* f, int fd, char* fmt, va_list ap) {
+    __fmt_file = f; __fmt_fd = fd; __fmt_buf = __fmt_chunk; __fmt_cap = 256; __fmt_len = 0; __fmt_count = 0; __fmt_err = 0;
+    __vformat(fmt, ap);
+    __fmt_flush();
+    if (__fmt_err) { return EOF; }
+    return __fmt_count;
+}
+int vdprintf(int fd, char* fmt, va_list ap) { return __vfdprintf(0, fd, fmt, ap); }
+int vfprintf(FILE* f, char* fmt, va_list ap) { return __vfdprintf(f, 0, fmt, ap); }
+int vprintf(char* fmt, va_list ap) { return vfprintf(stdout, fmt, ap); }
+int printf(char* fmt, ...) { va_list ap; va_start(ap, fmt); int r = vfprintf(stdout, fmt, ap); va_end(ap); return r; }
+int fprintf(FILE* f, char* fmt, ...) { va_list ap; va_start(ap, fmt); int r = vfprintf(f, fmt, ap); va_end(ap); return r; }
+int dprintf(int fd, char* fmt, ...) { va_list ap; va_start(ap, fmt); int r = vdprintf(fd, fmt, ap); va_end(ap); return r; }
+int sprintf(char* buf, char* fmt, ...) { va_list ap; va_start(ap, fmt); int r = vsnprintf(buf, 9223372036854775807, fmt, ap); va_end(ap); return r; }
+int snprintf(char* buf, int size, char* fmt, ...) { va_list ap; va_start(ap, fmt); int r = vsnprintf(buf, size, fmt, ap); va_end(ap); return r; }".to_string());
+    // A variadic function's extra arguments sit in VA_MAX frame slots right after its last named parameter.
+    std_vfs.insert("stdarg.h".to_string(), "#define va_list int
+#define va_start(ap, last) ap = &last + 8
+#define va_arg(ap, type) __va_arg(&ap)
+#define va_end(ap) ap = 0
+#define va_copy(dest, src) dest = src
+int __va_arg(int* ap) { int* p = *ap; *ap = p + 8; return *p; }".to_string());
+    // Syscalls return -errno; __syscall_ret turns that into the C convention of -1 with `errno` set.
+    std_vfs.insert("errno.h".to_string(), "#define ENOENT 2\n#define EBADF 9\n#define EACCES 13\n#define EFAULT 14\n#define EEXIST 17\n#define EINVAL 22\n#define EMFILE 24\n#define ESPIPE 29\n#define ERANGE 34\n#define ENOSYS 38\n#define EPERM 1\n#define ENOTDIR 20\n#define EISDIR 21\n#define ENOTEMPTY 39\n#define ELOOP 40\n#define EROFS 30\n#define EAGAIN 11\n#define ENOTTY 25\n#define ENXIO 6\n#define ECHILD 10\n#define ENOEXEC 8\n#define E2BIG 7\n#define EPIPE 32\n#define ENOMEM 12\n#define EFBIG 27\nint errno;\nint __syscall_ret(int r) { if (r > 18446744073709547520) { errno = 0 - r; return 0 - 1; } return r; }".to_string());
+    std_vfs.insert("fcntl.h".to_string(), "#include <errno.h>\n#define O_RDONLY 0\n#define O_WRONLY 1\n#define O_RDWR 2\n#define O_CREAT 64\n#define O_EXCL 128\n#define O_TRUNC 512\n#define O_APPEND 1024\nint open(char* path, int flags, int mode) { return __syscall_ret(syscall(1, path, flags, mode)); }".to_string());
+    std_vfs.insert("unistd.h".to_string(), "#include <errno.h>\n#define STDIN_FILENO 0\n#define STDOUT_FILENO 1\n#define STDERR_FILENO 2\n#define SEEK_SET 0\n#define SEEK_CUR 1\n#define SEEK_END 2\nint read(int fd, char* buf, int len) { return __syscall_ret(syscall(2, fd, buf, len)); }\nint write(int fd, char* buf, int len) { return __syscall_ret(syscall(3, fd, buf, len)); }\nint close(int fd) { return __syscall_ret(syscall(5, fd)); }\nint lseek(int fd, int off, int whence) { return __syscall_ret(syscall(6, fd, off, whence)); }\nint pread(int fd, char* buf, int len, int off) { return __syscall_ret(syscall(23, fd, buf, len, off)); }\nint pwrite(int fd, char* buf, int len, int off) { return __syscall_ret(syscall(24, fd, buf, len, off)); }\nint ftruncate(int fd, int len) { return __syscall_ret(syscall(25, fd, len)); }\nint dup(int fd) { return __syscall_ret(syscall(15, fd)); }\nint dup2(int fd, int newfd) { return __syscall_ret(syscall(16, fd, newfd)); }\nint unlink(char* path) { return __syscall_ret(syscall(9, path)); }\nint rmdir(char* path) { return __syscall_ret(syscall(17, path)); }\nint link(char* old, char* new) { return __syscall_ret(syscall(19, old, new)); }\nint symlink(char* target, char* path) { return __syscall_ret(syscall(20, target, path)); }\nint readlink(char* path, char* buf, int size) { return __syscall_ret(syscall(21, path, buf, size)); }\nint isatty(int fd) { if (__syscall_ret(syscall(26, fd, 21505, 0)) == 0) { return 1; } return 0; }\nint fork() { return __syscall_ret(syscall(28)); }\nint execve(char* path, char** argv, char** envp) { return __syscall_ret(syscall(29, path, argv, envp)); }\nint spawn(char* path, char** argv, char** envp) { return __syscall_ret(syscall(30, path, argv, envp)); }\nint getpid() { return syscall(32); }\nint getppid() { return syscall(33); }\nint pipe(int* fds) { return __syscall_ret(syscall(34, fds)); }".to_string());
+    std_vfs.insert("sys/wait.h".to_string(), "#include <errno.h>\n#define WNOHANG 1\nint waitpid(int pid, int* status, int options) { return __syscall_ret(syscall(31, pid, status, options)); }\nint wait(int* status) { return waitpid(0 - 1, status, 0); }\nint WIFEXITED(int s) { return s < 256; }\nint WEXITSTATUS(int s) { return s; }".to_string());
+    std_vfs.insert("sys/ioctl.h".to_string(), "#include <errno.h>\n#define TCGETS 21505\n#define TIOCGWINSZ 21523\nint ioctl(int fd, int req, char* arg) { return __syscall_ret(syscall(26, fd, req, arg)); }".to_string());
+    std_vfs.insert("sys/stat.h".to_string(), "#include <errno.h>\n#define S_IFCHR 8192\n#define S_IFDIR 16384\n#define S_IFREG 32768\n#define S_IFLNK 40960\nint stat(char* path, int* st) { return __syscall_ret(syscall(7, path, st)); }\nint fstat(int fd, int* st) { return __syscall_ret(syscall(8, fd, st)); }\nint lstat(char* path, int* st) { return __syscall_ret(syscall(22, path, st)); }\nint mkdir(char* path, int mode) { return __syscall_ret(syscall(11, path, mode)); }".to_string());
+    // readdir here takes a directory fd and copies the next name out, rather than returning a struct dirent*.
+    std_vfs.insert("dirent.h".to_string(), "#include <errno.h>\nint readdir(int fd, char* name, int size) { return __syscall_ret(syscall(18, fd, name, size)); }".to_string());
+    std_vfs
+}
+
+// Writes the libc shim headers under /usr/include, where the preprocessor looks for <...> includes.
+pub fn install_libc(fs: &mut dyn vfs::FileSystem) -> Result<(), String> {
+    let mut headers: Vec<_> = libc_headers().into_iter().collect(); headers.sort();
+    for (name, text) in headers { fs.write_file(&format!("/usr/include/{}", name), text.into_bytes())?; }
+    Ok(())
+}
+
+// Build driver: compiles the C file at `src`, with includes read from the same tree, and writes the BEF image to
+// `out`. Returns the image size.
+pub fn build_file(fs: &mut dyn vfs::FileSystem, src: &str, out: &str) -> Result<usize, String> {
+    let source = fs.read_file(src).ok_or_else(|| format!("{}: no such file", src))?;
+    let mut cc = MiniCC::new_named(src, &String::from_utf8_lossy(&source), &*fs);
+    let bef = Assembler::compile_bef(&cc.compile(), &cc.data);
+    let size = bef.len();
+    fs.write_file(out, bef)?;
+    Ok(size)
+}
+
+// Upper bound for any single suite program; a hang reports FAIL instead of freezing the tab.
+const SUITE_GAS: u64 = 10_000_000;
+
 pub fn run_suite() -> String {
     let mut report = String::from(SYSTEM_STATUS);
     let pass_msg = "\x1b[32mPASS\x1b[0m\n";
-    let mut std_vfs = HashMap::new();
-    std_vfs.insert("stdlib.h".to_string(), "#define NULL 0\nint* malloc(int size) { return syscall(4, size); }\nvoid free(int* ptr) { return; }".to_string());
-    std_vfs.insert("stdio.h".to_string(), "#define EOF -1\nint fputs(char* s, int fd) { int len=0; while(s[len]!=0){len=len+1;} return syscall(3, fd, s, len); }".to_string());
+    let std_vfs = libc_headers();
     
     // Tests 1-7
     report.push_str("TEST: COMPILER_STACK_VARS ......... ");
     let mut cc1 = MiniCC::new("int main() { return 118; }", &std_vfs);
     let mut vm1 = Machine::new(); vm1.load(&Assembler::compile_bef(&cc1.compile(), &cc1.data));
-    while vm1.step().unwrap_or(false) {}
+    vm1.run(SUITE_GAS);
     if vm1.stack.last() == Some(&118) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
     report.push_str("TEST: COMPILER_ARRAYS_NESTED ...... ");
     let mut cc2 = MiniCC::new("int arr[10]; int main() { arr[0] = 100; arr[1] = 50; return arr[0] + arr[1]; }", &std_vfs);
     let mut vm2 = Machine::new(); vm2.load(&Assembler::compile_bef(&cc2.compile(), &cc2.data));
-    while vm2.step().unwrap_or(false) {}
+    vm2.run(SUITE_GAS);
     if vm2.stack.last() == Some(&150) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
     report.push_str("TEST: MULTIPASS_FORWARD_DECLS ..... ");
     let mut cc3 = MiniCC::new("int main() { return foo(); } int foo() { return 99; }", &std_vfs);
     let mut vm3 = Machine::new(); vm3.load(&Assembler::compile_bef(&cc3.compile(), &cc3.data));
-    while vm3.step().unwrap_or(false) {}
+    vm3.run(SUITE_GAS);
     if vm3.stack.last() == Some(&99) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
     report.push_str("TEST: PREPROCESSOR_DEFINES ........ ");
     let mut cc4 = MiniCC::new("#define M 42\nint main() { return M; }", &std_vfs);
     let mut vm4 = Machine::new(); vm4.load(&Assembler::compile_bef(&cc4.compile(), &cc4.data));
-    while vm4.step().unwrap_or(false) {}
+    vm4.run(SUITE_GAS);
     if vm4.stack.last() == Some(&42) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
     report.push_str("TEST: LIBC_MALLOC_SBRK ............ ");
     let mut cc5 = MiniCC::new("#define NULL 0\nint main() { int* p=syscall(4,8); *p=1234; return *p; }", &std_vfs);
     let mut vm5 = Machine::new(); vm5.load(&Assembler::compile_bef(&cc5.compile(), &cc5.data));
-    while vm5.step().unwrap_or(false) {}
+    vm5.run(SUITE_GAS);
     if vm5.stack.last() == Some(&1234) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
     report.push_str("TEST: POINTER_DECAY_STRINGS ....... ");
     let mut cc6 = MiniCC::new("int main() { char* s=\"A\"; return 0; }", &std_vfs);
     let mut vm6 = Machine::new(); vm6.load(&Assembler::compile_bef(&cc6.compile(), &cc6.data));
-    while vm6.step().unwrap_or(false) {}
+    vm6.run(SUITE_GAS);
     if vm6.stack.last() == Some(&0) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
     report.push_str("TEST: PREPROCESSOR_INCLUDE ........ ");
     let mut vfs7 = std_vfs.clone(); vfs7.insert("m.h".into(), "int a(){return 1;}".into());
     let mut cc7 = MiniCC::new("#include \"m.h\"\nint main(){return a();}", &vfs7);
     let mut vm7 = Machine::new(); vm7.load(&Assembler::compile_bef(&cc7.compile(), &cc7.data));
-    while vm7.step().unwrap_or(false) {}
+    vm7.run(SUITE_GAS);
     if vm7.stack.last() == Some(&1) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
     report.push_str("TEST: LIBC_SHIM_INTEGRATION ....... ");
-    let mut cc8 = MiniCC::new("#include <stdlib.h>\n#include <stdio.h>\nint main(){char* b=malloc(2);b[0]=65;b[1]=0;fputs(b,1);return 0;}", &std_vfs);
+    let mut cc8 = MiniCC::new("#include <stdlib.h>\n#include <stdio.h>\nint main(){char* b=malloc(2);b[0]=65;b[1]=0;fputs(b,stdout);return 0;}", &std_vfs);
     let mut vm8 = Machine::new(); vm8.load(&Assembler::compile_bef(&cc8.compile(), &cc8.data));
-    while vm8.step().unwrap_or(false) {}
-    if vm8.vfs.get("/dev/stdout").unwrap() == b"A" { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+    vm8.run(SUITE_GAS);
+    if vm8.drain_output(1) == b"A" { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
     // Test 9: Function Pointers
     report.push_str("TEST: FUNCTION_POINTERS ........... ");
@@ -543,11 +1651,815 @@ pub fn run_suite() -> String {
     let mut cc9 = MiniCC::new(src_fp, &std_vfs);
     let mut vm9 = Machine::new();
     vm9.load(&Assembler::compile_bef(&cc9.compile(), &cc9.data));
-    while vm9.step().unwrap_or(false) {}
+    vm9.run(SUITE_GAS);
     if vm9.stack.last() == Some(&20) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
 
+    report.push_str("TEST: GAS_METERING_HANG ........... ");
+    let mut cc10 = MiniCC::new("int main() { while (1) { } return 0; }", &std_vfs);
+    let mut vm10 = Machine::new(); vm10.load(&Assembler::compile_bef(&cc10.compile(), &cc10.data));
+    if vm10.run(10_000) == RunOutcome::OutOfGas && vm10.gas_used <= 10_000 { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: GAS_METERING_RESUME ......... ");
+    let src11 = "int main() { int i = 0; int s = 0; while (i < 50) { s = s + i; i = i + 1; } return s; }";
+    let mut cc11 = MiniCC::new(src11, &std_vfs);
+    let bef11 = Assembler::compile_bef(&cc11.compile(), &cc11.data);
+    let mut whole = Machine::new(); whole.load(&bef11);
+    let expect11 = whole.run(SUITE_GAS);
+    let mut sliced = Machine::new(); sliced.load(&bef11);
+    let mut out11 = sliced.run(7);
+    while out11 == RunOutcome::OutOfGas { out11 = sliced.run(7); }
+    if expect11 == RunOutcome::Halted(1225) && out11 == expect11 && sliced.gas_used == whole.gas_used { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: SNAPSHOT_RESTORE_EXACT ...... ");
+    let mut vm13 = Machine::new(); vm13.load(&bef11);
+    vm13.run(300);
+    let _ = vm13.vfs.write_file("/tmp/note", b"kept".to_vec());
+    let snap13 = vm13.snapshot(Some(&bef11));
+    let restored13 = Machine::restore(&snap13, Some(&bef11));
+    match restored13 {
+        Ok(mut r) => { if r.snapshot(Some(&bef11)) == snap13 && r.memory == vm13.memory && snap13.len() < 3 * PAGE_SIZE && r.run(SUITE_GAS) == vm13.run(SUITE_GAS) && r.gas_used == vm13.gas_used { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); } }
+        Err(_) => report.push_str("\x1b[31mFAIL\x1b[0m\n"),
+    }
+
+    report.push_str("TEST: TRACE_DETERMINISM ........... ");
+    let trace_of = |mode: trace::TraceMode, budget: u64| { let mut vm = Machine::new(); vm.load(&bef11); let mut rec = trace::TraceRecorder::new(mode); vm.run_traced(budget, Some(&mut rec)); rec.finish() };
+    let (full_a, full_b) = (trace_of(trace::TraceMode::Full, SUITE_GAS), trace_of(trace::TraceMode::Full, SUITE_GAS));
+    let (hash_a, hash_short) = (trace_of(trace::TraceMode::RollingHash(64), SUITE_GAS), trace_of(trace::TraceMode::RollingHash(64), 500));
+    let diverged = trace::diff_traces(&hash_a, &hash_short);
+    if trace::diff_traces(&full_a, &full_b).is_none() && full_a.contains(&format!(" w{}=", DATA_BASE)) && diverged.is_some() && full_a.lines().count() as u64 > hash_a.lines().count() as u64 * 32 { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: DEBUGGER_BREAK_STEP ......... ");
+    let mut dbg = debugger::Debugger::from_c("int g; int sq(int x) { g = x; return x * x; } int main() { int a = sq(3); return a + 1; }", &std_vfs);
+    let hit = dbg.command("break sq"); let cont = dbg.command("continue"); let bt = dbg.command("bt");
+    let fin = dbg.command("finish"); let stepped = dbg.command("next");
+    dbg.command("delete sq");
+    let g_addr = dbg.command("disas sq 4").lines().find_map(|l| l.split("PUSH ").nth(1).map(|a| a.to_string())).unwrap_or_default();
+    let mut dbg2 = debugger::Debugger::from_c("int g; int sq(int x) { g = x; return x * x; } int main() { int a = sq(3); return a + 1; }", &std_vfs);
+    dbg2.command(&format!("watch {}", g_addr)); let watched = dbg2.command("continue"); let g_val = dbg2.command(&format!("mem {} 1", g_addr));
+    let done = dbg.command("continue");
+    if hit.contains("<sq>") && cont.starts_with("breakpoint hit") && bt.lines().count() == 3 && bt.contains("<main+") && fin.contains("<main+") && !stepped.contains("<sq") && watched.starts_with("watchpoint") && watched.contains("<sq+") && g_val.ends_with(" 03") && done.starts_with("program halted") { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: DEBUG_INFO_LINE_TABLE ....... ");
+    let mut cc14 = MiniCC::new("#include <stdio.h>\nint main() {\n  int* p = 99999999;\n  fputs(\"x\", stderr);\n  return *p;\n}", &std_vfs);
+    let asm14 = cc14.compile(); let bef14 = Assembler::compile_bef(&asm14, &cc14.data); let syms14 = Assembler::symbols(&asm14);
+    let mut vm14 = Machine::new(); vm14.load(&bef14);
+    let info14 = debuginfo::DebugInfo::from_bef(&bef14).unwrap_or_default();
+    let fputs_at = info14.describe(syms14.addr("fputs").unwrap_or(0), &syms14);
+    let p_local = info14.locals_of("main").any(|v| v.name == "p" && v.ty == "int*" && v.offset == 0);
+    match vm14.run(SUITE_GAS) {
+        RunOutcome::Trapped(t) if info14.explain(&t, &syms14).contains("(main.c:5 in main): memory fault at 99999999") && fputs_at == "stdio.h:3 in fputs" && p_local && vm14.drain_output(2) == b"x" => report.push_str(pass_msg),
+        _ => report.push_str("\x1b[31mFAIL\x1b[0m\n"),
+    }
+
+    report.push_str("TEST: PROFILER_CALL_GRAPH ......... ");
+    let mut cc15 = MiniCC::new("int leaf(int x) { return x + 1; } int mid(int x) { return leaf(x) + leaf(x); } int main() { int i = 0; while (i < 10) { i = i + 1; mid(i); } return leaf(0); }", &std_vfs);
+    let asm15 = cc15.compile(); let bef15 = Assembler::compile_bef(&asm15, &cc15.data);
+    let mut prof15 = profiler::Profiler::new(Assembler::symbols(&asm15), profiler::ProfileMode::Exact);
+    let mut vm15 = Machine::new(); vm15.load(&bef15); vm15.run_traced(SUITE_GAS, Some(&mut prof15));
+    let mut steps15 = trace::TraceRecorder::new(trace::TraceMode::RollingHash(0));
+    let mut vm15b = Machine::new(); vm15b.load(&bef15); vm15b.run_traced(SUITE_GAS, Some(&mut steps15));
+    let folded15 = prof15.folded();
+    let folded_sum: u64 = folded15.lines().filter_map(|l| l.rsplit(' ').next()?.parse::<u64>().ok()).sum();
+    let leaf_calls = prof15.flat().lines().find(|l| l.ends_with(" leaf")).and_then(|l| l.split_whitespace().nth(4).map(String::from));
+    if prof15.total == steps15.steps && folded_sum == prof15.total && folded15.contains("<entry>;main;mid;leaf ") && folded15.contains("<entry>;main;leaf ") && leaf_calls.as_deref() == Some("21") && prof15.call_graph().contains("-> leaf") { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: COVERAGE_LCOV_BRANCHES ...... ");
+    let mut cc16 = MiniCC::new("int pick(int x) {\n  if (x < 5) {\n    return 1;\n  } else {\n    return 2;\n  }\n}\nint unused() {\n  return 3;\n}\nint main() {\n  return pick(1) + pick(2);\n}", &std_vfs);
+    let asm16 = cc16.compile(); let bef16 = Assembler::compile_bef(&asm16, &cc16.data);
+    let mut cov16 = coverage::Coverage::new(&bef16, Assembler::symbols(&asm16));
+    let mut vm16 = Machine::new(); vm16.load(&bef16); vm16.run_traced(SUITE_GAS, Some(&mut cov16));
+    let lcov16 = cov16.lcov();
+    let has = |l: &str| lcov16.lines().any(|x| x == l);
+    if has("SF:main.c") && has("DA:3,2") && has("DA:5,0") && has("DA:9,0") && has("BRDA:2,0,0,0") && has("BRDA:2,0,1,2") && has("FNDA:0,unused") && has("FNDA:2,pick") && has("BRH:1") && has("end_of_record") { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: SYSCALL_ABI_ERRNO ........... ");
+    let src17 = "#include <errno.h>
+    int main() {
+        int fd = syscall(1, \"/tmp/a\", 66, 420);
+        syscall(3, fd, \"hello\", 5);
+        syscall(6, fd, 1, 0);
+        char buf[8]; int st[5];
+        int n = syscall(2, fd, buf, 4);
+        syscall(8, fd, st);
+        syscall(5, fd);
+        int bad = __syscall_ret(syscall(5, fd));
+        syscall(10, \"/tmp/a\", \"/tmp/b\");
+        if (__syscall_ret(syscall(7, \"/tmp/a\", st)) == bad) { if (errno == ENOENT) { syscall(13, n + st[1] + EBADF); } }
+        return 1;
+    }";
+    let mut cc17 = MiniCC::new(src17, &std_vfs);
+    let mut vm17 = Machine::new(); vm17.load(&Assembler::compile_bef(&cc17.compile(), &cc17.data));
+    let out17 = vm17.run(SUITE_GAS);
+    if out17 == RunOutcome::Halted(18) && vm17.exit_status == Some(18) && vm17.vfs.get("/tmp/b").as_deref() == Some(&b"hello"[..]) && !vm17.vfs.contains("/tmp/a") && vm17.fds.len() == 3 { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: OPEN_FLAGS_DUP .............. ");
+    let src19 = "#include <fcntl.h>
+    #include <unistd.h>
+    int main() {
+        int r = 0;
+        if (open(\"/tmp/none\", O_RDONLY, 0) == 0 - 1) { if (errno == ENOENT) { r = r + 1; } }
+        int fd = open(\"/tmp/f\", O_CREAT + O_EXCL + O_RDWR, 420);
+        if (open(\"/tmp/f\", O_CREAT + O_EXCL + O_WRONLY, 420) == 0 - 1) { if (errno == EEXIST) { r = r + 2; } }
+        if (open(\"/dev/stdin\", O_WRONLY, 0) == 0 - 1) { if (errno == EACCES) { r = r + 4; } }
+        write(fd, \"abcdef\", 6);
+        int d = dup(fd);
+        lseek(d, 2, SEEK_SET);
+        char buf[8];
+        read(fd, buf, 1);
+        if (buf[0] == 99) { r = r + 8; }
+        close(fd);
+        int again = open(\"/tmp/f\", O_WRONLY + O_APPEND, 0);
+        if (again == fd) { r = r + 16; }
+        write(again, \"gh\", 2);
+        if (read(again, buf, 1) == 0 - 1) { if (errno == EBADF) { r = r + 32; } }
+        int t = open(\"/tmp/g\", O_CREAT + O_TRUNC + O_WRONLY, 420);
+        dup2(t, 1);
+        write(1, \"xyz\", 3);
+        close(t);
+        return r;
+    }";
+    let mut cc19 = MiniCC::new(src19, &std_vfs);
+    let mut vm19 = Machine::new(); vm19.load(&Assembler::compile_bef(&cc19.compile(), &cc19.data));
+    let _ = vm19.vfs.write_file("/tmp/g", b"old contents".to_vec());
+    let out19 = vm19.run(SUITE_GAS);
+    let fds_ok = vm19.fds.keys().copied().collect::<Vec<_>>() == [0, 1, 2, 3, 4] && vm19.fds[&1] == vm19.fds.values().copied().max().unwrap_or(0) && vm19.open_files.len() == 5;
+    if out19 == RunOutcome::Halted(63) && vm19.vfs.get("/tmp/f").as_deref() == Some(&b"abcdefgh"[..]) && vm19.vfs.get("/tmp/g").as_deref() == Some(&b"xyz"[..]) && fds_ok { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: VFS_TREE_LINKS .............. ");
+    let src20 = "#include <fcntl.h>
+    #include <unistd.h>
+    #include <sys/stat.h>
+    #include <dirent.h>
+    int main() {
+        int r = 0; int st[5];
+        mkdir(\"/src\", 493);
+        mkdir(\"/src/lib\", 493);
+        int fd = open(\"/src/lib/../main.c\", O_CREAT + O_WRONLY, 420);
+        write(fd, \"int x;\", 6);
+        close(fd);
+        if (stat(\"//src/./main.c\", st) == 0) { if (st[1] == 6) { r = r + 1; } }
+        link(\"/src/main.c\", \"/src/lib/alias.c\");
+        stat(\"/src/main.c\", st);
+        if (st[3] == 2) { r = r + 2; }
+        symlink(\"lib\", \"/src/l\");
+        if (stat(\"/src/l/alias.c\", st) == 0) { lstat(\"/src/l\", st); if (st[0] == S_IFLNK + 511) { r = r + 4; } }
+        if (rmdir(\"/src/lib\") == 0 - 1) { if (errno == ENOTEMPTY) { r = r + 8; } }
+        int d = open(\"/src\", O_RDONLY, 0);
+        char name[32]; int n = 0; int total = 0;
+        int len = readdir(d, name, 32);
+        while (len > 0) { n = n + 1; total = total + len; len = readdir(d, name, 32); }
+        if (n == 5) { if (total == 13) { r = r + 16; } }
+        int h = open(\"/src/l/alias.c\", O_RDONLY, 0);
+        unlink(\"/src/main.c\");
+        unlink(\"/src/lib/alias.c\");
+        char buf[8];
+        if (read(h, buf, 6) == 6) { if (buf[4] == 120) { r = r + 32; } }
+        if (open(\"/src/main.c\", O_RDONLY, 0) == 0 - 1) { if (errno == ENOENT) { r = r + 64; } }
+        close(h);
+        return r;
+    }";
+    let mut cc20 = MiniCC::new(src20, &std_vfs);
+    let mut vm20 = Machine::new(); vm20.load(&Assembler::compile_bef(&cc20.compile(), &cc20.data));
+    vm20.clock = 1_700_000_000;
+    let out20 = vm20.run(SUITE_GAS);
+    let src_dir = vm20.vfs.resolve("/src").and_then(|i| vm20.vfs.stat(i));
+    let orphans = vm20.vfs.inodes.values().filter(|n| n.nlink == 0).count();
+    if out20 == RunOutcome::Halted(127) && src_dir.map(|s| (s[0], s[3], s[4])) == Ok((syscall::S_IFDIR | 0o755, 3, 1_700_000_000)) && orphans == 0 { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: VFS_MOUNT_EXPORT ............ ");
+    if cfg!(target_arch = "wasm32") { report.push_str("\x1b[33mSKIP\x1b[0m (no host file system)\n"); } else {
+        let host = std::env::temp_dir().join(format!("dre-mount-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&host);
+        let _ = std::fs::create_dir_all(host.join("ro/sub")); let _ = std::fs::create_dir_all(host.join("ov"));
+        let _ = std::fs::write(host.join("ro/sub/a.txt"), "abc"); let _ = std::fs::write(host.join("ov/b.txt"), "host");
+        let src21 = "#include <fcntl.h>
+        #include <unistd.h>
+        int main() {
+            int r = 0;
+            if (open(\"/ro/sub/a.txt\", O_WRONLY, 0) == 0 - 1) { if (errno == EROFS) { r = r + 1; } }
+            if (open(\"/ro/new.txt\", O_CREAT + O_WRONLY, 420) == 0 - 1) { if (errno == EROFS) { r = r + 2; } }
+            if (unlink(\"/ro/sub/a.txt\") == 0 - 1) { r = r + 4; }
+            int fd = open(\"/ov/b.txt\", O_WRONLY + O_TRUNC, 0);
+            write(fd, \"guest\", 5);
+            close(fd);
+            fd = open(\"/ov/c.txt\", O_CREAT + O_WRONLY, 420);
+            write(fd, \"new\", 3);
+            return r;
+        }";
+        let mut cc21 = MiniCC::new(src21, &std_vfs);
+        let mut vm21 = Machine::new(); vm21.load(&Assembler::compile_bef(&cc21.compile(), &cc21.data));
+        let mounted = vm21.vfs.mount("/ro", &host.join("ro"), vfs::MountMode::ReadOnly).and(vm21.vfs.mount("/ov", &host.join("ov"), vfs::MountMode::Overlay));
+        let out21 = vm21.run(SUITE_GAS);
+        let host_untouched = std::fs::read(host.join("ov/b.txt")).ok() == Some(b"host".to_vec()) && !host.join("ov/c.txt").exists();
+        let exported = vm21.vfs.export("/ov", &host.join("out")).ok() == Some(2) && std::fs::read(host.join("out/b.txt")).ok() == Some(b"guest".to_vec());
+        let _ = std::fs::remove_dir_all(&host);
+        if mounted == Ok(1) && out21 == RunOutcome::Halted(7) && host_untouched && exported && vm21.vfs.get("/ro/sub/a.txt").as_deref() == Some(&b"abc"[..]) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+    }
+
+    report.push_str("TEST: VFS_IMAGE_REPRODUCIBLE ...... ");
+    let mut fs_a = vfs::Vfs::new(); let mut fs_b = vfs::Vfs::new();
+    let _ = fs_a.write_file("/env/usr/include/answer.h", b"#define ANSWER 42".to_vec());
+    let _ = fs_a.write_file("/env/src/x.c", b"int x;".to_vec());
+    let _ = fs_a.symlink("../usr/include/answer.h", "/env/src/a.h");
+    let _ = fs_a.link("/env/src/x.c", "/env/src/y.c");
+    // same tree, built in another order at another time
+    fs_b.now = 99;
+    let _ = fs_b.write_file("/env/src/x.c", b"int x;".to_vec());
+    let _ = fs_b.link("/env/src/x.c", "/env/src/y.c");
+    let _ = fs_b.symlink("../usr/include/answer.h", "/env/src/a.h");
+    let _ = fs_b.write_file("/env/usr/include/answer.h", b"#define ANSWER 42".to_vec());
+    let img = fs_a.pack("/env").unwrap_or_default();
+    let same = Ok(img.clone()) == fs_b.pack("/env");
+    let mut vm22 = Machine::new();
+    let loaded = vm22.vfs.unpack("/", &img);
+    let linked = vm22.vfs.resolve("/src/y.c").ok() == vm22.vfs.resolve("/src/x.c").ok() && vm22.vfs.get("/src/a.h").as_deref() == Some(&b"#define ANSWER 42"[..]);
+    let mut corrupt = img.clone(); if let Some(b) = corrupt.get_mut(20) { *b ^= 1; }
+    // a correctly checksummed image whose mode carries file-type bits is rejected too
+    if let Ok(ino) = fs_a.resolve("/env/src/x.c") { if let Ok(n) = fs_a.node_mut(ino) { n.mode = 0o100644; } }
+    let typed = fs_a.pack("/env").map(|i| image::read_image(&i).is_err()).unwrap_or(false);
+    let includes = image::include_map(&img, "/usr/include").unwrap_or_default();
+    let mut cc22 = MiniCC::new("#include <answer.h>\nint main() { return ANSWER; }", &includes);
+    vm22.load(&Assembler::compile_bef(&cc22.compile(), &cc22.data));
+    if same && loaded == Ok(7) && linked && image::read_image(&corrupt).is_err() && typed && vm22.run(SUITE_GAS) == RunOutcome::Halted(42) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: VFS_CHUNKS_COW_MERKLE ....... ");
+    let big: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let mut vm23 = Machine::new();
+    let _ = vm23.vfs.write_file("/data/a.bin", big.clone()); let _ = vm23.vfs.write_file("/data/b.bin", big.clone());
+    let deduped = vm23.vfs.store.live() == 3;
+    let root_before = vm23.vfs.merkle_root();
+    let mut fork = vm23.clone();
+    let b_ino = fork.vfs.resolve("/data/b.bin").unwrap_or(0);
+    let _ = fork.vfs.write_at(b_ino, 5000, b"patch");
+    let chunks = |m: &Machine, p: &str| match m.vfs.resolve(p).and_then(|i| m.vfs.node(i)).map(|n| &n.kind) { Ok(vfs::Kind::File(b)) => b.chunks.clone(), _ => Vec::new() };
+    let (orig, forked) = (chunks(&vm23, "/data/b.bin"), chunks(&fork, "/data/b.bin"));
+    let cow = orig.len() == 3 && forked.len() == 3 && std::rc::Rc::ptr_eq(&orig[0].data, &forked[0].data) && !std::rc::Rc::ptr_eq(&orig[1].data, &forked[1].data) && vm23.vfs.get("/data/b.bin") == Some(big.clone());
+    let roots = vm23.vfs.merkle_root() == root_before && fork.vfs.merkle_root() != root_before;
+    let snap23 = fork.snapshot(None);
+    let restored = Machine::restore(&snap23, None).map(|m| m.vfs.merkle_root() == fork.vfs.merkle_root()).unwrap_or(false);
+    let _ = fork.vfs.set_len(b_ino, 1 << 20);
+    let sparse = fork.vfs.store.live() <= 7 && fork.vfs.get("/data/b.bin").map(|f| f.len()) == Some(1 << 20)
+        && fork.vfs.set_len(b_ino, usize::MAX) == Err(syscall::errno::EFBIG) && fork.vfs.write_at(b_ino, usize::MAX, b"x") == Err(syscall::errno::EFBIG);
+    if deduped && cow && roots && restored && snap23.len() < big.len() + 8192 && sparse { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: SHARED_VFS_BUILD ............ ");
+    // Stage one writes a header into its VFS; the driver then builds a program from that same tree, which
+    // includes both the guest-written header and libc from /usr/include.
+    let mut vm24 = Machine::new();
+    let installed = install_libc(&mut vm24.vfs);
+    let _ = vm24.vfs.write_file("/src/gen.c", b"#include \"seven.h\"\n#include <errno.h>\nint main() { errno = SEVEN; return errno; }".to_vec());
+    let mut cc24 = MiniCC::new("#include <fcntl.h>\n#include <unistd.h>\nint main() { int fd = open(\"/src/seven.h\", O_CREAT + O_WRONLY, 420); return write(fd, \"#define SEVEN 77\", 16); }", &vm24.vfs);
+    vm24.load(&Assembler::compile_bef(&cc24.compile(), &cc24.data));
+    let stage1 = vm24.run(SUITE_GAS);
+    let built = build_file(&mut vm24.vfs, "/src/gen.c", "/bin/gen.bef");
+    let mut vm25 = Machine::new(); vm25.vfs = vm24.vfs.clone();
+    let rejected = vm25.load_file("/src/gen.c").is_err();
+    let loaded = vm25.load_file("/bin/gen.bef");
+    if installed.is_ok() && stage1 == RunOutcome::Halted(16) && built.is_ok() && rejected && loaded.is_ok() && vm25.run(SUITE_GAS) == RunOutcome::Halted(77) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: SEEK_PREAD_TRUNCATE ......... ");
+    // Patches a header after writing the body, leaves a zero-filled hole past the end, and checks that
+    // pread/pwrite leave the offset alone and ftruncate shrinks and zero-extends, but not past `Limits::file_bytes`.
+    let src26 = "#include <fcntl.h>
+    #include <unistd.h>
+    int main() {
+        int r = 0;
+        char buf[8];
+        int fd = open(\"/tmp/obj\", O_CREAT + O_RDWR, 420);
+        write(fd, \"HDR?body\", 8);
+        lseek(fd, 3, SEEK_SET);
+        write(fd, \"!\", 1);
+        if (lseek(fd, 0, SEEK_CUR) == 4) { r = r + 1; }
+        lseek(fd, 4, SEEK_END);
+        write(fd, \"T\", 1);
+        buf[0] = 7;
+        if (pread(fd, buf, 2, 9) == 2) { if (buf[0] == 0) { r = r + 2; } }
+        pwrite(fd, \"P\", 1, 0);
+        if (lseek(fd, 0, SEEK_CUR) == 13) { r = r + 4; }
+        if (pread(fd, buf, 1, 0 - 1) == 0 - 1) { if (errno == EINVAL) { r = r + 8; } }
+        if (pread(0, buf, 1, 0) == 0 - 1) { if (errno == ESPIPE) { r = r + 16; } }
+        int ro = open(\"/tmp/obj\", O_RDONLY, 0);
+        if (ftruncate(ro, 0) == 0 - 1) { if (errno == EINVAL) { r = r + 32; } }
+        ftruncate(fd, 6);
+        ftruncate(fd, 8);
+        if (ftruncate(fd, 65536 * 65536 * 8) == 0 - 1) { if (errno == EFBIG) {
+            lseek(fd, 65536 * 65536 * 8, SEEK_SET);
+            if (write(fd, \"x\", 1) == 0 - 1) { if (errno == EFBIG) { if (pwrite(fd, \"x\", 1, 65536 * 65536 * 8) == 0 - 1) { r = r + 64; } } }
+        } }
+        return r;
+    }";
+    let mut cc26 = MiniCC::new(src26, &std_vfs);
+    let mut vm26 = Machine::new(); vm26.load(&Assembler::compile_bef(&cc26.compile(), &cc26.data));
+    let out26 = vm26.run(SUITE_GAS);
+    if out26 == RunOutcome::Halted(127) && vm26.vfs.get("/tmp/obj").as_deref() == Some(&b"PDR!bo\0\0"[..]) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: STDIN_BLOCKS_STDOUT_STREAMS . ");
+    // An echo loop blocks on empty stdin, streams what it has echoed so far, and ends when stdin is closed;
+    // feeding the input in pieces costs exactly the same gas as providing it all up front.
+    let src27 = "#include <unistd.h>
+    int main() {
+        char buf[8];
+        int n = 0;
+        while (read(0, buf, 1) == 1) { write(1, buf, 1); n = n + 1; }
+        return n;
+    }";
+    let mut cc27 = MiniCC::new(src27, &std_vfs);
+    let bef27 = Assembler::compile_bef(&cc27.compile(), &cc27.data);
+    let mut vm27 = Machine::new(); vm27.load(&bef27);
+    let first = vm27.run(SUITE_GAS);
+    let (ip27, depth27) = (vm27.ip, vm27.stack.len());
+    let still = vm27.run(SUITE_GAS) == RunOutcome::WaitingForInput(0) && vm27.ip == ip27 && vm27.stack.len() == depth27;
+    vm27.push_stdin(b"ab");
+    let second = vm27.run(SUITE_GAS);
+    let out_ab = vm27.drain_output(1);
+    vm27.push_stdin(b"c"); vm27.close_stdin();
+    let done = vm27.run(SUITE_GAS);
+    let mut vm28 = Machine::new(); vm28.load(&bef27); vm28.push_stdin(b"abc"); vm28.close_stdin();
+    let whole = vm28.run(SUITE_GAS);
+    if first == RunOutcome::WaitingForInput(0) && still && second == RunOutcome::WaitingForInput(0) && out_ab == b"ab" && done == RunOutcome::Halted(3) && vm27.drain_output(1) == b"c" && vm27.drain_output(1).is_empty() && whole == RunOutcome::Halted(3) && vm28.gas_used == vm27.gas_used { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: DEVICE_FILES ................ ");
+    // stderr is its own stream, /dev/tty writes land on stdout, null/zero behave as on Linux, /dev/urandom
+    // replays per seed, and devices survive a snapshot.
+    let src29 = "#include <fcntl.h>
+    #include <unistd.h>
+    #include <sys/ioctl.h>
+    int main() {
+        int r = 0;
+        char buf[8];
+        write(STDERR_FILENO, \"err\", 3);
+        write(STDOUT_FILENO, \"out\", 3);
+        int dn = open(\"/dev/null\", O_RDWR, 0);
+        if (write(dn, \"gone\", 4) == 4) { if (read(dn, buf, 8) == 0) { r = r + 1; } }
+        int dz = open(\"/dev/zero\", O_RDONLY, 0);
+        buf[3] = 7;
+        if (read(dz, buf, 8) == 8) { if (buf[3] == 0) { r = r + 2; } }
+        int rnd = open(\"/dev/urandom\", O_RDONLY, 0);
+        read(rnd, buf, 8);
+        int f = open(\"/tmp/r\", O_CREAT + O_WRONLY, 420);
+        write(f, buf, 8);
+        if (isatty(0)) { if (isatty(f) == 0) { if (errno == ENOTTY) { r = r + 4; } } }
+        int tty = open(\"/dev/tty\", O_RDWR, 0);
+        write(tty, \"T\", 1);
+        if (ioctl(tty, TIOCGWINSZ, buf) == 0) { if (buf[0] == 24) { if (buf[2] == 80) { r = r + 8; } } }
+        if (ioctl(dn, TCGETS, 0) == 0 - 1) { if (errno == ENOTTY) { r = r + 16; } }
+        return r;
+    }";
+    let mut cc29 = MiniCC::new(src29, &std_vfs);
+    let bef29 = Assembler::compile_bef(&cc29.compile(), &cc29.data);
+    let seeded = |seed: u64| { let mut vm = Machine::new(); vm.load(&bef29); vm.seed_random(seed); let out = vm.run(SUITE_GAS); (out, vm) };
+    let ((out29, mut vm29), (_, vm30), (_, vm31)) = (seeded(42), seeded(42), seeded(43));
+    let restored29 = Machine::restore(&vm29.snapshot(Some(&bef29)), Some(&bef29)).map(|m| m.vfs.merkle_root());
+    let random = vm29.vfs.get("/tmp/r").unwrap_or_default();
+    let replays = random.len() == 8 && vm30.vfs.get("/tmp/r") == Some(random.clone()) && vm31.vfs.get("/tmp/r").is_some_and(|r| r != random);
+    if out29 == RunOutcome::Halted(31) && vm29.drain_output(1) == b"outT" && vm29.drain_output(2) == b"err" && replays && restored29 == Ok(vm30.vfs.merkle_root()) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: PROCESS_ARGS_EXIT ........... ");
+    // main sees the argv and envp the host laid out; exit() and a plain return both set the exit code.
+    let src32 = "#include <stdlib.h>
+    int main(int argc, char** argv, char** envp) {
+        int r = 0;
+        char* s = argv[2];
+        if (s[2] == 99) { r = r + 1; }
+        char* e = envp[1];
+        if (e[0] == 66) { r = r + 2; }
+        if (argv[3] == 0) { if (envp[2] == 0) { r = r + 4; } }
+        exit(256 + r + argc);
+        return 1;
+    }";
+    let mut cc32 = MiniCC::new(src32, &std_vfs);
+    let mut vm32 = Machine::new(); vm32.load(&Assembler::compile_bef(&cc32.compile(), &cc32.data));
+    let args32 = vm32.set_args(&["prog", "-v", "abc"], &["A=1", "B=2"]);
+    let running = vm32.exit_code().is_none();
+    let out32 = vm32.run(SUITE_GAS);
+    let mut cc33 = MiniCC::new("int main(int argc, char** argv) { char* p = argv[0]; return argc + p[0]; }", &std_vfs);
+    let mut vm33 = Machine::new(); vm33.load(&Assembler::compile_bef(&cc33.compile(), &cc33.data));
+    let _ = vm33.set_args(&["a", "b"], &[]);
+    let out33 = vm33.run(SUITE_GAS);
+    let too_big = Machine::new().set_args(&[&"x".repeat(ARG_MAX)], &[]).is_err();
+    if args32.is_ok() && running && out32 == RunOutcome::Halted(266) && vm32.exit_code() == Some(10) && out33 == RunOutcome::Halted(99) && vm33.exit_code() == Some(99) && too_big { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: PROCESS_SPAWN_WAIT .......... ");
+    // The parent spawns a BEF from the VFS and forks itself; waitpid collects both exit codes, and output
+    // interleaves the same way on every run, including one resumed from a snapshot taken mid-run.
+    let mut vm34 = Machine::new();
+    let _ = install_libc(&mut vm34.vfs);
+    let _ = vm34.vfs.write_file("/src/child.c", b"#include <unistd.h>\nint main(int argc, char** argv) { char* a = argv[1]; write(1, a, 1); return 40 + argc + getppid(); }".to_vec());
+    let built34 = build_file(&mut vm34.vfs, "/src/child.c", "/bin/child");
+    let src34 = "#include <unistd.h>
+    #include <sys/wait.h>
+    int st[1];
+    int argv[3];
+    int main() {
+        int r = 0;
+        argv[0] = \"child\"; argv[1] = \"x\"; argv[2] = 0;
+        int pid = spawn(\"/bin/child\", argv, 0);
+        if (waitpid(pid, st, 0) == pid) { if (WIFEXITED(st[0])) { if (WEXITSTATUS(st[0]) == 43) { r = r + 1; } } }
+        if (spawn(\"/tmp\", argv, 0) == 0 - 1) { if (errno == EACCES) { r = r + 2; } }
+        if (wait(st) == 0 - 1) { if (errno == ECHILD) { r = r + 4; } }
+        int f = fork();
+        if (f == 0) { int i = 0; while (i < 3) { write(1, \"c\", 1); i = i + 1; } return getpid() + 5; }
+        int j = 0;
+        while (j < 3) { write(1, \"p\", 1); j = j + 1; }
+        if (waitpid(0 - 1, st, WNOHANG) == 0) { r = r + 8; }
+        if (wait(st) == f) { if (st[0] == f + 5) { r = r + 16; } }
+        if (getpid() == 1) { if (getppid() == 0) { r = r + 32; } }
+        return r;
+    }";
+    let mut cc34 = MiniCC::new(src34, &vm34.vfs);
+    let bef34 = Assembler::compile_bef(&cc34.compile(), &cc34.data);
+    vm34.load(&bef34); vm34.quantum = 20;
+    let mut vm35 = vm34.clone();
+    let out34 = vm34.run(SUITE_GAS);
+    let text34 = vm34.drain_output(1);
+    let mut part35 = Vec::new();
+    let paused = vm35.run(1100) == RunOutcome::OutOfGas && !vm35.procs.is_empty();
+    part35.extend(vm35.drain_output(1));
+    let resumed = Machine::restore(&vm35.snapshot(Some(&bef34)), Some(&bef34)).map(|mut m| { let out = m.run(SUITE_GAS); part35.extend(m.drain_output(1)); (out, m.procs.len()) });
+    if built34.is_ok() && out34 == RunOutcome::Halted(63) && text34 == b"xpcpcpc" && paused && resumed == Ok((RunOutcome::Halted(63), 0)) && part35 == text34 { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: PIPE_BETWEEN_PROCESSES ...... ");
+    // 6400 bytes through a 4096-byte pipe: the writer blocks when it is full, the reader when it is empty, and
+    // the reader sees end of file once the child exits. Writing to a pipe nobody reads fails with EPIPE, and a
+    // lone process reading its own empty pipe is a deadlock.
+    let src36 = "#include <unistd.h>
+    #include <sys/wait.h>
+    int p[2];
+    int q[2];
+    int st[1];
+    char chunk[64];
+    int main() {
+        int r = 0;
+        pipe(p);
+        int pid = fork();
+        if (pid == 0) {
+            close(p[0]);
+            int i = 0;
+            while (i < 100) { chunk[0] = 65 + i; write(p[1], chunk, 64); i = i + 1; }
+            return 0;
+        }
+        close(p[1]);
+        int total = 0;
+        int n = read(p[0], chunk, 64);
+        while (n > 0) { total = total + n; n = read(p[0], chunk, 64); }
+        close(p[0]);
+        if (total == 6400) { r = r + 1; }
+        if (wait(st) == pid) { r = r + 2; }
+        pipe(q);
+        close(q[0]);
+        if (write(q[1], chunk, 1) == 0 - 1) { if (errno == EPIPE) { r = r + 4; } }
+        return r;
+    }";
+    let mut cc36 = MiniCC::new(src36, &std_vfs);
+    let bef36 = Assembler::compile_bef(&cc36.compile(), &cc36.data);
+    let piped = |quantum: u64| { let mut vm = Machine::new(); vm.load(&bef36); vm.quantum = quantum; let out = vm.run(SUITE_GAS); (out, vm.gas_used, vm.vfs.inodes.len()) };
+    let (out36, gas36, inodes36) = piped(50);
+    let mut cc37 = MiniCC::new("#include <unistd.h>\nint p[2];\nchar c[1];\nint main() { pipe(p); return read(p[0], c, 1); }", &std_vfs);
+    let mut vm37 = Machine::new(); vm37.load(&Assembler::compile_bef(&cc37.compile(), &cc37.data));
+    let deadlock = matches!(vm37.run(SUITE_GAS), RunOutcome::Trapped(t) if t.kind == TrapKind::Deadlock);
+    if out36 == RunOutcome::Halted(7) && piped(50) == (out36.clone(), gas36, inodes36) && piped(3).0 == out36 && inodes36 == Machine::new().vfs.inodes.len() + 1 && deadlock { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: DEADLOCK_AFTER_CHILD_EXIT ... ");
+    // The child exits while its parent still holds the write end it reads from, so nothing can run. The run must
+    // stop on the parent, not the zombie, with an address space that snapshots and restores.
+    let mut cc49 = MiniCC::new("#include <unistd.h>\nint p[2];\nchar c[1];\nint main() { pipe(p); if (fork() == 0) { return 3; } return read(p[0], c, 1); }", &std_vfs);
+    let bef49 = Assembler::compile_bef(&cc49.compile(), &cc49.data);
+    let mut vm49 = Machine::new(); vm49.load(&bef49);
+    let stuck = matches!(vm49.run(SUITE_GAS), RunOutcome::Trapped(t) if t.kind == TrapKind::Deadlock);
+    let snap49 = vm49.snapshot(Some(&bef49));
+    let round_trip = Machine::restore(&snap49, Some(&bef49)).map(|m| m.snapshot(Some(&bef49)) == snap49).unwrap_or(false);
+    let mut gutted = vm49.clone(); gutted.memory.clear(); gutted.perms.clear();
+    let guarded = Machine::restore(&gutted.snapshot(Some(&bef49)), Some(&bef49)).map(|m| m.memory.is_empty()).unwrap_or(false);
+    let again = matches!(vm49.run(SUITE_GAS), RunOutcome::Trapped(t) if t.kind == TrapKind::Deadlock);
+    if stuck && vm49.pid == process::INIT_PID && vm49.memory.len() >= HEAP_BASE && round_trip && guarded && again { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: HEAP_ALLOCATOR .............. ");
+    // Freed blocks are reused and merged with free neighbours, calloc zeroes a recycled block, realloc keeps the
+    // contents, a malloc/free loop does not move the break, and an impossible request fails with ENOMEM.
+    let src38 = "#include <stdlib.h>
+    int main() {
+        int r = 0;
+        char* a = malloc(40);
+        char* b = malloc(40);
+        char* c = malloc(100);
+        free(b);
+        char* d = malloc(24);
+        if (d == b) { r = r + 1; }
+        free(a); free(d);
+        char* e = malloc(96);
+        if (e == a) { r = r + 2; }
+        e[5] = 9;
+        free(e);
+        int* z = calloc(4, 8);
+        if (z == a) { if (z[0] == 0) { r = r + 4; } }
+        c[0] = 7; c[99] = 8;
+        char* g = realloc(c, 4000);
+        if (g[0] == 7) { if (g[99] == 8) { r = r + 8; } }
+        int top = syscall(4, 0);
+        int i = 0;
+        while (i < 500) { char* t = malloc(1000); char* u = malloc(300); free(t); free(u); i = i + 1; }
+        if (syscall(4, 0) - top < 1400) { r = r + 16; }
+        if (malloc(100000000) == 0) { if (errno == ENOMEM) { r = r + 32; } }
+        if (syscall(4, 0 - 1048576) == 0 - ENOMEM) { if (syscall(4, 1073741824) == 0 - ENOMEM) { if (calloc(1, 17) > 0) { r = r + 64; } } }
+        return r;
+    }";
+    let mut cc38 = MiniCC::new(src38, &std_vfs);
+    let mut vm38 = Machine::new(); vm38.load(&Assembler::compile_bef(&cc38.compile(), &cc38.data));
+    let out38 = vm38.run(SUITE_GAS);
+    if out38 == RunOutcome::Halted(127) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: MEMORY_PROTECTION ........... ");
+    let run39 = |src: &str| { let mut cc = MiniCC::new(src, &std_vfs); let mut vm = Machine::new(); vm.load(&Assembler::compile_bef(&cc.compile(), &cc.data)); (vm.run(SUITE_GAS), vm) };
+    let fault = |o: &RunOutcome| match o { RunOutcome::Trapped(Trap { kind: TrapKind::ProtectionFault(a, access), .. }) => Some((*a, *access)), _ => None };
+    let (null39, _) = run39("int main() { int* p = 0; return *p; }");
+    let (rodata39, _) = run39("int main() { char* s = \"abc\"; s[1] = 65; return 0; }");
+    let (code39, _) = run39("int main() { char* f = &main; return f[0]; }");
+    let (exec39, _) = run39("int g; int main() { int f = &g; return (*f)(1); }");
+    let null_msg = matches!(&null39, RunOutcome::Trapped(t) if t.to_string().contains("read at 0 (null pointer)"));
+    let (heap39, vm39) = run39("#include <stdlib.h>\n#include <fcntl.h>\n#include <unistd.h>\n
+    int g;
+    int main() {
+        int r = 0;
+        char* big = malloc(2000000);
+        if (big > 0) { big[1999999] = 5; if (big[1999999] == 5) { r = r + 1; } }
+        int fd = open(\"/dev/zero\", O_RDONLY, 0);
+        if (read(fd, \"abc\", 3) == 0 - 1) { if (errno == EFAULT) { r = r + 2; } }
+        if (read(fd, &g, 8) == 8) { r = r + 4; }
+        if (read(fd, 0, 1) == 0 - 1) { r = r + 8; }
+        if (\"abc\" == \"abc\") { r = r + 16; }
+        return r;
+    }");
+    let grown = vm39.memory.len() > 2000000 && vm39.perms.len() == vm39.memory.len() / PAGE_SIZE;
+    if fault(&null39) == Some((0, Access::Read)) && null_msg && fault(&rodata39) == Some((DATA_BASE + 1, Access::Write)) && matches!(fault(&code39), Some((a, Access::Read)) if (CODE_BASE..DATA_BASE).contains(&a))
+        && matches!(fault(&exec39), Some((a, Access::Execute)) if a >= DATA_BASE) && heap39 == RunOutcome::Halted(31) && grown { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: STACK_LIMITS ................ ");
+    let run40 = |src: &str, limits: Limits| { let mut cc = MiniCC::new(src, &std_vfs); let mut vm = Machine::new(); vm.limits = limits; vm.load(&Assembler::compile_bef(&cc.compile(), &cc.data)); (vm.run(SUITE_GAS), vm) };
+    let kind = |o: &RunOutcome| match o { RunOutcome::Trapped(t) => Some(t.kind.clone()), _ => None };
+    let runaway = "int f(int n) { return f(n + 1); } int main() { return f(0); }";
+    let (calls40, vm40) = run40(runaway, Limits::default());
+    let (frames40, _) = run40(runaway, Limits { call_depth: 1 << 20, ..Limits::default() });
+    let (stack40, _) = run40("int f(int n) { return n + f(n + 1); } int main() { return f(0); }", Limits { stack_depth: 100, ..Limits::default() });
+    let (ok40, vm40b) = run40("int d(int n) { if (n > 0) { return d(n - 1); } return 7; } int main() { return d(10); }", Limits::default());
+    let hw = vm40b.high_water;
+    if kind(&calls40) == Some(TrapKind::CallDepthExceeded) && vm40.call_stack.len() == Limits::default().call_depth && matches!(kind(&frames40), Some(TrapKind::FrameOverflow(_)))
+        && kind(&stack40) == Some(TrapKind::StackOverflow) && ok40 == RunOutcome::Halted(7) && hw.call_depth == 12 && hw.frame_bytes == 88 && hw.stack_depth > 0 { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: DIV_MOD ..................... ");
+    let run43 = |src: &str| { let mut cc = MiniCC::new(src, &std_vfs); let mut vm = Machine::new(); vm.load(&Assembler::compile_bef(&cc.compile(), &cc.data)); (vm.run(SUITE_GAS), vm) };
+    // Division is unsigned: -7 / 2 is (2^64 - 7) / 2.
+    let (ok43, _) = run43("int main() { int a = 0 - 7; int r = 0; if (47 / 5 == 9) { r = r + 1; } if (47 % 5 == 2) { r = r + 2; } if (2 + 12 / 4 * 3 % 5 == 6) { r = r + 4; } if (a / 2 == 9223372036854775804) { r = r + 8; } if (a % 10 == 9) { r = r + 16; } return r; }");
+    let by_zero = |op: u8, src: &str| { let (o, vm) = run43(src); matches!(o, RunOutcome::Trapped(t) if t.kind == TrapKind::DivideByZero && vm.memory[t.ip] == op && t.to_string().contains("division by zero")) };
+    let gas43 = GasTable::default();
+    if ok43 == RunOutcome::Halted(31) && by_zero(0x23, "int main() { int z = 0; return 5 / z; }") && by_zero(0x27, "int main() { int z = 0; return 5 % z; }")
+        && opcode_info(0x23) == Some(("DIV", false)) && opcode_info(0x27) == Some(("MOD", false)) && gas43.cost(0x23) == 3 && gas43.cost(0x27) == 3 { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: NE_AND_CHAR_CONSTANTS ....... ");
+    let mut cc44 = MiniCC::new("#define q 5
+    int main() {
+        int r = 0;
+        if (3 != 4) { r = r + 1; }
+        if (4 != 4) { r = r + 2; }
+        if ('a' == 97) { r = r + 4; }
+        if ('\\n' == 10) { if ('\\t' == 9) { if ('\\r' == 13) { r = r + 8; } } }
+        if ('\\0' == 0) { r = r + 16; }
+        if ('\\'' == 39) { if ('\\\\' == 92) { r = r + 32; } }
+        if ('q' == 113) { if (q == 5) { r = r + 64; } }
+        if ('\"' != 0) { if (1 + 1 != 3 - 1) { r = r + 2; } else { r = r + 128; } }
+        return r;
+    }", &std_vfs);
+    let mut vm44 = Machine::new(); vm44.load(&Assembler::compile_bef(&cc44.compile(), &cc44.data));
+    if vm44.run(SUITE_GAS) == RunOutcome::Halted(253) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: FUNCTION_MACROS ............. ");
+    let mut cc45 = MiniCC::new("#define SQ(x) ((x) * (x))
+    #define CUBE(x) (SQ(x) * (x))
+    #define FIRST(a, b) a
+    #define TWICE(f, v) f(f(v))
+    #define ID(x) x
+    int pick(int c, int a, int b) { if (c) { return a; } return b; }
+    int inc(int v) { return v + 1; }
+    int main() {
+        int r = 0;
+        if (SQ(3 + 1) == 16) { r = r + 1; }
+        if (CUBE(2) == 8) { r = r + 2; }
+        if (FIRST(pick(1, 2, 3), 9) == 2) { r = r + 4; }
+        if (TWICE(inc, 5) == 7) { r = r + 8; }
+        char* s = \"SQ(2)\";
+        if (s[0] == 'S') { if (FIRST(',', ')') == ',') { r = r + 16; } }
+        int ID = 3;
+        if (ID == 3) { if (ID (4) == 4) { r = r + 32; } }
+        return r;
+    }", &std_vfs);
+    let mut vm45 = Machine::new(); vm45.load(&Assembler::compile_bef(&cc45.compile(), &cc45.data));
+    if vm45.run(SUITE_GAS) == RunOutcome::Halted(63) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: VARARGS ..................... ");
+    let mut cc46 = MiniCC::new("#include <stdarg.h>
+    int sum(int n, ...) { va_list ap; va_start(ap, n); int s = 0; int i = 0; while (i < n) { s = s + va_arg(ap, int); i = i + 1; } va_end(ap); return s; }
+    int third(int a, int b, ...) { va_list ap; va_start(ap, b); va_arg(ap, int); return va_arg(ap, int); }
+    int peek(int n, ...) { va_list ap; va_start(ap, n); va_list cp; va_copy(cp, ap); va_arg(cp, int); return va_arg(cp, int) * 10 + va_arg(ap, int); }
+    int main() {
+        int r = 0;
+        if (sum(3, 1, 2, 3) == 6) { r = r + 1; }
+        if (sum(0) == 0) { r = r + 2; }
+        if (sum(16, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1) == 16) { r = r + 4; }
+        if (third(9, 9, 4, 5) == 5) { r = r + 8; }
+        if (peek(2, 7, 8) == 87) { r = r + 16; }
+        return r;
+    }", &std_vfs);
+    let mut vm46 = Machine::new(); vm46.load(&Assembler::compile_bef(&cc46.compile(), &cc46.data));
+    if vm46.run(SUITE_GAS) == RunOutcome::Halted(31) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: LARGE_CODE_SECTION .......... ");
+    // Past 8 KiB of code the data section no longer starts at file offset 8192 but straight after the code.
+    let funcs47: String = (0..300).map(|i| format!("int f{}(int x) {{ g = g + 1; return x; }}\n", i)).collect();
+    let calls47: String = (0..300).map(|i| format!("    s = s + f{}(1);\n", i)).collect();
+    let mut cc47 = MiniCC::new(&format!("int g;\n{}int main() {{\n    char* m = \"ok\";\n    int s = 0;\n{}    if (m[1] == 'k') {{ s = s + 1; }}\n    return s + g;\n}}", funcs47, calls47), &std_vfs);
+    let bef47 = Assembler::compile_bef(&cc47.compile(), &cc47.data);
+    let code47 = u32::from_le_bytes(bef47[8..12].try_into().unwrap()) as usize;
+    let at47 = bef_data_offset(code47);
+    let mut big47 = bef47.clone(); big47[8..12].copy_from_slice(&(CODE_MAX as u32 + 1).to_le_bytes());
+    let mut vm47 = Machine::new(); vm47.load(&bef47);
+    if code47 > 8192 && at47 == 16 + code47 && bef47.get(at47..at47 + 3) == Some(&b"ok\0"[..]) && is_bef(&bef47) && !is_bef(&big47) && bef_data_offset(100) == 8192
+        && vm47.run(SUITE_GAS) == RunOutcome::Halted(601) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: PRINTF_CONFORMANCE .......... ");
+    // Expected lines are glibc's output for the same calls.
+    let cases41: [(&str, &str); 59] = [
+        (r#""%d", 42"#, "42"), (r#""%d", 0 - 42"#, "-42"), (r#""%i", 7"#, "7"), (r#""%5d", 42"#, "   42"), (r#""%-5d|", 42"#, "42   |"),
+        (r#""%05d", 42"#, "00042"), (r#""%05d", 0 - 42"#, "-0042"), (r#""%+d", 42"#, "+42"), (r#""% d", 42"#, " 42"), (r#""%+d", 0 - 42"#, "-42"),
+        (r#""%.3d", 7"#, "007"), (r#""%8.3d", 0 - 7"#, "    -007"), (r#""%-8.3d|", 7"#, "007     |"), (r#""%08.3d", 7"#, "     007"),
+        (r#""%.0d|", 0"#, "|"), (r#""%lu", 3000000000"#, "3000000000"), (r#""%lu", 0 - 1"#, "18446744073709551615"), (r#""%x", 255"#, "ff"),
+        (r#""%X", 48879"#, "BEEF"), (r#""%#x", 255"#, "0xff"), (r#""%#X", 255"#, "0XFF"), (r#""%#x", 0"#, "0"), (r#""%o", 8"#, "10"),
+        (r#""%#o", 8"#, "010"), (r#""%#o", 0"#, "0"), (r#""%#.0o", 0"#, "0"), (r#""%#08x", 255"#, "0x0000ff"),
+        (r#""%lld", 9223372036854775807"#, "9223372036854775807"), (r#""%ld", 0 - 9223372036854775807 - 1"#, "-9223372036854775808"),
+        (r#""%hd", 65535"#, "-1"), (r#""%hhu", 257"#, "1"), (r#""%hhd", 200"#, "-56"), (r#""%hx", 74565"#, "2345"), (r#""%zu", 12"#, "12"),
+        (r#""%c", 'A'"#, "A"), (r#""%3c|", 'x'"#, "  x|"), (r#""%-3c|", 'x'"#, "x  |"), (r#""%s", "hello""#, "hello"),
+        (r#""%10s|", "hi""#, "        hi|"), (r#""%-10s|", "hi""#, "hi        |"), (r#""%.2s", "hello""#, "he"), (r#""%*d", 6, 42"#, "    42"),
+        (r#""%-*d|", 6, 42"#, "42    |"), (r#""%*d|", 0 - 6, 42"#, "42    |"), (r#""%.*d", 4, 7"#, "0007"), (r#""%%""#, "%"),
+        (r#""a%%b%dc", 1"#, "a%b1c"), (r#""%p", 0"#, "(nil)"), (r#""%p", 4096"#, "0x1000"), (r#""%s=%d, %s=%x", "a", 1, "b", 255"#, "a=1, b=ff"),
+        (r#""%+.3d", 5"#, "+005"), (r#""% 05d", 42"#, " 0042"), (r#""%-+6d|", 42"#, "+42   |"), (r#""%5s|", "toolong""#, "toolong|"),
+        (r#""%.0s|", "abc""#, "|"), (r#""%lx", 0 - 1"#, "ffffffffffffffff"), (r#""%#lo", 0 - 1"#, "01777777777777777777777"), (r#""%u", 0"#, "0"),
+        (r#""%5.1s|", "abc""#, "    a|")
+    ];
+    let calls41: String = cases41.iter().map(|(args, _)| format!("    printf({}); printf(\"%c\", 10);\n", args)).collect();
+    let mut cc41 = MiniCC::new(&format!("#include <stdio.h>\nint main() {{\n{}    return 0;\n}}", calls41), &std_vfs);
+    let mut vm41 = Machine::new(); vm41.load(&Assembler::compile_bef(&cc41.compile(), &cc41.data));
+    let out41 = vm41.run(SUITE_GAS);
+    let lines41 = String::from_utf8_lossy(&vm41.drain_output(1)).into_owned();
+    let table41 = out41 == RunOutcome::Halted(0) && lines41.split('\n').zip(cases41.iter()).all(|(got, (_, want))| got == *want) && lines41.lines().count() == cases41.len();
+    let mut cc41b = MiniCC::new("#include <stdio.h>\n
+    char buf[32];
+    int same(char* a, char* b) { while (a[0] == b[0]) { if (a[0] == 0) { return 1; } a = a + 1; b = b + 1; } return 0; }
+    int fmtto(char* out, int n, char* fmt, ...) { va_list ap; va_start(ap, fmt); int r = vsnprintf(out, n, fmt, ap); va_end(ap); return r; }
+    int say(char* fmt, ...) { va_list ap; va_start(ap, fmt); int r = vprintf(fmt, ap); va_end(ap); return r; }
+    int main() {
+        int r = 0;
+        if (snprintf(buf, 6, \"%d-%s\", 12345, \"abc\") == 9) { if (same(buf, \"12345\")) { r = r + 1; } }
+        if (snprintf(0, 0, \"%05x\", 255) == 5) { r = r + 2; }
+        if (sprintf(buf, \"[%3d]\", 7) == 5) { if (same(buf, \"[  7]\")) { r = r + 4; } }
+        if (fmtto(buf, 32, \"%s:%d\", \"ln\", 42) == 5) { if (same(buf, \"ln:42\")) { r = r + 8; } }
+        if (say(\"v=%x;\", 48879) == 7) { r = r + 16; }
+        if (fprintf(stderr, \"err %d\", 3) == 5) { r = r + 32; }
+        return r;
+    }", &std_vfs);
+    let mut vm41b = Machine::new(); vm41b.load(&Assembler::compile_bef(&cc41b.compile(), &cc41b.data));
+    let out41b = vm41b.run(SUITE_GAS);
+    if table41 && out41b == RunOutcome::Halted(63) && vm41b.drain_output(1) == b"v=beef;" && vm41b.drain_output(2) == b"err 3" { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: UNARY_MINUS ................. ");
+    let mut cc48 = MiniCC::new("#define NEG -1
+    int main() {
+        int a = -5;
+        int r = 0;
+        if (a == 0 - 5) { r = r + 1; }
+        if (-a == 5) { r = r + 2; }
+        if (3 - -2 == 5) { r = r + 4; }
+        if (-2 * 3 == 0 - 6) { r = r + 8; }
+        if (NEG + 2 == 1) { r = r + 16; }
+        return r;
+    }", &std_vfs);
+    let mut vm48 = Machine::new(); vm48.load(&Assembler::compile_bef(&cc48.compile(), &cc48.data));
+    if vm48.run(SUITE_GAS) == RunOutcome::Halted(31) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: STDIO_FILES ................. ");
+    let mut cc42 = MiniCC::new("#include <stdio.h>\n
+    char buf[64];
+    int same(char* a, char* b) { while (a[0] == b[0]) { if (a[0] == 0) { return 1; } a = a + 1; b = b + 1; } return 0; }
+    int main() {
+        int r = 0;
+        FILE* f = fopen(\"/tmp/f42.txt\", \"w\");
+        fputs(\"hello\", f); fputc(10, f);
+        fprintf(f, \"n=%d%c\", 42, 10);
+        fputc('z', f);
+        if (ftell(f) == 12) { r = r + 1; }
+        if (fclose(f) == 0) { r = r + 2; }
+        f = fopen(\"/tmp/f42.txt\", \"r\");
+        if (fgetc(f) == 'h') { if (ungetc('H', f) == 'H') { if (fgetc(f) == 'H') { r = r + 4; } } }
+        if (fgets(buf, 64, f)) { if (buf[4] == 10) { buf[4] = 0; }  if (same(buf, \"ello\")) { if (ftell(f) == 6) { r = r + 8; } } }
+        if (fread(buf, 1, 64, f) == 6) { if (feof(f)) { if (ferror(f) == 0) { if (fgetc(f) == EOF) { r = r + 16; } } } }
+        clearerr(f);
+        if (fseek(f, 2, SEEK_SET) == 0) { if (feof(f) == 0) { if (fgetc(f) == 'l') { if (ftell(f) == 3) { r = r + 32; } } } }
+        if (fwrite(\"x\", 1, 1, f) == 0) { if (ferror(f)) { r = r + 64; } }
+        fclose(f);
+        f = fopen(\"/tmp/f42.txt\", \"a+\");
+        fputs(\"!\", f);
+        fseek(f, 0 - 2, SEEK_END);
+        if (fread(buf, 1, 2, f) == 2) { if (buf[0] == 'z') { if (buf[1] == '!') { r = r + 128; } } }
+        fclose(f);
+        if (fopen(\"/nope/x\", \"r\") == 0) { if (errno == ENOENT) { if (fopen(\"/tmp/f42.txt\", \"q\") == 0) { if (errno == EINVAL) { r = r + 256; } } } }
+        if (fopen(\"/tmp/f42.txt\", \"wx\") == 0) { if (errno == EEXIST) { r = r + 512; } }
+        f = fopen(\"/tmp/f42.txt\", \"r+\");
+        fseek(f, 1, SEEK_SET);
+        fputc('E', f);
+        fseek(f, 0, SEEK_SET);
+        if (fread(buf, 1, 3, f) == 3) { if (buf[1] == 'E') { r = r + 1024; } }
+        fclose(f);
+        printf(\"ready?\");
+        int c = getchar();
+        if (c == 'q') { if (getchar() == 10) { if (getchar() == EOF) { if (feof(stdin)) { r = r + 2048; } } } }
+        printf(\"out:%d\", r);
+        fputs(\"err\", stderr);
+        return r % 256;
+    }", &std_vfs);
+    let mut vm42 = Machine::new(); vm42.load(&Assembler::compile_bef(&cc42.compile(), &cc42.data));
+    // getchar blocks on stdin, which is line buffered, so the prompt has to be out by then.
+    let wait42 = vm42.run(SUITE_GAS);
+    let prompt42 = vm42.drain_output(1);
+    vm42.push_stdin(b"q\n"); vm42.close_stdin();
+    let out42 = vm42.run(SUITE_GAS);
+    let file42 = vm42.vfs.resolve("/tmp/f42.txt").and_then(|ino| vm42.vfs.contents(ino)).unwrap_or_default();
+    if wait42 == RunOutcome::WaitingForInput(0) && prompt42 == b"ready?" && out42 == RunOutcome::Halted(255) && vm42.drain_output(1) == b"out:4095" && vm42.drain_output(2) == b"err"
+        && file42 == b"hEllo\nn=42\nz!" { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
+    report.push_str("TEST: SYSCALL_UNKNOWN_TRAPS ....... ");
+    let mut cc18 = MiniCC::new("int main() { syscall(99, 1); return 0; }", &std_vfs);
+    let mut vm18 = Machine::new(); vm18.load(&Assembler::compile_bef(&cc18.compile(), &cc18.data));
+    match vm18.run(SUITE_GAS) { RunOutcome::Trapped(t) if t.kind == TrapKind::BadSyscall(99) => report.push_str(pass_msg), _ => report.push_str("\x1b[31mFAIL\x1b[0m\n") }
+
+    report.push_str("TEST: TRAP_INVALID_OPCODE ......... ");
+    let mut vm12 = Machine::new(); vm12.memory[CODE_BASE] = 0xFF;
+    if let RunOutcome::Trapped(t) = vm12.run(SUITE_GAS) { if t.ip == CODE_BASE && t.kind == TrapKind::InvalidOpcode(0xFF) { report.push_str(pass_msg); } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); } } else { report.push_str("\x1b[31mFAIL\x1b[0m\n"); }
+
     report
 }
 
 #[wasm_bindgen]
 pub fn init_shell() -> String { run_suite() }
+
+thread_local! { static DEBUG_SESSION: RefCell<Option<debugger::Debugger>> = const { RefCell::new(None) }; }
+
+// Compiles `source` with the libc shim and starts a debugger session for `debug_command`.
+#[wasm_bindgen]
+pub fn debug_open(source: &str) -> String {
+    let dbg = debugger::Debugger::from_c(source, &libc_headers());
+    let banner = format!("loaded {} symbols, stopped at entry\n{}", dbg.symbols.functions.len(), dbg.disasm(CODE_BASE).0);
+    DEBUG_SESSION.with(|s| *s.borrow_mut() = Some(dbg));
+    banner
+}
+
+#[wasm_bindgen]
+pub fn debug_command(line: &str) -> String {
+    DEBUG_SESSION.with(|s| match s.borrow_mut().as_mut() { Some(d) => d.command(line), None => "no program loaded".into() })
+}
+
+thread_local! { static PROGRAM_SESSION: RefCell<Option<Machine>> = const { RefCell::new(None) }; }
+
+// Compiles `source` against libc and loads it for the terminal; drive it with `program_run`.
+#[wasm_bindgen]
+pub fn program_open(source: &str) -> String {
+    let mut vm = Machine::new();
+    if let Err(e) = install_libc(&mut vm.vfs) { return e; }
+    let mut cc = MiniCC::new(source, &vm.vfs);
+    let asm = cc.compile();
+    vm.load(&Assembler::compile_bef(&asm, &cc.data));
+    PROGRAM_SESSION.with(|s| *s.borrow_mut() = Some(vm));
+    "loaded".into()
+}
+
+// Queues terminal input for the program's stdin; `eof` closes it after these bytes.
+#[wasm_bindgen]
+pub fn program_input(data: &str, eof: bool) {
+    PROGRAM_SESSION.with(|s| if let Some(vm) = s.borrow_mut().as_mut() { vm.push_stdin(data.as_bytes()); if eof { vm.close_stdin(); } })
+}
+
+// Runs for up to `budget` gas and returns "running", "waiting", "exited <status>" or the trap.
+#[wasm_bindgen]
+pub fn program_run(budget: u32) -> String {
+    PROGRAM_SESSION.with(|s| match s.borrow_mut().as_mut() {
+        Some(vm) => match vm.run(budget as u64) {
+            RunOutcome::OutOfGas => "running".into(),
+            RunOutcome::WaitingForInput(_) => "waiting".into(),
+            RunOutcome::Halted(_) => format!("exited {}", vm.exit_code().unwrap_or(0)),
+            RunOutcome::Trapped(t) => t.to_string(),
+        },
+        None => "no program loaded".into(),
+    })
+}
+
+// Output written to `fd` (1 stdout) since the last call, for streaming into the terminal.
+#[wasm_bindgen]
+pub fn program_output(fd: u32) -> String {
+    PROGRAM_SESSION.with(|s| s.borrow_mut().as_mut().map_or_else(String::new, |vm| String::from_utf8_lossy(&vm.drain_output(fd as u64)).into_owned()))
+}
@@ -1,3 +1,144 @@
+use std::io::{BufRead, Write};
+use vfs_core::{coverage, debugger, install_libc, libc_headers, profiler, trace, vfs, Assembler, Machine, MiniCC, RunOutcome};
+
+fn read_source(path: &str) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|e| { eprintln!("{}: {}", path, e); std::process::exit(2) })
+}
+
+// Returns the assembly listing (for symbols) and the BEF image.
+fn build(path: &str) -> (String, Vec<u8>) { build_with(path, &libc_headers()) }
+
+fn build_with(path: &str, headers: &dyn vfs::FileSystem) -> (String, Vec<u8>) {
+    let src = read_source(path);
+    let mut cc = MiniCC::new_named(path, &src, headers);
+    let asm = cc.compile();
+    let bef = Assembler::compile_bef(&asm, &cc.data);
+    (asm, bef)
+}
+
+// Values following each occurrence of `flag`, e.g. every `--mount X`.
+fn flag_values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+    args.windows(2).filter(|w| w[0] == flag).map(|w| w[1].as_str()).collect()
+}
+
+// Gas per `run` call in the run subcommand, i.e. how often output is flushed.
+const RUN_SLICE: u64 = 1_000_000;
+
+fn fail(msg: String) -> ! { eprintln!("{}", msg); std::process::exit(2) }
+
 fn main() {
-    print!("{}", vfs_core::run_suite());
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        // pack <host dir> <out.img>: bundle a host tree into a reproducible VFS image
+        Some("pack") if args.len() >= 4 => {
+            let mut fs = vfs::Vfs::new();
+            let img = fs.mount("/", std::path::Path::new(&args[2]), vfs::MountMode::Overlay).and_then(|_| fs.pack("/")).unwrap_or_else(|e| fail(e));
+            if let Err(e) = std::fs::write(&args[3], &img) { fail(format!("{}: {}", args[3], e)); }
+        }
+        // run <file.c> [--image img] [--mount host:guest[:ro]]... [--export guest:host]... [--seed N] [--env K=V]...
+        // [--max-stack N] [--max-calls N] [--max-frame BYTES] [--max-file BYTES] [--high-water] [-- args...]: run a program with libc in /usr/include, an image unpacked at / and host directories mounted
+        // (overlay unless :ro). argv is file.c plus the arguments after --, envp the --env pairs and --seed sets
+        // /dev/urandom. Stdout and stderr stream, host stdin is fed a line at a time, and the exit status is the
+        // program's. It is compiled against that same tree, so headers from the image or a mount override the
+        // built-in ones. The --max flags override the default `Limits`; --high-water reports how much of each a run
+        // used on stderr. Nothing else on the host is visible to the guest, e.g.
+        // `run prog.c --mount vfs_root/usr:/usr:ro -- -v input.txt`.
+        Some("run") if args.len() >= 3 => {
+            // The file is always args[2]; only a later `--` starts the program's args.
+            if args[2] == "--" { fail("usage: run <file.c> [options] [-- args...]".to_string()); }
+            let (args, prog_args) = args.split_at(args.iter().skip(3).position(|a| a == "--").map_or(args.len(), |p| p + 3));
+            let mut vm = Machine::new();
+            if let Err(e) = install_libc(&mut vm.vfs) { fail(e); }
+            if let Some(p) = flag_values(args, "--image").first() {
+                let img = std::fs::read(p).unwrap_or_else(|e| fail(format!("{}: {}", p, e)));
+                if let Err(e) = vm.vfs.unpack("/", &img) { fail(e); }
+            }
+            for spec in flag_values(args, "--mount") {
+                let parts: Vec<&str> = spec.split(':').collect();
+                let mode = match parts.get(2) { None | Some(&"overlay") => vfs::MountMode::Overlay, Some(&"ro") => vfs::MountMode::ReadOnly, Some(m) => fail(format!("unknown mount mode '{}'", m)) };
+                let (Some(host), Some(guest)) = (parts.first(), parts.get(1)) else { fail(format!("bad mount '{}', expected host:guest[:ro]", spec)) };
+                if let Err(e) = vm.vfs.mount(guest, std::path::Path::new(host), mode) { fail(e); }
+            }
+            if let Some(seed) = flag_values(args, "--seed").first() { vm.seed_random(seed.parse().unwrap_or_else(|_| fail(format!("bad seed '{}'", seed)))); }
+            for (flag, limit) in [("--max-stack", &mut vm.limits.stack_depth), ("--max-calls", &mut vm.limits.call_depth), ("--max-frame", &mut vm.limits.frame_bytes), ("--max-file", &mut vm.limits.file_bytes)] {
+                if let Some(v) = flag_values(args, flag).first() { *limit = v.parse().unwrap_or_else(|_| fail(format!("bad {} '{}'", flag, v))); }
+            }
+            let bef = build_with(&args[2], &vm.vfs).1; vm.load(&bef);
+            let argv: Vec<&str> = std::iter::once(&args[2]).chain(prog_args.iter().skip(1)).map(String::as_str).collect();
+            if let Err(e) = vm.set_args(&argv, &flag_values(args, "--env")) { fail(e); }
+            let mut stdin = std::io::stdin().lock();
+            let outcome = loop {
+                let outcome = vm.run(RUN_SLICE);
+                let mut out = std::io::stdout(); let _ = out.write_all(&vm.drain_output(1)); let _ = out.flush();
+                let _ = std::io::stderr().write_all(&vm.drain_output(2));
+                match outcome {
+                    RunOutcome::OutOfGas => {}
+                    RunOutcome::WaitingForInput(_) => {
+                        let mut line = Vec::new();
+                        match stdin.read_until(b'\n', &mut line) { Ok(n) if n > 0 => vm.push_stdin(&line), _ => vm.close_stdin() }
+                    }
+                    other => break other,
+                }
+            };
+            for spec in flag_values(args, "--export") {
+                let Some((guest, host)) = spec.split_once(':') else { fail(format!("bad export '{}', expected guest:host", spec)) };
+                if let Err(e) = vm.vfs.export(guest, std::path::Path::new(host)) { fail(e); }
+            }
+            if args.iter().any(|a| a == "--high-water") {
+                let (h, l) = (vm.high_water, vm.limits);
+                eprintln!("high-water: operand stack {}/{}, call depth {}/{}, frame bytes {}/{}", h.stack_depth, l.stack_depth, h.call_depth, l.call_depth, h.frame_bytes, l.frame_bytes);
+            }
+            match (outcome, vm.exit_code()) {
+                (RunOutcome::Halted(_), Some(code)) => std::process::exit(code as i32),
+                (other, _) => fail(format!("{:?}", other)),
+            }
+        }
+        // trace <file.c> [--hash N]: run a program and print its execution trace
+        Some("trace") if args.len() >= 3 => {
+            let mode = match (args.get(3).map(String::as_str), args.get(4).and_then(|n| n.parse().ok())) {
+                (Some("--hash"), Some(n)) => trace::TraceMode::RollingHash(n),
+                _ => trace::TraceMode::Full,
+            };
+            let mut vm = Machine::new(); vm.load(&build(&args[2]).1);
+            let mut rec = trace::TraceRecorder::new(mode);
+            vm.run_traced(u64::MAX, Some(&mut rec));
+            print!("{}", rec.finish());
+        }
+        // trace-diff <a> <b>: report the first divergence between two traces
+        Some("trace-diff") if args.len() >= 4 => {
+            match trace::diff_traces(&read_source(&args[2]), &read_source(&args[3])) {
+                None => println!("traces identical"),
+                Some(d) => { println!("{}", d); std::process::exit(1); }
+            }
+        }
+        // profile <file.c> [--sample N] [--folded]: flat profile and call graph, or folded stacks for flamegraphs
+        Some("profile") if args.len() >= 3 => {
+            let (asm, bef) = build(&args[2]);
+            let sample = args.iter().position(|a| a == "--sample").and_then(|i| args.get(i + 1)?.parse().ok());
+            let mut prof = profiler::Profiler::new(Assembler::symbols(&asm), sample.map_or(profiler::ProfileMode::Exact, profiler::ProfileMode::Sampling));
+            let mut vm = Machine::new(); vm.load(&bef);
+            vm.run_traced(u64::MAX, Some(&mut prof));
+            if args.iter().any(|a| a == "--folded") { print!("{}", prof.folded()); } else { print!("{}\n{}", prof.flat(), prof.call_graph()); }
+        }
+        // coverage <file.c>: run once and print an lcov tracefile
+        Some("coverage") if args.len() >= 3 => {
+            let (asm, bef) = build(&args[2]);
+            let mut cov = coverage::Coverage::new(&bef, Assembler::symbols(&asm));
+            let mut vm = Machine::new(); vm.load(&bef);
+            vm.run_traced(u64::MAX, Some(&mut cov));
+            print!("{}", cov.lcov());
+        }
+        // debug <file.c>: interactive debugger reading commands from stdin
+        Some("debug") if args.len() >= 3 => {
+            let mut dbg = debugger::Debugger::from_c(&read_source(&args[2]), &libc_headers());
+            print!("(dre) "); let _ = std::io::stdout().flush();
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if matches!(line.trim(), "quit" | "q") { break; }
+                print!("{}\n(dre) ", dbg.command(&line)); let _ = std::io::stdout().flush();
+            }
+            println!();
+        }
+        _ => print!("{}", vfs_core::run_suite()),
+    }
 }
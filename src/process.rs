@@ -0,0 +1,189 @@
+use crate::syscall::errno::*;
+use crate::{is_bef, Machine, RunOutcome, Trap, TrapKind, CODE_BASE, HEAP_BASE};
+use std::collections::BTreeMap;
+
+// --- PROCESSES ---
+// `Machine` always holds the running process's registers, memory and fd table in its own fields, so the
+// interpreter, debugger and tracers only ever see one program. Every other process is parked in `Machine::procs`
+// and swapped in by `switch_to`. The VFS, the system-wide open file table, gas and the clock are shared.
+// Scheduling is round-robin in pid order: the running process gives way after `quantum` instructions, when it
+// blocks, or when it exits, so the interleaving depends only on instruction counts. pid 1 is the program the host
+// loaded; when it ends the machine halts, taking every other process with it.
+pub const INIT_PID: u64 = 1;
+pub const DEFAULT_QUANTUM: u64 = 10_000;
+pub const PROC_MAX: usize = 64;
+// The status `waitpid` reports for a process killed by a trap; a normal exit reports its code, 0..=255.
+pub const KILLED: u64 = 256;
+pub const WNOHANG: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProcState {
+    #[default]
+    Ready,
+    // Blocked in a read on this fd until the host supplies input, in waitpid, or in a read or write on this pipe fd.
+    Input(u64),
+    Child,
+    Pipe(u64),
+    // Exited with this status and not yet waited for.
+    Zombie(u64),
+}
+
+// A parked process: the per-process fields of `Machine`.
+#[derive(Debug, Clone, Default)]
+pub struct Process {
+    pub pid: u64, pub ppid: u64, pub state: ProcState,
+    pub memory: Vec<u8>, pub perms: Vec<u8>, pub stack: Vec<u64>, pub call_stack: Vec<(usize, usize)>,
+    pub ip: usize, pub bp: usize, pub sp: usize, pub frame_base: usize, pub brk: usize,
+    pub fds: BTreeMap<u64, u64>, pub exit_status: Option<u64>, pub startup: [u64; 3],
+}
+
+impl Machine {
+    fn swap_context(&mut self, p: &mut Process) {
+        use std::mem::swap;
+        swap(&mut self.pid, &mut p.pid); swap(&mut self.ppid, &mut p.ppid);
+        swap(&mut self.memory, &mut p.memory); swap(&mut self.perms, &mut p.perms); swap(&mut self.stack, &mut p.stack); swap(&mut self.call_stack, &mut p.call_stack);
+        swap(&mut self.ip, &mut p.ip); swap(&mut self.bp, &mut p.bp); swap(&mut self.sp, &mut p.sp); swap(&mut self.frame_base, &mut p.frame_base); swap(&mut self.brk, &mut p.brk);
+        swap(&mut self.fds, &mut p.fds); swap(&mut self.exit_status, &mut p.exit_status); swap(&mut self.startup, &mut p.startup);
+    }
+
+    // Parks the running process in `leaving` state and resumes `pid`.
+    pub fn switch_to(&mut self, pid: u64, leaving: ProcState) {
+        let Some(mut next) = self.procs.remove(&pid) else { return };
+        self.swap_context(&mut next);
+        next.state = leaving;
+        self.procs.insert(next.pid, next);
+        self.slice = 0;
+    }
+
+    // The next ready process after the running one in pid order, wrapping around.
+    pub(crate) fn next_ready(&self) -> Option<u64> {
+        let ready = |(pid, p): (&u64, &Process)| (p.state == ProcState::Ready).then_some(*pid);
+        self.procs.range(self.pid + 1..).find_map(ready).or_else(|| self.procs.range(..self.pid).find_map(ready))
+    }
+
+    // Moves off a process that cannot continue. None means another process now runs; otherwise the run stops,
+    // parked on a process that is waiting for input or, in a deadlock, on a live one, never on a zombie.
+    pub(crate) fn yield_cpu(&mut self, leaving: ProcState) -> Option<RunOutcome> {
+        if let Some(pid) = self.next_ready() { self.switch_to(pid, leaving); return None; }
+        if let ProcState::Input(fd) = leaving { return Some(RunOutcome::WaitingForInput(fd)); }
+        match self.procs.iter().find_map(|(pid, p)| match p.state { ProcState::Input(fd) => Some((*pid, fd)), _ => None }) {
+            Some((pid, fd)) => { self.switch_to(pid, leaving); Some(RunOutcome::WaitingForInput(fd)) }
+            None => {
+                if let ProcState::Zombie(_) = leaving {
+                    let live = self.procs.iter().find_map(|(pid, p)| (!matches!(p.state, ProcState::Zombie(_))).then_some(*pid));
+                    if let Some(pid) = live { self.switch_to(pid, leaving); }
+                }
+                Some(RunOutcome::Trapped(Trap { ip: self.ip, kind: TrapKind::Deadlock }))
+            }
+        }
+    }
+
+    // Readers blocked on input get another try, since the host may have pushed some since the last run.
+    pub(crate) fn wake_readers(&mut self) {
+        for p in self.procs.values_mut() { if let ProcState::Input(_) = p.state { p.state = ProcState::Ready; } }
+    }
+
+    // Processes blocked on a pipe retry once data has moved through one or an end has closed.
+    pub(crate) fn wake_pipes(&mut self) {
+        for p in self.procs.values_mut() { if let ProcState::Pipe(_) = p.state { p.state = ProcState::Ready; } }
+    }
+
+    // Applies the outcome of one step to the process table: parks a blocked process, preempts one whose quantum
+    // is used up, and turns a child's return or trap into its exit. Some(..) ends the run.
+    pub(crate) fn schedule(&mut self, r: Result<bool, Trap>) -> Option<RunOutcome> {
+        match r {
+            Ok(true) => match self.blocked.take() {
+                Some(state) => self.yield_cpu(state),
+                None => {
+                    self.slice += 1;
+                    if self.slice >= self.quantum { match self.next_ready() { Some(pid) => self.switch_to(pid, ProcState::Ready), None => self.slice = 0 } }
+                    None
+                }
+            },
+            Ok(false) if self.pid == INIT_PID => { self.halt(); Some(RunOutcome::Halted(self.exit_status.unwrap_or(0))) }
+            Ok(false) => { let status = self.exit_status.or(self.stack.last().copied()).unwrap_or(0) & 0xff; self.exit_process(status) }
+            Err(t) if self.pid == INIT_PID => Some(RunOutcome::Trapped(t)),
+            Err(_) => self.exit_process(KILLED),
+        }
+    }
+
+    // Ends the running process (not init): closes its fds, hands its children to init, wakes a waiting parent,
+    // and leaves a zombie holding `status` for waitpid.
+    pub(crate) fn exit_process(&mut self, status: u64) -> Option<RunOutcome> {
+        for fd in self.fds.keys().copied().collect::<Vec<_>>() { let _ = self.sys_close(fd); }
+        let (pid, ppid) = (self.pid, self.ppid);
+        let mut orphans = false;
+        for p in self.procs.values_mut().filter(|p| p.ppid == pid) { p.ppid = INIT_PID; orphans |= matches!(p.state, ProcState::Zombie(_)); }
+        for (waiter, wake) in [(ppid, true), (INIT_PID, orphans)] {
+            if let Some(p) = self.procs.get_mut(&waiter).filter(|p| wake && p.state == ProcState::Child) { p.state = ProcState::Ready; }
+        }
+        self.memory = Vec::new(); self.perms = Vec::new(); self.stack.clear(); self.call_stack.clear();
+        self.yield_cpu(ProcState::Zombie(status))
+    }
+
+    // fork: the child is a copy of the running process that sees 0 where the parent sees its pid. Open file
+    // descriptions are shared, so parent and child move one offset.
+    pub(crate) fn sys_fork(&mut self) -> Result<u64, u64> {
+        if self.procs.len() + 1 >= PROC_MAX { return Err(EAGAIN); }
+        let pid = self.next_pid; self.next_pid += 1;
+        for id in self.fds.values() { if let Some(o) = self.open_files.get_mut(id) { o.refs += 1; } }
+        let mut stack = self.stack.clone(); stack.push(0);
+        self.procs.insert(pid, Process {
+            pid, ppid: self.pid, state: ProcState::Ready, memory: self.memory.clone(), perms: self.perms.clone(), stack, call_stack: self.call_stack.clone(),
+            ip: self.ip, bp: self.bp, sp: self.sp, frame_base: self.frame_base, brk: self.brk, fds: self.fds.clone(), exit_status: None, startup: self.startup,
+        });
+        Ok(pid)
+    }
+
+    // Replaces the running program with the BEF at `path` in a fresh address space; fds stay open.
+    pub(crate) fn exec_image(&mut self, path: &str, argv: &[String], envp: &[String]) -> Result<(), u64> {
+        let ino = self.vfs.resolve(path)?;
+        if self.vfs.node(ino)?.is_dir() { return Err(EACCES); }
+        let bef = self.vfs.contents(ino)?;
+        if !is_bef(&bef) { return Err(ENOEXEC); }
+        let (argv, envp): (Vec<&str>, Vec<&str>) = (argv.iter().map(String::as_str).collect(), envp.iter().map(String::as_str).collect());
+        if crate::args_size(&argv, &envp) > crate::ARG_MAX { return Err(E2BIG); }
+        self.memory = vec![0; HEAP_BASE]; self.perms = Machine::default_perms(HEAP_BASE);
+        self.stack.clear(); self.call_stack.clear();
+        (self.ip, self.brk, self.exit_status) = (CODE_BASE, HEAP_BASE, None);
+        self.load(&bef);
+        self.set_args(&argv, &envp).map_err(|_| E2BIG)
+    }
+
+    pub(crate) fn sys_exec(&mut self, path: u64, argv: u64, envp: u64) -> Result<u64, u64> {
+        let (path, argv, envp) = (self.guest_str(path)?, self.guest_strv(argv)?, self.guest_strv(envp)?);
+        self.exec_image(&path, &argv, &envp).map(|_| 0)
+    }
+
+    // fork + exec in one step, returning the child's pid; nothing changes if the image cannot be loaded.
+    pub(crate) fn sys_spawn(&mut self, path: u64, argv: u64, envp: u64) -> Result<u64, u64> {
+        let (path, argv, envp) = (self.guest_str(path)?, self.guest_strv(argv)?, self.guest_strv(envp)?);
+        let (parent, slice) = (self.pid, self.slice);
+        let child = self.sys_fork()?;
+        self.switch_to(child, ProcState::Ready);
+        let r = self.exec_image(&path, &argv, &envp);
+        if r.is_err() { for fd in self.fds.keys().copied().collect::<Vec<_>>() { let _ = self.sys_close(fd); } }
+        self.switch_to(parent, ProcState::Ready);
+        self.slice = slice;
+        if r.is_err() { self.procs.remove(&child); }
+        r.map(|_| child)
+    }
+
+    // waitpid(pid or -1, status, options): reaps an exited child, storing its status word at `status` when
+    // non-zero. Blocks while the children it asks about are still running, unless WNOHANG.
+    pub(crate) fn sys_waitpid(&mut self, pid: u64, status: u64, options: u64) -> Result<u64, u64> {
+        let me = self.pid;
+        let mut children = self.procs.values().filter(|p| p.ppid == me && (pid as i64 == -1 || p.pid == pid)).peekable();
+        if children.peek().is_none() { return Err(ECHILD); }
+        let zombie = children.find_map(|p| match p.state { ProcState::Zombie(code) => Some((p.pid, code)), _ => None });
+        match zombie {
+            Some((child, code)) => {
+                if status != 0 { self.write_u64(status as usize, code).map_err(|_| EFAULT)?; }
+                self.procs.remove(&child);
+                Ok(child)
+            }
+            None if options & WNOHANG != 0 => Ok(0),
+            None => Err(EAGAIN),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::trace::{StepEvent, Tracer};
+use crate::{Machine, SymbolMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+// --- PROFILER ---
+// Attributes executed instructions to call stacks. The shadow stack follows CALL/ICALL/RET as they execute, so
+// every count lands on the exact stack that was live. Stacks are interned in a trie: node 0 is the empty stack,
+// and each node is (parent, function).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileMode { Exact, Sampling(u64) }
+
+struct Node { parent: usize, func: usize, count: u64 }
+
+pub struct Profiler {
+    pub mode: ProfileMode, pub total: u64, steps: u64, symbols: SymbolMap,
+    funcs: Vec<String>, func_ids: HashMap<String, usize>,
+    nodes: Vec<Node>, children: HashMap<(usize, usize), usize>, cur: usize,
+    calls: BTreeMap<(usize, usize), u64>,
+}
+
+impl Profiler {
+    pub fn new(symbols: SymbolMap, mode: ProfileMode) -> Self {
+        Self { mode, total: 0, steps: 0, symbols, funcs: Vec::new(), func_ids: HashMap::new(), nodes: vec![Node { parent: 0, func: usize::MAX, count: 0 }], children: HashMap::new(), cur: 0, calls: BTreeMap::new() }
+    }
+
+    fn func_id(&mut self, addr: usize) -> usize {
+        let name = self.symbols.function_at(addr).map_or("<entry>".to_string(), |(n, _)| n.to_string());
+        if let Some(id) = self.func_ids.get(&name) { return *id; }
+        self.funcs.push(name.clone()); self.func_ids.insert(name, self.funcs.len() - 1);
+        self.funcs.len() - 1
+    }
+
+    fn enter(&mut self, func: usize) {
+        let key = (self.cur, func);
+        self.cur = match self.children.get(&key) {
+            Some(n) => *n,
+            None => { self.nodes.push(Node { parent: self.cur, func, count: 0 }); self.children.insert(key, self.nodes.len() - 1); self.nodes.len() - 1 }
+        };
+    }
+
+    // Functions on the stack of `node`, outermost first.
+    fn path(&self, mut node: usize) -> Vec<usize> {
+        let mut p = Vec::new();
+        while node != 0 { p.push(self.nodes[node].func); node = self.nodes[node].parent; }
+        p.reverse(); p
+    }
+
+    // (self, inclusive) per function; recursion counts a sample once towards inclusive time.
+    fn totals(&self) -> Vec<(u64, u64)> {
+        let mut t = vec![(0u64, 0u64); self.funcs.len()];
+        for (i, n) in self.nodes.iter().enumerate().skip(1).filter(|(_, n)| n.count > 0) {
+            t[n.func].0 += n.count;
+            for f in self.path(i).into_iter().collect::<BTreeSet<_>>() { t[f].1 += n.count; }
+        }
+        t
+    }
+
+    fn pct(&self, v: u64) -> f64 { if self.total == 0 { 0.0 } else { v as f64 * 100.0 / self.total as f64 } }
+
+    pub fn flat(&self) -> String {
+        let totals = self.totals();
+        let mut order: Vec<usize> = (0..self.funcs.len()).collect();
+        order.sort_by(|a, b| totals[*b].0.cmp(&totals[*a].0).then(self.funcs[*a].cmp(&self.funcs[*b])));
+        let mut out = format!("{:>10} {:>7} {:>10} {:>7} {:>8}  function\n", "self", "self%", "total", "total%", "calls");
+        for f in order {
+            let calls: u64 = self.calls.iter().filter(|((_, c), _)| *c == f).map(|(_, n)| n).sum();
+            let _ = writeln!(out, "{:>10} {:>6.2}% {:>10} {:>6.2}% {:>8}  {}", totals[f].0, self.pct(totals[f].0), totals[f].1, self.pct(totals[f].1), calls, self.funcs[f]);
+        }
+        out
+    }
+
+    // Per function: its callers with call counts, then its callees with call counts and the instructions spent
+    // under that callee when called from here.
+    pub fn call_graph(&self) -> String {
+        let mut edge_time: BTreeMap<(usize, usize), u64> = BTreeMap::new();
+        for (i, n) in self.nodes.iter().enumerate().skip(1).filter(|(_, n)| n.count > 0) {
+            let p = self.path(i);
+            for pair in p.windows(2).map(|w| (w[0], w[1])).collect::<BTreeSet<_>>() { *edge_time.entry(pair).or_default() += n.count; }
+        }
+        let totals = self.totals();
+        let mut names: Vec<usize> = (0..self.funcs.len()).collect();
+        names.sort_by(|a, b| self.funcs[*a].cmp(&self.funcs[*b]));
+        let mut out = String::new();
+        for f in names {
+            let _ = writeln!(out, "{} (self {}, total {})", self.funcs[f], totals[f].0, totals[f].1);
+            for ((caller, _), n) in self.calls.iter().filter(|((_, c), _)| *c == f) { let _ = writeln!(out, "    <- {:<24} {:>8} calls", self.funcs[*caller], n); }
+            for ((_, callee), n) in self.calls.iter().filter(|((c, _), _)| *c == f) {
+                let _ = writeln!(out, "    -> {:<24} {:>8} calls {:>10} instr", self.funcs[*callee], n, edge_time.get(&(f, *callee)).copied().unwrap_or(0));
+            }
+        }
+        out
+    }
+
+    // One "outer;inner count" line per distinct stack, for flamegraph.pl / inferno / speedscope.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.nodes.iter().enumerate().skip(1).filter(|(_, n)| n.count > 0)
+            .map(|(i, n)| format!("{} {}", self.path(i).iter().map(|f| self.funcs[*f].as_str()).collect::<Vec<_>>().join(";"), n.count))
+            .collect();
+        lines.sort();
+        let mut s = lines.join("\n"); s.push('\n'); s
+    }
+}
+
+impl Tracer for Profiler {
+    fn on_step(&mut self, m: &Machine, ev: &StepEvent) {
+        if self.cur == 0 { let f = self.func_id(ev.ip); self.enter(f); }
+        self.steps += 1;
+        let weight = match self.mode { ProfileMode::Exact => 1, ProfileMode::Sampling(n) => if n > 0 && self.steps.is_multiple_of(n) { n } else { 0 } };
+        self.nodes[self.cur].count += weight; self.total += weight;
+        match ev.op {
+            0x40 | 0x41 => {
+                let caller = self.nodes[self.cur].func; let callee = self.func_id(m.ip);
+                *self.calls.entry((caller, callee)).or_default() += 1;
+                self.enter(callee);
+            }
+            0x42 if self.nodes[self.cur].parent != 0 => self.cur = self.nodes[self.cur].parent,
+            _ => {}
+        }
+    }
+}
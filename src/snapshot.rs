@@ -0,0 +1,188 @@
+use crate::process::{ProcState, Process};
+use crate::vfs::Vfs;
+use crate::{GasTable, HighWater, Limits, Machine, OpenFile, HEAP_BASE, PAGE_SIZE};
+use std::collections::BTreeMap;
+
+// --- SNAPSHOT FORMAT ---
+// "DRES" | version u32 | fnv1a(base image) u64 | varint registers | gas table | limits | stack | call_stack
+//   | dirty pages | page permissions | vfs inodes | open files | fds | scheduler | parked processes
+// Every integer after the header is an unsigned LEB128 varint, so the bytes are identical on native and WASM
+// regardless of usize width. Memory is stored as the pages that differ from the loaded BEF image; a parked
+// process may be running another image, so its pages are stored where they differ from zero.
+const MAGIC: &[u8; 4] = b"DRES";
+const VERSION: u32 = 13;
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes { h ^= *b as u64; h = h.wrapping_mul(0x100000001b3); }
+    h
+}
+
+pub(crate) fn put(out: &mut Vec<u8>, mut v: u64) {
+    loop { let b = (v & 0x7f) as u8; v >>= 7; if v == 0 { out.push(b); break; } out.push(b | 0x80); }
+}
+pub(crate) fn put_bytes(out: &mut Vec<u8>, b: &[u8]) { put(out, b.len() as u64); out.extend_from_slice(b); }
+
+pub(crate) struct Reader<'a> { pub d: &'a [u8], pub pos: usize }
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(n).filter(|e| *e <= self.d.len()).ok_or("unexpected end of data")?;
+        let s = &self.d[self.pos..end]; self.pos = end; Ok(s)
+    }
+    pub fn get(&mut self) -> Result<u64, String> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) { let b = self.take(1)?[0]; v |= ((b & 0x7f) as u64) << shift; if b & 0x80 == 0 { return Ok(v); } }
+        Err("varint overflow".into())
+    }
+    pub fn usize(&mut self) -> Result<usize, String> { usize::try_from(self.get()?).map_err(|_| "value exceeds usize".to_string()) }
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> { let n = self.usize()?; self.take(n) }
+    pub fn string(&mut self) -> Result<String, String> { String::from_utf8(self.bytes()?.to_vec()).map_err(|_| "string is not utf-8".to_string()) }
+}
+
+// Memory exactly as `Machine::load` would leave it for this image (all zeroes when there is none, or when `len`
+// is too short to have held it, as in a gutted process, so a bad state still snapshots rather than panics).
+fn base_memory(len: usize, base: Option<&[u8]>) -> Vec<u8> {
+    let mut m = Machine::new(); m.memory = vec![0; len];
+    if let Some(b) = base.filter(|_| len >= HEAP_BASE) { m.load(b); }
+    m.memory
+}
+
+fn put_pages(out: &mut Vec<u8>, memory: &[u8], reference: &[u8]) {
+    let dirty: Vec<usize> = (0..memory.len().div_ceil(PAGE_SIZE))
+        .filter(|p| { let r = p * PAGE_SIZE..((p + 1) * PAGE_SIZE).min(memory.len()); memory[r.clone()] != reference[r] })
+        .collect();
+    put(out, memory.len() as u64);
+    put(out, dirty.len() as u64);
+    for p in dirty { put(out, p as u64); out.extend_from_slice(&memory[p * PAGE_SIZE..((p + 1) * PAGE_SIZE).min(memory.len())]); }
+}
+
+// Reads `put_pages` output over `reference(len)`.
+fn get_pages(r: &mut Reader, reference: impl FnOnce(usize) -> Vec<u8>) -> Result<Vec<u8>, String> {
+    let mut memory = reference(r.usize()?);
+    for _ in 0..r.usize()? {
+        let p = r.usize()?;
+        let start = p.checked_mul(PAGE_SIZE).filter(|s| *s < memory.len()).ok_or("snapshot page out of range")?;
+        let end = (start + PAGE_SIZE).min(memory.len());
+        memory[start..end].copy_from_slice(r.take(end - start)?);
+    }
+    Ok(memory)
+}
+
+// One permission byte for each page of a memory `len` bytes long.
+fn get_perms(r: &mut Reader, len: usize) -> Result<Vec<u8>, String> {
+    let perms = r.bytes()?;
+    if perms.len() != len.div_ceil(PAGE_SIZE) { return Err("snapshot page permissions do not match memory".into()); }
+    Ok(perms.to_vec())
+}
+
+// Operand stack and call stack (return ip, saved bp).
+type Stacks = (Vec<u64>, Vec<(usize, usize)>);
+
+fn put_stacks(out: &mut Vec<u8>, stack: &[u64], call_stack: &[(usize, usize)]) {
+    put(out, stack.len() as u64);
+    for v in stack { put(out, *v); }
+    put(out, call_stack.len() as u64);
+    for (ri, ob) in call_stack { put(out, *ri as u64); put(out, *ob as u64); }
+}
+
+fn get_stacks(r: &mut Reader) -> Result<Stacks, String> {
+    let stack = (0..r.usize()?).map(|_| r.get()).collect::<Result<_, _>>()?;
+    Ok((stack, (0..r.usize()?).map(|_| Ok((r.usize()?, r.usize()?))).collect::<Result<_, String>>()?))
+}
+
+fn put_fds(out: &mut Vec<u8>, fds: &BTreeMap<u64, u64>) {
+    put(out, fds.len() as u64);
+    for (fd, id) in fds { put(out, *fd); put(out, *id); }
+}
+
+fn get_fds(r: &mut Reader) -> Result<BTreeMap<u64, u64>, String> {
+    (0..r.usize()?).map(|_| Ok((r.get()?, r.get()?))).collect()
+}
+
+// Process states as (tag, value).
+fn state_word(s: ProcState) -> (u64, u64) {
+    match s { ProcState::Ready => (0, 0), ProcState::Input(fd) => (1, fd), ProcState::Child => (2, 0), ProcState::Zombie(st) => (3, st), ProcState::Pipe(fd) => (4, fd) }
+}
+
+impl Machine {
+    // Serializes the complete machine state. `base` is the BEF image the machine was loaded from; pages equal to
+    // it are omitted, so a snapshot taken right after `load` holds only registers and the VFS.
+    pub fn snapshot(&self, base: Option<&[u8]>) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&fnv1a(base.unwrap_or(&[])).to_le_bytes());
+        for r in [self.ip, self.bp, self.sp, self.frame_base, self.brk] { put(&mut out, r as u64); }
+        for r in [self.gas, self.gas_used, self.halted as u64, self.exit_status.is_some() as u64, self.exit_status.unwrap_or(0), self.clock, self.startup[0], self.startup[1], self.startup[2]] { put(&mut out, r); }
+        for c in self.gas_table.costs { put(&mut out, c); }
+        let (l, h) = (self.limits, self.high_water);
+        for r in [l.stack_depth, l.call_depth, l.frame_bytes, l.file_bytes, h.stack_depth, h.call_depth, h.frame_bytes] { put(&mut out, r as u64); }
+        put_stacks(&mut out, &self.stack, &self.call_stack);
+        put_pages(&mut out, &self.memory, &base_memory(self.memory.len(), base));
+        put_bytes(&mut out, &self.perms);
+
+        self.vfs.encode(&mut out);
+        put(&mut out, self.open_files.len() as u64);
+        for (id, f) in &self.open_files { put(&mut out, *id); put(&mut out, f.ino); put(&mut out, f.pos as u64); put(&mut out, f.flags); put(&mut out, f.refs as u64); }
+        put_fds(&mut out, &self.fds);
+
+        for r in [self.pid, self.ppid, self.next_pid, self.quantum, self.slice] { put(&mut out, r); }
+        put(&mut out, self.procs.len() as u64);
+        for p in self.procs.values() {
+            let (tag, v) = state_word(p.state);
+            for r in [p.pid, p.ppid, tag, v, p.ip as u64, p.bp as u64, p.sp as u64, p.frame_base as u64, p.brk as u64, p.exit_status.is_some() as u64, p.exit_status.unwrap_or(0)] { put(&mut out, r); }
+            for w in p.startup { put(&mut out, w); }
+            put_stacks(&mut out, &p.stack, &p.call_stack);
+            put_pages(&mut out, &p.memory, &vec![0; p.memory.len()]);
+            put_bytes(&mut out, &p.perms);
+            put_fds(&mut out, &p.fds);
+        }
+        out
+    }
+
+    // Rebuilds a machine from `snapshot`. `base` must be the same image that was passed to `snapshot`.
+    pub fn restore(snapshot: &[u8], base: Option<&[u8]>) -> Result<Machine, String> {
+        let mut r = Reader { d: snapshot, pos: 0 };
+        if r.take(4)? != MAGIC { return Err("not a DRE snapshot".into()); }
+        let version = u32::from_le_bytes(r.take(4)?.try_into().unwrap());
+        if version != VERSION { return Err(format!("unsupported snapshot version {}", version)); }
+        if u64::from_le_bytes(r.take(8)?.try_into().unwrap()) != fnv1a(base.unwrap_or(&[])) { return Err("snapshot base image mismatch".into()); }
+
+        let mut m = Machine::new();
+        m.ip = r.usize()?; m.bp = r.usize()?; m.sp = r.usize()?; m.frame_base = r.usize()?; m.brk = r.usize()?;
+        m.gas = r.get()?; m.gas_used = r.get()?; m.halted = r.get()? != 0;
+        let exited = r.get()? != 0; let status = r.get()?; m.exit_status = exited.then_some(status); m.clock = r.get()?;
+        for w in m.startup.iter_mut() { *w = r.get()?; }
+        let mut table = GasTable::default();
+        for c in table.costs.iter_mut() { *c = r.get()?; }
+        m.gas_table = table;
+        m.limits = Limits { stack_depth: r.usize()?, call_depth: r.usize()?, frame_bytes: r.usize()?, file_bytes: r.usize()? };
+        m.high_water = HighWater { stack_depth: r.usize()?, call_depth: r.usize()?, frame_bytes: r.usize()? };
+        (m.stack, m.call_stack) = get_stacks(&mut r)?;
+        m.memory = get_pages(&mut r, |len| base_memory(len, base))?;
+        m.perms = get_perms(&mut r, m.memory.len())?;
+
+        m.vfs = Vfs::decode(&mut r)?;
+        m.open_files = BTreeMap::new();
+        for _ in 0..r.usize()? { let id = r.get()?; let f = OpenFile { ino: r.get()?, pos: r.usize()?, flags: r.get()?, refs: r.usize()? }; m.open_files.insert(id, f); }
+        m.fds = get_fds(&mut r)?;
+
+        (m.pid, m.ppid, m.next_pid, m.quantum, m.slice) = (r.get()?, r.get()?, r.get()?, r.get()?, r.get()?);
+        for _ in 0..r.usize()? {
+            let mut p = Process { pid: r.get()?, ppid: r.get()?, ..Default::default() };
+            p.state = match (r.get()?, r.get()?) {
+                (0, _) => ProcState::Ready, (1, fd) => ProcState::Input(fd), (2, _) => ProcState::Child, (3, st) => ProcState::Zombie(st), (4, fd) => ProcState::Pipe(fd),
+                (t, _) => return Err(format!("unknown process state {}", t)),
+            };
+            (p.ip, p.bp, p.sp, p.frame_base, p.brk) = (r.usize()?, r.usize()?, r.usize()?, r.usize()?, r.usize()?);
+            let exited = r.get()? != 0; let status = r.get()?; p.exit_status = exited.then_some(status);
+            for w in p.startup.iter_mut() { *w = r.get()?; }
+            (p.stack, p.call_stack) = get_stacks(&mut r)?;
+            p.memory = get_pages(&mut r, |len| vec![0; len])?;
+            p.perms = get_perms(&mut r, p.memory.len())?;
+            p.fds = get_fds(&mut r)?;
+            m.procs.insert(p.pid, p);
+        }
+        if r.pos != snapshot.len() { return Err("trailing bytes after snapshot".into()); }
+        Ok(m)
+    }
+}
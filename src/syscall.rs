@@ -0,0 +1,416 @@
+use crate::device::Pipe;
+use crate::vfs::Kind;
+use crate::process::ProcState;
+use crate::{Access, Machine, OpenFile, TrapKind, HEAP_BASE, MEMORY_MAX, PAGE_SIZE};
+
+// --- SYSCALL ABI ---
+// `syscall(n, a1, a2, ...)` leaves `n` on top of the operand stack with the arguments below it in order. The VM
+// pops the number, then exactly `Syscall::arity` arguments, and pushes one result: a value, or -errno (as a
+// two's complement u64) on failure. Unknown numbers trap.
+//
+//   n  name     arguments              result
+//   1  open     path, flags, mode      lowest free fd; flags are the O_* constants below, mode applies with O_CREAT
+//   2  read     fd, buf, len           bytes read, 0 at end of file; -EISDIR on a directory. A device with
+//                                      nothing to read yet (stdin before the host pushes input) blocks, see below
+//   3  write    fd, buf, len           bytes written; writing past the end zero-fills the gap
+//   4  sbrk     increment              previous break; memory grows or shrinks with the break; -ENOMEM if it
+//                                      would drop below the heap start or the frames, or pass MEMORY_MAX
+//   5  close    fd                     0; the open file description goes away with its last fd
+//   6  lseek    fd, offset, whence     new offset; whence is SEEK_SET 0, SEEK_CUR 1, SEEK_END 2
+//   7  stat     path, statbuf          0; fills a dre_stat
+//   8  fstat    fd, statbuf            0; fills a dre_stat
+//   9  unlink   path                   0; the inode lives on while it is still open
+//  10  rename   old, new               0; replaces `new` if it exists
+//  11  mkdir    path, mode             0
+//  12  getcwd   buf, size              length including the NUL
+//  13  exit     status                 does not return; the run halts with `Machine::exit_code`
+//  14  time     tloc                   `Machine::clock`, also stored at tloc when it is non-zero
+//  15  dup      fd                     lowest free fd sharing fd's offset and flags
+//  16  dup2     fd, newfd              newfd, closed first if it was open; a no-op when fd == newfd
+//  17  rmdir    path                   0; -ENOTEMPTY unless the directory is empty
+//  18  readdir  fd, buf, size          length of the next entry name, copied NUL-terminated to buf; 0 at the end
+//  19  link     old, new               0; hard link, -EPERM for directories
+//  20  symlink  target, path           0
+//  21  readlink path, buf, size        bytes copied, without a NUL
+//  22  lstat    path, statbuf          0; like stat but does not follow a final symlink
+//  23  pread    fd, buf, len, offset   like read at `offset`; the file offset is left alone, -ESPIPE on devices
+//  24  pwrite   fd, buf, len, offset   like write at `offset`, even with O_APPEND; the file offset is left alone
+//  25  ftruncate fd, length            0; shrinks or zero-extends a regular file open for writing
+//  26  ioctl    fd, request, arg       device-specific; -ENOTTY unless fd is a device that knows `request`.
+//                                      TCGETS succeeds on terminals, TIOCGWINSZ stores a struct winsize at arg
+//  27  startup  which                  argc (0), argv (1) or envp (2) from `Machine::set_args`; used by the startup
+//                                      code MiniCC emits before `CALL main`
+//  28  fork                            the child's pid in the parent, 0 in the child; see src/process.rs
+//  29  execve   path, argv, envp       does not return on success: the BEF at path replaces the program. argv and
+//                                      envp are NULL-terminated pointer arrays; -ENOEXEC if path is not a BEF
+//  30  spawn    path, argv, envp       pid of a new child running the BEF at path, as fork + execve in one call
+//  31  waitpid  pid, status, options   pid of a reaped child, its exit status stored at status when non-zero;
+//                                      pid -1 means any child. Blocks until one exits, or returns 0 with WNOHANG
+//  32  getpid                          pid of the calling process; the program the host loaded is pid 1
+//  33  getppid                         pid of its parent, 0 for pid 1
+//  34  pipe     fds                    0; stores a read fd and a write fd as two words at fds. Reads block while
+//                                      the pipe is empty, writes while it holds PIPE_SIZE bytes; -EPIPE once
+//                                      no read end is open, and end of file once no write end is
+//
+// A blocked read consumes no gas and has no effect: the arguments are pushed back, ip is left on the SYSCALL and
+// `Machine::run` returns `WaitingForInput(fd)` unless another process can run, so resuming after `push_stdin`
+// simply retries it. A blocked waitpid is retried the same way once a child exits, and a read or write blocked on
+// a pipe once another process moves data through a pipe or closes an end of one. Devices
+// ignore the file offset, so dup'd and reopened stdin fds all share one input queue.
+//
+// dre_stat is five u64 words: { st_mode, st_size, st_ino, st_nlink, st_mtime }; mtime is `Machine::clock` at the
+// last change. Relative paths resolve from "/". An fd names an entry in `Machine::open_files` (an open file description), so dup'd fds share one offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall { Open = 1, Read, Write, Sbrk, Close, Lseek, Stat, Fstat, Unlink, Rename, Mkdir, Getcwd, Exit, Time, Dup, Dup2,
+    Rmdir, Readdir, Link, Symlink, Readlink, Lstat, Pread, Pwrite, Ftruncate, Ioctl, Startup,
+    Fork, Execve, Spawn, Waitpid, Getpid, Getppid, Pipe }
+
+impl Syscall {
+    pub fn from_number(n: u64) -> Option<Self> {
+        use Syscall::*;
+        Some(match n {
+            1 => Open, 2 => Read, 3 => Write, 4 => Sbrk, 5 => Close, 6 => Lseek, 7 => Stat,
+            8 => Fstat, 9 => Unlink, 10 => Rename, 11 => Mkdir, 12 => Getcwd, 13 => Exit, 14 => Time,
+            15 => Dup, 16 => Dup2, 17 => Rmdir, 18 => Readdir, 19 => Link, 20 => Symlink, 21 => Readlink, 22 => Lstat,
+            23 => Pread, 24 => Pwrite, 25 => Ftruncate, 26 => Ioctl, 27 => Startup,
+            28 => Fork, 29 => Execve, 30 => Spawn, 31 => Waitpid, 32 => Getpid, 33 => Getppid, 34 => Pipe,
+            _ => return None,
+        })
+    }
+
+    pub fn arity(self) -> usize {
+        use Syscall::*;
+        match self {
+            Fork | Getpid | Getppid => 0,
+            Sbrk | Close | Unlink | Exit | Time | Dup | Rmdir | Startup | Pipe => 1,
+            Stat | Fstat | Rename | Mkdir | Getcwd | Dup2 | Link | Symlink | Lstat | Ftruncate => 2,
+            Open | Read | Write | Lseek | Readdir | Readlink | Ioctl | Execve | Spawn | Waitpid => 3,
+            Pread | Pwrite => 4,
+        }
+    }
+}
+
+// Linux errno numbers, so ported code that compares against them keeps working.
+pub mod errno {
+    pub const ENOENT: u64 = 2; pub const EBADF: u64 = 9; pub const EFAULT: u64 = 14; pub const EINVAL: u64 = 22;
+    pub const ERANGE: u64 = 34; pub const ENOSYS: u64 = 38; pub const ESPIPE: u64 = 29; pub const EACCES: u64 = 13;
+    pub const EEXIST: u64 = 17; pub const EMFILE: u64 = 24; pub const EPERM: u64 = 1; pub const ENOTDIR: u64 = 20;
+    pub const EISDIR: u64 = 21; pub const ENOTEMPTY: u64 = 39; pub const ELOOP: u64 = 40; pub const EROFS: u64 = 30;
+    pub const EAGAIN: u64 = 11; pub const ENOTTY: u64 = 25; pub const ENXIO: u64 = 6; pub const ECHILD: u64 = 10;
+    pub const ENOEXEC: u64 = 8; pub const E2BIG: u64 = 7; pub const EPIPE: u64 = 32; pub const ENOMEM: u64 = 12; pub const EFBIG: u64 = 27;
+}
+use errno::*;
+
+pub const S_IFIFO: u64 = 0o010000;
+pub const S_IFCHR: u64 = 0o020000;
+pub const S_IFDIR: u64 = 0o040000;
+pub const S_IFREG: u64 = 0o100000;
+pub const S_IFLNK: u64 = 0o120000;
+
+// open flags, Linux values.
+pub const O_RDONLY: u64 = 0; pub const O_WRONLY: u64 = 1; pub const O_RDWR: u64 = 2; pub const O_ACCMODE: u64 = 3;
+pub const O_CREAT: u64 = 0o100; pub const O_EXCL: u64 = 0o200; pub const O_TRUNC: u64 = 0o1000; pub const O_APPEND: u64 = 0o2000;
+pub const OPEN_MAX: u64 = 1024;
+
+type SysResult = Result<u64, u64>;
+
+impl Machine {
+    pub(crate) fn syscall(&mut self) -> Result<bool, TrapKind> {
+        let n = self.pop()?;
+        let call = Syscall::from_number(n).ok_or(TrapKind::BadSyscall(n))?;
+        let mut a = [0u64; 4];
+        for v in a.iter_mut().take(call.arity()) { *v = self.pop()?; }
+        if call == Syscall::Exit {
+            self.exit_status = Some(a[0]);
+            if let Some(ev) = self.trace_buf.as_mut() { ev.syscall = Some((n, a[0])); }
+            return Ok(false);
+        }
+        self.vfs.now = self.clock;
+        let r = self.dispatch(call, a);
+        let pipe = matches!(call, Syscall::Read | Syscall::Write) && self.is_pipe(a[0]);
+        if pipe && r.is_ok() { self.wake_pipes(); }
+        let park = match call {
+            Syscall::Read | Syscall::Write if pipe => Some(ProcState::Pipe(a[0])),
+            Syscall::Read => Some(ProcState::Input(a[0])),
+            Syscall::Waitpid => Some(ProcState::Child),
+            _ => None,
+        };
+        if let (Err(EAGAIN), Some(state)) = (r, park) {
+            for v in a.iter().take(call.arity()).rev() { self.stack.push(*v); }
+            self.stack.push(n); self.ip -= 1; self.blocked = Some(state);
+            return Ok(true);
+        }
+        // A successful execve has nothing to return to.
+        if call == Syscall::Execve && r.is_ok() { return Ok(true); }
+        let v = match r { Ok(v) => v, Err(e) => e.wrapping_neg() };
+        self.stack.push(v);
+        if let Some(ev) = self.trace_buf.as_mut() { ev.syscall = Some((n, v)); }
+        Ok(true)
+    }
+
+    fn dispatch(&mut self, call: Syscall, a: [u64; 4]) -> SysResult {
+        match call {
+            Syscall::Open => self.sys_open(a[0], a[1], a[2]),
+            Syscall::Read => self.sys_read(a[0], a[1], a[2]),
+            Syscall::Write => self.sys_write(a[0], a[1], a[2]),
+            Syscall::Sbrk => self.sys_sbrk(a[0] as i64),
+            Syscall::Close => self.sys_close(a[0]),
+            Syscall::Lseek => self.sys_lseek(a[0], a[1] as i64, a[2]),
+            Syscall::Stat => { let ino = self.vfs.resolve(&self.guest_str(a[0])?)?; self.sys_stat(ino, a[1]) }
+            Syscall::Lstat => { let ino = self.vfs.lresolve(&self.guest_str(a[0])?)?; self.sys_stat(ino, a[1]) }
+            Syscall::Fstat => { let ino = self.ofd(a[0])?.ino; self.sys_stat(ino, a[1]) }
+            Syscall::Unlink => { let ino = self.vfs.unlink(&self.guest_str(a[0])?)?; self.reap(ino); Ok(0) }
+            Syscall::Rename => { let (old, new) = (self.guest_str(a[0])?, self.guest_str(a[1])?); if let Some(ino) = self.vfs.rename(&old, &new)? { self.reap(ino); } Ok(0) }
+            Syscall::Mkdir => { self.vfs.mkdir(&self.guest_str(a[0])?, a[1])?; Ok(0) }
+            Syscall::Rmdir => { let ino = self.vfs.rmdir(&self.guest_str(a[0])?)?; self.reap(ino); Ok(0) }
+            Syscall::Readdir => self.sys_readdir(a[0], a[1], a[2]),
+            Syscall::Link => { self.vfs.link(&self.guest_str(a[0])?, &self.guest_str(a[1])?)?; Ok(0) }
+            Syscall::Symlink => { self.vfs.symlink(&self.guest_str(a[0])?, &self.guest_str(a[1])?)?; Ok(0) }
+            Syscall::Readlink => { let t = self.vfs.readlink(&self.guest_str(a[0])?)?.as_bytes().to_vec(); self.put_guest(a[1], &t[..t.len().min(a[2] as usize)]) }
+            Syscall::Getcwd => self.sys_getcwd(a[0], a[1]),
+            Syscall::Time => { if a[0] != 0 { self.write_u64(a[0] as usize, self.clock).map_err(|_| EFAULT)?; } Ok(self.clock) }
+            Syscall::Dup => { let id = *self.fds.get(&a[0]).ok_or(EBADF)?; let fd = self.lowest_fd()?; self.bind(fd, id); Ok(fd) }
+            Syscall::Dup2 => self.sys_dup2(a[0], a[1]),
+            Syscall::Pread => self.sys_pread(a[0], a[1], a[2], a[3] as i64),
+            Syscall::Pwrite => self.sys_pwrite(a[0], a[1], a[2], a[3] as i64),
+            Syscall::Ftruncate => self.sys_ftruncate(a[0], a[1] as i64),
+            Syscall::Startup => self.startup.get(a[0] as usize).copied().ok_or(EINVAL),
+            Syscall::Ioctl => { let ino = self.ofd(a[0])?.ino; let (v, out) = self.vfs.device(ino, false)?.ioctl(a[1])?; self.put_guest(a[2], &out)?; Ok(v) }
+            Syscall::Fork => self.sys_fork(),
+            Syscall::Execve => self.sys_exec(a[0], a[1], a[2]),
+            Syscall::Spawn => self.sys_spawn(a[0], a[1], a[2]),
+            Syscall::Waitpid => self.sys_waitpid(a[0], a[1], a[2]),
+            Syscall::Getpid => Ok(self.pid),
+            Syscall::Getppid => Ok(self.ppid),
+            Syscall::Pipe => self.sys_pipe(a[0]),
+            Syscall::Exit => unreachable!("exit is handled before dispatch"),
+        }
+    }
+
+    // NUL-terminated guest string; EFAULT if it runs off the end of memory or through a page it may not read.
+    pub(crate) fn guest_str(&self, addr: u64) -> Result<String, u64> {
+        let tail = self.memory.get(addr as usize..).ok_or(EFAULT)?;
+        let len = tail.iter().position(|b| *b == 0).ok_or(EFAULT)?;
+        self.guest_range(addr, len as u64 + 1, Access::Read)?;
+        Ok(tail[..len].iter().map(|b| *b as char).collect())
+    }
+
+    // NULL-terminated array of string pointers, as in argv; a NULL array reads as empty.
+    pub(crate) fn guest_strv(&self, addr: u64) -> Result<Vec<String>, u64> {
+        let mut v = Vec::new();
+        if addr == 0 { return Ok(v); }
+        for i in 0.. {
+            let at = (addr as usize).checked_add(i * 8).ok_or(EFAULT)?;
+            match self.read_u64(at).map_err(|_| EFAULT)? { 0 => break, s => v.push(self.guest_str(s)?) }
+        }
+        Ok(v)
+    }
+
+    // A guest buffer the program may `access`; EFAULT where the same access from the program itself would trap.
+    fn guest_range(&self, addr: u64, len: u64, access: Access) -> Result<std::ops::Range<usize>, u64> {
+        if len == 0 { return Ok(0..0); }
+        self.check_access(addr as usize, len as usize, access).map_err(|_| EFAULT)
+    }
+
+    fn ofd(&self, fd: u64) -> Result<&OpenFile, u64> { self.fds.get(&fd).and_then(|id| self.open_files.get(id)).ok_or(EBADF) }
+
+    fn is_pipe(&self, fd: u64) -> bool { self.ofd(fd).and_then(|f| self.vfs.node(f.ino)).is_ok_and(|n| n.type_bits() == S_IFIFO) }
+
+    fn lowest_fd(&self) -> SysResult { (0..OPEN_MAX).find(|fd| !self.fds.contains_key(fd)).ok_or(EMFILE) }
+
+    fn bind(&mut self, fd: u64, id: u64) {
+        if let Some(f) = self.open_files.get_mut(&id) { f.refs += 1; }
+        self.fds.insert(fd, id);
+    }
+
+    // Copies `bytes` to guest memory and returns how many were copied.
+    fn put_guest(&mut self, addr: u64, bytes: &[u8]) -> SysResult {
+        let r = self.guest_range(addr, bytes.len() as u64, Access::Write)?;
+        self.memory[r.clone()].copy_from_slice(bytes);
+        if !bytes.is_empty() { self.record_write(r.start, bytes.len()); }
+        Ok(bytes.len() as u64)
+    }
+
+    // Frees an inode once it has no names left and no open file description refers to it.
+    fn reap(&mut self, ino: u64) {
+        if self.vfs.node(ino).is_ok_and(|n| n.nlink == 0) && !self.open_files.values().any(|f| f.ino == ino) { self.vfs.inodes.remove(&ino); }
+    }
+
+    fn sys_open(&mut self, path: u64, flags: u64, mode: u64) -> SysResult {
+        let n = self.guest_str(path)?;
+        let acc = flags & O_ACCMODE;
+        if acc == O_ACCMODE { return Err(EINVAL); }
+        let ino = match self.vfs.resolve(&n) {
+            Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(EEXIST),
+            Ok(ino) => ino,
+            Err(ENOENT) if flags & O_CREAT != 0 => self.vfs.create(&n, Kind::File(Default::default()), mode)?,
+            Err(e) => return Err(e),
+        };
+        let node = self.vfs.node(ino)?;
+        if node.is_dir() && acc != O_RDONLY { return Err(EISDIR); }
+        if (acc != O_WRONLY && node.mode & 0o444 == 0) || (acc != O_RDONLY && node.mode & 0o222 == 0) { return Err(EACCES); }
+        if acc != O_RDONLY { self.vfs.writable(ino)?; }
+        if flags & O_TRUNC != 0 && acc != O_RDONLY && matches!(self.vfs.node(ino)?.kind, Kind::File(_)) { self.vfs.set_len(ino, 0)?; }
+        let fd = self.lowest_fd()?;
+        let id = (0..).find(|id| !self.open_files.contains_key(id)).unwrap();
+        self.open_files.insert(id, OpenFile { ino, pos: 0, flags, refs: 0 });
+        self.bind(fd, id);
+        Ok(fd)
+    }
+
+    pub(crate) fn sys_close(&mut self, fd: u64) -> SysResult {
+        let id = self.fds.remove(&fd).ok_or(EBADF)?;
+        if let Some(f) = self.open_files.get_mut(&id) {
+            f.refs -= 1;
+            if f.refs == 0 { let (ino, flags) = (f.ino, f.flags); self.open_files.remove(&id); self.release(ino, flags); self.reap(ino); }
+        }
+        Ok(0)
+    }
+
+    // Tells a device when the last description reading or writing it has closed, waking processes blocked on
+    // a pipe that just lost its writers or readers.
+    fn release(&mut self, ino: u64, flags: u64) {
+        let acc = flags & O_ACCMODE;
+        for (write, held) in [(false, acc != O_WRONLY), (true, acc != O_RDONLY)] {
+            let side = |f: &OpenFile| f.ino == ino && if write { f.flags & O_ACCMODE != O_RDONLY } else { f.flags & O_ACCMODE != O_WRONLY };
+            if !held || self.open_files.values().any(side) { continue; }
+            if let Ok(d) = self.vfs.device(ino, write) { d.release(write); }
+        }
+        if self.vfs.node(ino).is_ok_and(|n| n.type_bits() == S_IFIFO) { self.wake_pipes(); }
+    }
+
+    fn sys_sbrk(&mut self, inc: i64) -> SysResult {
+        let new = (self.brk as i64).checked_add(inc).ok_or(ENOMEM)?;
+        if new < HEAP_BASE.max(self.sp) as i64 || new > MEMORY_MAX as i64 { return Err(ENOMEM); }
+        let old = self.brk; self.brk = new as usize;
+        self.resize_memory(self.brk.next_multiple_of(PAGE_SIZE));
+        Ok(old as u64)
+    }
+
+    fn sys_pipe(&mut self, fds: u64) -> SysResult {
+        let r = self.guest_range(fds, 16, Access::Write)?;
+        let mut free = (0..OPEN_MAX).filter(|fd| !self.fds.contains_key(fd));
+        let ends = [free.next().ok_or(EMFILE)?, free.next().ok_or(EMFILE)?];
+        let ino = self.vfs.create_anon(Kind::CharDev(Box::<Pipe>::default()), 0o600);
+        for (fd, flags) in ends.into_iter().zip([O_RDONLY, O_WRONLY]) {
+            let id = (0..).find(|id| !self.open_files.contains_key(id)).unwrap();
+            self.open_files.insert(id, OpenFile { ino, pos: 0, flags, refs: 0 });
+            self.bind(fd, id);
+        }
+        for (i, fd) in ends.into_iter().enumerate() { self.write_u64(r.start + i * 8, fd).map_err(|_| EFAULT)?; }
+        Ok(0)
+    }
+
+    fn sys_dup2(&mut self, old: u64, new: u64) -> SysResult {
+        let id = *self.fds.get(&old).ok_or(EBADF)?;
+        if new >= OPEN_MAX { return Err(EBADF); }
+        if old == new { return Ok(new); }
+        if self.fds.contains_key(&new) { self.sys_close(new)?; }
+        self.bind(new, id);
+        Ok(new)
+    }
+
+    fn sys_read(&mut self, fd: u64, buf: u64, len: u64) -> SysResult {
+        let id = *self.fds.get(&fd).ok_or(EBADF)?;
+        let OpenFile { ino, pos, .. } = self.ofd(fd)?.clone();
+        if matches!(self.vfs.node(ino)?.kind, Kind::CharDev(_)) { return self.read_at_fd(fd, buf, len, 0).map(|n| n as u64); }
+        let n = self.read_at_fd(fd, buf, len, pos)?;
+        if let Some(o) = self.open_files.get_mut(&id) { o.pos += n; }
+        Ok(n as u64)
+    }
+
+    fn sys_write(&mut self, fd: u64, buf: u64, len: u64) -> SysResult {
+        let id = *self.fds.get(&fd).ok_or(EBADF)?;
+        let OpenFile { ino, pos, flags, .. } = self.ofd(fd)?.clone();
+        let node = self.vfs.node(ino)?;
+        if matches!(node.kind, Kind::CharDev(_)) { return self.write_at_fd(fd, buf, len, pos).map(|n| n as u64); }
+        let pos = if flags & O_APPEND != 0 { node.size() } else { pos };
+        let n = self.write_at_fd(fd, buf, len, pos)?;
+        if let Some(o) = self.open_files.get_mut(&id) { o.pos = pos + n; }
+        Ok(n as u64)
+    }
+
+    fn sys_pread(&mut self, fd: u64, buf: u64, len: u64, off: i64) -> SysResult {
+        let pos = self.seekable_at(fd, off)?;
+        self.read_at_fd(fd, buf, len, pos).map(|n| n as u64)
+    }
+
+    fn sys_pwrite(&mut self, fd: u64, buf: u64, len: u64, off: i64) -> SysResult {
+        let pos = self.seekable_at(fd, off)?;
+        self.write_at_fd(fd, buf, len, pos).map(|n| n as u64)
+    }
+
+    // Validates an explicit pread/pwrite offset: EBADF, ESPIPE for devices, EINVAL if negative.
+    fn seekable_at(&self, fd: u64, off: i64) -> Result<usize, u64> {
+        let ino = self.ofd(fd)?.ino;
+        if matches!(self.vfs.node(ino)?.kind, Kind::CharDev(_)) { return Err(ESPIPE); }
+        usize::try_from(off).map_err(|_| EINVAL)
+    }
+
+    // The shared body of read and pread: reads at `pos` without touching the file offset.
+    fn read_at_fd(&mut self, fd: u64, buf: u64, len: u64, pos: usize) -> Result<usize, u64> {
+        let OpenFile { ino, flags, .. } = self.ofd(fd)?.clone();
+        if flags & O_ACCMODE == O_WRONLY { return Err(EBADF); }
+        let r = self.guest_range(buf, len, Access::Write)?;
+        let n = self.vfs.read_at(ino, pos, &mut self.memory[r.clone()])?;
+        if n > 0 { self.record_write(r.start, n); }
+        Ok(n)
+    }
+
+    // The shared body of write and pwrite: writes at `pos`, zero-filling any gap past the end of the file.
+    fn write_at_fd(&mut self, fd: u64, buf: u64, len: u64, pos: usize) -> Result<usize, u64> {
+        let OpenFile { ino, flags, .. } = self.ofd(fd)?.clone();
+        if flags & O_ACCMODE == O_RDONLY { return Err(EBADF); }
+        if len > 0 && matches!(self.vfs.node(ino)?.kind, Kind::File(_)) && pos.saturating_add(len as usize) > self.limits.file_bytes { return Err(EFBIG); }
+        let r = self.guest_range(buf, len, Access::Read)?;
+        self.vfs.write_at(ino, pos, &self.memory[r])
+    }
+
+    fn sys_ftruncate(&mut self, fd: u64, len: i64) -> SysResult {
+        let OpenFile { ino, flags, .. } = self.ofd(fd)?.clone();
+        if flags & O_ACCMODE == O_RDONLY { return Err(EINVAL); }
+        let len = usize::try_from(len).map_err(|_| EINVAL)?;
+        if !matches!(self.vfs.node(ino)?.kind, Kind::File(_)) { return Err(EINVAL); }
+        if len > self.limits.file_bytes { return Err(EFBIG); }
+        self.vfs.set_len(ino, len)?;
+        Ok(0)
+    }
+
+    fn sys_lseek(&mut self, fd: u64, off: i64, whence: u64) -> SysResult {
+        let id = *self.fds.get(&fd).ok_or(EBADF)?;
+        let OpenFile { ino, pos, .. } = self.ofd(fd)?.clone();
+        let node = self.vfs.node(ino)?;
+        if matches!(node.kind, Kind::CharDev(_)) { return Err(ESPIPE); }
+        let base = match whence { 0 => 0, 1 => pos as i64, 2 => node.size() as i64, _ => return Err(EINVAL) };
+        let new = base.checked_add(off).filter(|p| *p >= 0).ok_or(EINVAL)?;
+        let pos = usize::try_from(new).map_err(|_| EINVAL)?;
+        if let Some(o) = self.open_files.get_mut(&id) { o.pos = pos; }
+        Ok(new as u64)
+    }
+
+    fn sys_stat(&mut self, ino: u64, buf: u64) -> SysResult {
+        let words = self.vfs.stat(ino)?;
+        let r = self.guest_range(buf, 40, Access::Write)?;
+        for (i, w) in words.into_iter().enumerate() { self.write_u64(r.start + i * 8, w).map_err(|_| EFAULT)?; }
+        Ok(0)
+    }
+
+    // The offset of a directory fd counts entries, so successive calls walk the listing in order.
+    fn sys_readdir(&mut self, fd: u64, buf: u64, size: u64) -> SysResult {
+        let id = *self.fds.get(&fd).ok_or(EBADF)?;
+        let OpenFile { ino, pos, .. } = self.ofd(fd)?.clone();
+        let list = self.vfs.list(ino)?;
+        let Some((name, _)) = list.get(pos) else { return Ok(0) };
+        if name.len() + 1 > size as usize { return Err(EINVAL); }
+        let mut bytes = name.as_bytes().to_vec(); bytes.push(0);
+        self.put_guest(buf, &bytes)?;
+        if let Some(o) = self.open_files.get_mut(&id) { o.pos += 1; }
+        Ok(name.len() as u64)
+    }
+
+    fn sys_getcwd(&mut self, buf: u64, size: u64) -> SysResult {
+        let cwd = b"/\0";
+        if (size as usize) < cwd.len() { return Err(ERANGE); }
+        self.put_guest(buf, cwd)
+    }
+}
@@ -0,0 +1,97 @@
+use crate::snapshot::fnv1a;
+use crate::{Machine, Trap};
+use std::fmt::Write;
+
+// --- EXECUTION TRACE ---
+// One record per executed instruction. `stack_top` is sampled after the instruction ran.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StepEvent {
+    pub ip: usize, pub op: u8, pub stack_top: Option<u64>,
+    pub writes: Vec<(usize, Vec<u8>)>, pub syscall: Option<(u64, u64)>,
+}
+
+impl StepEvent {
+    fn render(&self, out: &mut String) {
+        let _ = write!(out, "{:06x} {:02x}", self.ip, self.op);
+        match self.stack_top { Some(t) => { let _ = write!(out, " top={}", t); } None => out.push_str(" top=-") }
+        for (a, bytes) in &self.writes {
+            let _ = write!(out, " w{}=", a);
+            for b in bytes { let _ = write!(out, "{:02x}", b); }
+        }
+        if let Some((n, r)) = self.syscall { let _ = write!(out, " sys{}={}", n, r); }
+    }
+}
+
+pub trait Tracer { fn on_step(&mut self, m: &Machine, ev: &StepEvent); }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceMode { Full, RollingHash(u64) }
+
+// Records either every step as a text line, or a chained FNV-1a hash emitted once per window of N steps.
+// Both forms are line-oriented so `diff_traces` can compare them.
+pub struct TraceRecorder { pub mode: TraceMode, pub lines: Vec<String>, pub steps: u64, hash: u64 }
+
+impl TraceRecorder {
+    pub fn new(mode: TraceMode) -> Self { Self { mode, lines: Vec::new(), steps: 0, hash: fnv1a(&[]) } }
+
+    // Flushes a trailing partial hash window and returns the trace text.
+    pub fn finish(mut self) -> String {
+        if let TraceMode::RollingHash(n) = self.mode { if n > 0 && !self.steps.is_multiple_of(n) { self.lines.push(format!("{} {:016x}", self.steps, self.hash)); } }
+        let mut s = self.lines.join("\n"); s.push('\n'); s
+    }
+}
+
+impl Tracer for TraceRecorder {
+    fn on_step(&mut self, _m: &Machine, ev: &StepEvent) {
+        self.steps += 1;
+        let mut line = String::new(); ev.render(&mut line);
+        match self.mode {
+            TraceMode::Full => self.lines.push(format!("{} {}", self.steps, line)),
+            TraceMode::RollingHash(n) => {
+                let mut chained = self.hash.to_le_bytes().to_vec(); chained.extend_from_slice(line.as_bytes());
+                self.hash = fnv1a(&chained);
+                if n > 0 && self.steps.is_multiple_of(n) { self.lines.push(format!("{} {:016x}", self.steps, self.hash)); }
+            }
+        }
+    }
+}
+
+impl Machine {
+    // Executes one instruction and reports it to `tracer`. Trapping steps are not reported.
+    pub fn step_traced(&mut self, tracer: &mut dyn Tracer) -> Result<bool, Trap> {
+        self.trace_buf = Some(StepEvent { ip: self.ip, op: self.memory.get(self.ip).copied().unwrap_or(0), ..Default::default() });
+        let r = self.step();
+        let mut ev = self.trace_buf.take().unwrap_or_default();
+        if r.is_ok() && self.blocked.is_none() { ev.stack_top = self.stack.last().copied(); tracer.on_step(self, &ev); }
+        r
+    }
+
+    pub(crate) fn record_write(&mut self, addr: usize, len: usize) {
+        if let Some(ev) = self.trace_buf.as_mut() { ev.writes.push((addr, self.memory[addr..addr + len].to_vec())); }
+    }
+}
+
+// --- TRACE DIFF ---
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence { pub line: usize, pub left: Option<String>, pub right: Option<String> }
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "first divergence at trace line {}", self.line)?;
+        writeln!(f, "  < {}", self.left.as_deref().unwrap_or("<end of trace>"))?;
+        write!(f, "  > {}", self.right.as_deref().unwrap_or("<end of trace>"))
+    }
+}
+
+// Compares two traces of the same mode line by line. `None` means the runs were identical.
+pub fn diff_traces(a: &str, b: &str) -> Option<Divergence> {
+    let (mut la, mut lb) = (a.lines(), b.lines());
+    let mut line = 1;
+    loop {
+        match (la.next(), lb.next()) {
+            (None, None) => return None,
+            (x, y) if x != y => return Some(Divergence { line, left: x.map(String::from), right: y.map(String::from) }),
+            _ => line += 1,
+        }
+    }
+}
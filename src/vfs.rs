@@ -0,0 +1,440 @@
+use crate::chunk::{Blob, ChunkStore};
+use crate::device::{self, Device};
+use crate::snapshot::{fnv1a, put, put_bytes, Reader};
+use crate::syscall::errno::*;
+use crate::syscall::{S_IFDIR, S_IFLNK, S_IFREG};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+// --- VFS ---
+// An inode tree. Paths are resolved component by component from the root (the only working directory), so
+// `a/../b` and `b` name the same inode and symlinks are followed the way POSIX does. Inode numbers are handed out
+// in creation order and never reused, and mtimes come from `now`, so a run's file system is reproducible.
+pub const ROOT: u64 = 1;
+const SYMLOOP_MAX: usize = 40;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    File(Blob),
+    Dir { entries: BTreeMap<String, u64>, parent: u64 },
+    Symlink(String),
+    // Character device; I/O goes to its driver (see device.rs).
+    CharDev(Box<dyn Device>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+// `readonly` inodes came from a read-only mount; the guest can neither change them nor add names to them.
+pub struct Inode { pub kind: Kind, pub mode: u64, pub nlink: u64, pub mtime: u64, pub readonly: bool }
+
+impl Inode {
+    pub fn type_bits(&self) -> u64 {
+        match &self.kind { Kind::File(_) => S_IFREG, Kind::Dir { .. } => S_IFDIR, Kind::Symlink(_) => S_IFLNK, Kind::CharDev(d) => d.type_bits() }
+    }
+    pub fn size(&self) -> usize {
+        match &self.kind { Kind::File(b) => b.len(), Kind::CharDev(_) => 0, Kind::Dir { entries, .. } => entries.len() + 2, Kind::Symlink(t) => t.len() }
+    }
+    pub fn is_dir(&self) -> bool { matches!(self.kind, Kind::Dir { .. }) }
+}
+
+// What the preprocessor, the build driver and the VM need from a file tree. `Vfs` is the real one; a plain
+// name -> text map also works for host-side builds that only supply headers.
+pub trait FileSystem {
+    fn read_file(&self, path: &str) -> Option<Vec<u8>>;
+    fn write_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), String>;
+}
+
+impl FileSystem for Vfs {
+    fn read_file(&self, path: &str) -> Option<Vec<u8>> { self.get(path) }
+    fn write_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), String> { Vfs::write_file(self, path, data).map(|_| ()).map_err(|e| format!("{}: errno {}", path, e)) }
+}
+
+impl FileSystem for HashMap<String, String> {
+    fn read_file(&self, path: &str) -> Option<Vec<u8>> { self.get(path).map(|s| s.as_bytes().to_vec()) }
+    fn write_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), String> { self.insert(path.to_string(), String::from_utf8_lossy(&data).into_owned()); Ok(()) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountMode { ReadOnly, Overlay }
+
+// Cloning is O(inodes): file contents are shared chunk handles (see chunk.rs).
+#[derive(Debug, Clone)]
+pub struct Vfs { pub inodes: BTreeMap<u64, Inode>, pub next_ino: u64, pub now: u64, pub store: ChunkStore }
+
+impl Default for Vfs {
+    fn default() -> Self { Self::new() }
+}
+
+impl Vfs {
+    pub fn new() -> Self {
+        let root = Inode { kind: Kind::Dir { entries: BTreeMap::new(), parent: ROOT }, mode: 0o755, nlink: 2, mtime: 0, readonly: false };
+        Self { inodes: BTreeMap::from([(ROOT, root)]), next_ino: ROOT + 1, now: 0, store: ChunkStore::default() }
+    }
+
+    pub fn node(&self, ino: u64) -> Result<&Inode, u64> { self.inodes.get(&ino).ok_or(ENOENT) }
+    pub fn node_mut(&mut self, ino: u64) -> Result<&mut Inode, u64> { self.inodes.get_mut(&ino).ok_or(ENOENT) }
+
+    fn entries(&self, ino: u64) -> Result<&BTreeMap<String, u64>, u64> {
+        match &self.node(ino)?.kind { Kind::Dir { entries, .. } => Ok(entries), _ => Err(ENOTDIR) }
+    }
+    fn entries_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<String, u64>, u64> {
+        let now = self.now;
+        let n = self.node_mut(ino)?; n.mtime = now;
+        match &mut n.kind { Kind::Dir { entries, .. } => Ok(entries), _ => Err(ENOTDIR) }
+    }
+    pub fn writable(&self, ino: u64) -> Result<(), u64> { if self.node(ino)?.readonly { Err(EROFS) } else { Ok(()) } }
+    fn parent(&self, ino: u64) -> Result<u64, u64> {
+        match &self.node(ino)?.kind { Kind::Dir { parent, .. } => Ok(*parent), _ => Err(ENOTDIR) }
+    }
+
+    fn walk(&self, dir: u64, path: &str, follow_last: bool, budget: &mut usize) -> Result<u64, u64> {
+        if path.is_empty() { return Err(ENOENT); }
+        let mut cur = if path.starts_with('/') { ROOT } else { dir };
+        let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+        for (i, part) in parts.iter().enumerate() {
+            let entries = self.entries(cur)?;
+            if *part == "." { continue; }
+            if *part == ".." { cur = self.parent(cur)?; continue; }
+            let next = *entries.get(*part).ok_or(ENOENT)?;
+            let last = i + 1 == parts.len() && !path.ends_with('/');
+            if let Kind::Symlink(target) = &self.node(next)?.kind {
+                if !last || follow_last {
+                    if *budget == 0 { return Err(ELOOP); }
+                    *budget -= 1;
+                    cur = self.walk(cur, target, true, budget)?;
+                    continue;
+                }
+            }
+            cur = next;
+        }
+        if path.ends_with('/') && !self.node(cur)?.is_dir() { return Err(ENOTDIR); }
+        Ok(cur)
+    }
+
+    // Follows a symlink in the last component.
+    pub fn resolve(&self, path: &str) -> Result<u64, u64> { let mut budget = SYMLOOP_MAX; self.walk(ROOT, path, true, &mut budget) }
+    // Leaves a symlink in the last component unresolved, like lstat.
+    pub fn lresolve(&self, path: &str) -> Result<u64, u64> { let mut budget = SYMLOOP_MAX; self.walk(ROOT, path, false, &mut budget) }
+
+    // (directory inode, final component) for operations that create or remove a name.
+    pub fn parent_of(&self, path: &str) -> Result<(u64, String), u64> {
+        let trimmed = path.trim_end_matches('/');
+        if trimmed.is_empty() { return Err(if path.is_empty() { ENOENT } else { EEXIST }); }
+        let (dir, name) = match trimmed.rfind('/') { Some(i) => (if i == 0 { "/" } else { &trimmed[..i] }, &trimmed[i + 1..]), None => (".", trimmed) };
+        if name == "." || name == ".." { return Err(EINVAL); }
+        let d = self.resolve(dir)?;
+        self.entries(d)?;
+        Ok((d, name.to_string()))
+    }
+
+    fn alloc(&mut self, kind: Kind, mode: u64) -> u64 {
+        let ino = self.next_ino; self.next_ino += 1;
+        self.inodes.insert(ino, Inode { kind, mode: mode & 0o7777, nlink: 1, mtime: self.now, readonly: false });
+        ino
+    }
+
+    // An inode with no name, such as a pipe; it is freed with the last open file description that refers to it.
+    pub fn create_anon(&mut self, kind: Kind, mode: u64) -> u64 {
+        let ino = self.alloc(kind, mode);
+        if let Some(n) = self.inodes.get_mut(&ino) { n.nlink = 0; }
+        ino
+    }
+
+    // Links a new inode at `path`. Directories get their ".." parent and bump the parent's link count.
+    pub fn create(&mut self, path: &str, kind: Kind, mode: u64) -> Result<u64, u64> {
+        let (dir, name) = self.parent_of(path)?;
+        if self.entries(dir)?.contains_key(&name) { return Err(EEXIST); }
+        self.writable(dir)?;
+        let is_dir = matches!(kind, Kind::Dir { .. });
+        let kind = match kind { Kind::Dir { entries, .. } => Kind::Dir { entries, parent: dir }, k => k };
+        let ino = self.alloc(kind, mode);
+        if is_dir { self.node_mut(ino)?.nlink = 2; self.node_mut(dir)?.nlink += 1; }
+        self.entries_mut(dir)?.insert(name, ino);
+        Ok(ino)
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: u64) -> Result<u64, u64> { self.create(path, Kind::Dir { entries: BTreeMap::new(), parent: ROOT }, mode) }
+
+    pub fn mkdir_all(&mut self, path: &str) -> Result<u64, u64> {
+        let mut cur = String::new();
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            cur.push('/'); cur.push_str(part);
+            if let Err(e) = self.mkdir(&cur, 0o755) { if e != EEXIST { return Err(e); } }
+        }
+        let ino = self.resolve(if cur.is_empty() { "/" } else { &cur })?;
+        self.entries(ino)?;
+        Ok(ino)
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> Result<u64, u64> {
+        let (dir, name) = self.parent_of(path)?;
+        let ino = *self.entries(dir)?.get(&name).ok_or(ENOENT)?;
+        if !self.entries(ino)?.is_empty() { return Err(ENOTEMPTY); }
+        self.writable(dir)?;
+        self.entries_mut(dir)?.remove(&name);
+        self.node_mut(dir)?.nlink -= 1;
+        self.node_mut(ino)?.nlink = 0;
+        Ok(ino)
+    }
+
+    // Removes one name. Returns the inode so the caller can free it once nothing has it open.
+    pub fn unlink(&mut self, path: &str) -> Result<u64, u64> {
+        let (dir, name) = self.parent_of(path)?;
+        let ino = *self.entries(dir)?.get(&name).ok_or(ENOENT)?;
+        if self.node(ino)?.is_dir() { return Err(EISDIR); }
+        self.writable(dir)?;
+        self.entries_mut(dir)?.remove(&name);
+        self.node_mut(ino)?.nlink -= 1;
+        Ok(ino)
+    }
+
+    pub fn link(&mut self, old: &str, new: &str) -> Result<u64, u64> {
+        let ino = self.lresolve(old)?;
+        if self.node(ino)?.is_dir() { return Err(EPERM); }
+        let (dir, name) = self.parent_of(new)?;
+        if self.entries(dir)?.contains_key(&name) { return Err(EEXIST); }
+        self.writable(dir)?; self.writable(ino)?;
+        self.entries_mut(dir)?.insert(name, ino);
+        self.node_mut(ino)?.nlink += 1;
+        Ok(ino)
+    }
+
+    pub fn symlink(&mut self, target: &str, path: &str) -> Result<u64, u64> {
+        if target.is_empty() { return Err(ENOENT); }
+        self.create(path, Kind::Symlink(target.to_string()), 0o777)
+    }
+
+    pub fn readlink(&self, path: &str) -> Result<&str, u64> {
+        match &self.node(self.lresolve(path)?)?.kind { Kind::Symlink(t) => Ok(t), _ => Err(EINVAL) }
+    }
+
+    // POSIX rename: atomically replaces `new`. Returns the inode that lost its name there, if any.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<Option<u64>, u64> {
+        let (od, oname) = self.parent_of(old)?;
+        let src = *self.entries(od)?.get(&oname).ok_or(ENOENT)?;
+        let (nd, nname) = self.parent_of(new)?;
+        self.writable(od)?; self.writable(nd)?;
+        let src_dir = self.node(src)?.is_dir();
+        if src_dir {
+            // a directory cannot move beneath itself
+            let mut a = nd;
+            while a != ROOT { if a == src { return Err(EINVAL); } a = self.parent(a)?; }
+        }
+        let replaced = self.entries(nd)?.get(&nname).copied();
+        if let Some(t) = replaced {
+            if t == src { return Ok(None); }
+            match (src_dir, self.node(t)?.is_dir()) {
+                (true, true) => { if !self.entries(t)?.is_empty() { return Err(ENOTEMPTY); } self.node_mut(nd)?.nlink -= 1; self.node_mut(t)?.nlink = 0; }
+                (true, false) => return Err(ENOTDIR),
+                (false, true) => return Err(EISDIR),
+                (false, false) => self.node_mut(t)?.nlink -= 1,
+            }
+        }
+        self.entries_mut(od)?.remove(&oname);
+        self.entries_mut(nd)?.insert(nname, src);
+        if src_dir {
+            if let Kind::Dir { parent, .. } = &mut self.node_mut(src)?.kind { *parent = nd; }
+            self.node_mut(od)?.nlink -= 1; self.node_mut(nd)?.nlink += 1;
+        }
+        Ok(replaced)
+    }
+
+    // Directory listing in readdir order: ".", "..", then names sorted bytewise.
+    pub fn list(&self, ino: u64) -> Result<Vec<(String, u64)>, u64> {
+        let mut out = vec![(".".to_string(), ino), ("..".to_string(), self.parent(ino)?)];
+        out.extend(self.entries(ino)?.iter().map(|(n, i)| (n.clone(), *i)));
+        Ok(out)
+    }
+
+    // dre_stat words: { st_mode, st_size, st_ino, st_nlink, st_mtime }.
+    pub fn stat(&self, ino: u64) -> Result<[u64; 5], u64> {
+        let n = self.node(ino)?;
+        Ok([n.type_bits() | n.mode, n.size() as u64, ino, n.nlink, n.mtime])
+    }
+
+    pub fn touch(&mut self, ino: u64) { let now = self.now; if let Some(n) = self.inodes.get_mut(&ino) { n.mtime = now; } }
+
+    // Reads from a file at `pos`, or from a device; returns the number of bytes copied into `buf`.
+    pub fn read_at(&mut self, ino: u64, pos: usize, buf: &mut [u8]) -> Result<usize, u64> {
+        match &self.node(ino)?.kind {
+            Kind::File(b) => Ok(b.read_at(pos, buf)),
+            Kind::CharDev(_) => self.device(ino, false)?.read(buf),
+            Kind::Dir { .. } => Err(EISDIR), Kind::Symlink(_) => Err(EINVAL),
+        }
+    }
+
+    // Writes at `pos` (zero-filling any gap); devices ignore `pos`.
+    pub fn write_at(&mut self, ino: u64, pos: usize, data: &[u8]) -> Result<usize, u64> {
+        let now = self.now;
+        let n = self.inodes.get_mut(&ino).ok_or(ENOENT)?;
+        match &mut n.kind {
+            Kind::File(b) => self.store.write_at(b, pos, data)?,
+            Kind::CharDev(_) => return self.device(ino, true)?.write(data),
+            Kind::Dir { .. } => return Err(EISDIR), Kind::Symlink(_) => return Err(EINVAL),
+        }
+        n.mtime = now;
+        Ok(data.len())
+    }
+
+    // The driver behind a device inode, following a /dev/tty style route to the reading or writing side.
+    pub fn device(&mut self, ino: u64, write: bool) -> Result<&mut Box<dyn Device>, u64> {
+        let ino = match &self.node(ino)?.kind {
+            Kind::CharDev(d) => d.route().map_or(ino, |(r, w)| if write { w } else { r }),
+            _ => return Err(ENOTTY),
+        };
+        match &mut self.node_mut(ino)?.kind { Kind::CharDev(d) if d.route().is_none() => Ok(d), _ => Err(ENXIO) }
+    }
+
+    pub fn set_len(&mut self, ino: u64, len: usize) -> Result<(), u64> {
+        let now = self.now;
+        let n = self.inodes.get_mut(&ino).ok_or(ENOENT)?;
+        match &mut n.kind { Kind::File(b) => self.store.set_len(b, len)?, Kind::Dir { .. } => return Err(EISDIR), _ => return Err(EINVAL) }
+        n.mtime = now;
+        Ok(())
+    }
+
+    pub fn contents(&self, ino: u64) -> Result<Vec<u8>, u64> {
+        match &self.node(ino)?.kind { Kind::File(b) => Ok(b.to_vec()), Kind::Dir { .. } => Err(EISDIR), Kind::Symlink(_) | Kind::CharDev(_) => Err(EINVAL) }
+    }
+
+    // Host-side conveniences: contents of the file at `path`, and whether anything is linked there.
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> { self.resolve(path).and_then(|i| self.contents(i)).ok() }
+    pub fn contains(&self, path: &str) -> bool { self.lresolve(path).is_ok() }
+
+    // Creates or replaces a regular file, making missing parent directories.
+    pub fn write_file(&mut self, path: &str, data: Vec<u8>) -> Result<u64, u64> {
+        let blob = self.store.blob(&data);
+        if let Ok(ino) = self.resolve(path) {
+            match &mut self.node_mut(ino)?.kind { Kind::File(b) => *b = blob, Kind::Dir { .. } => return Err(EISDIR), Kind::Symlink(_) | Kind::CharDev(_) => return Err(EINVAL) }
+            self.touch(ino);
+            return Ok(ino);
+        }
+        let (dir, _) = path.trim_end_matches('/').rsplit_once('/').unwrap_or(("", path));
+        self.mkdir_all(dir)?;
+        self.create(path, Kind::File(blob), 0o644)
+    }
+
+    // Merkle hash of the subtree at `ino`: files hash their chunk hashes, directories hash their sorted
+    // (name, child hash) pairs, and every node folds in its type and permission bits. Inode numbers and mtimes
+    // are left out, so two trees with the same shape and contents agree however they were built.
+    pub fn hash_of(&self, ino: u64) -> Result<u64, u64> {
+        let n = self.node(ino)?;
+        let mut b = (n.type_bits() | n.mode).to_le_bytes().to_vec();
+        match &n.kind {
+            Kind::File(blob) => b.extend_from_slice(&blob.hash().to_le_bytes()),
+            Kind::CharDev(d) => { let (name, state) = d.encode(); b.extend_from_slice(name.as_bytes()); b.extend_from_slice(&fnv1a(&state).to_le_bytes()); }
+            Kind::Symlink(t) => b.extend_from_slice(t.as_bytes()),
+            Kind::Dir { entries, .. } => for (name, i) in entries {
+                b.extend_from_slice(&(name.len() as u64).to_le_bytes()); b.extend_from_slice(name.as_bytes());
+                b.extend_from_slice(&self.hash_of(*i)?.to_le_bytes());
+            },
+        }
+        Ok(fnv1a(&b))
+    }
+
+    pub fn merkle_root(&self) -> u64 { self.hash_of(ROOT).unwrap_or(0) }
+
+    // Copies the host tree at `host` into the VFS at `guest` and returns the number of files imported. The guest
+    // only ever sees these copies, never host paths, and host symlinks arrive as guest symlinks instead of being
+    // followed out of the mount. Entries are imported in name order with `now` as their mtime, so the same host
+    // tree always yields the same inodes. ReadOnly marks everything imported, including `guest` itself; Overlay
+    // lets the guest change its copies in memory while the host stays untouched.
+    pub fn mount(&mut self, guest: &str, host: &Path, mode: MountMode) -> Result<usize, String> {
+        let root = self.mkdir_all(guest).map_err(|e| format!("{}: cannot mount on this path (errno {})", guest, e))?;
+        let n = self.import(guest.trim_end_matches('/'), host, mode == MountMode::ReadOnly)?;
+        if mode == MountMode::ReadOnly { self.node_mut(root).map_err(|_| "mount root vanished")?.readonly = true; }
+        Ok(n)
+    }
+
+    fn import(&mut self, guest: &str, host: &Path, readonly: bool) -> Result<usize, String> {
+        let err = |e: std::io::Error| format!("{}: {}", host.display(), e);
+        let mut entries = std::fs::read_dir(host).map_err(err)?.collect::<Result<Vec<_>, _>>().map_err(err)?;
+        entries.sort_by_key(|e| e.file_name());
+        let mut files = 0;
+        for e in entries {
+            let name = e.file_name().into_string().map_err(|n| format!("{}: non-UTF-8 file name {:?}", host.display(), n))?;
+            let (path, meta) = (format!("{}/{}", guest, name), e.path().symlink_metadata().map_err(err)?);
+            let errno = |n: u64| format!("{}: errno {}", path, n);
+            let ino = if meta.file_type().is_symlink() {
+                let target = std::fs::read_link(e.path()).map_err(err)?;
+                let _ = self.unlink(&path);
+                self.symlink(&target.to_string_lossy(), &path).map_err(errno)?
+            } else if meta.is_dir() {
+                let ino = self.mkdir_all(&path).map_err(errno)?;
+                files += self.import(&path, &e.path(), readonly)?;
+                ino
+            } else if meta.is_file() {
+                files += 1;
+                self.write_file(&path, std::fs::read(e.path()).map_err(err)?).map_err(errno)?
+            } else { continue };
+            if readonly { self.node_mut(ino).map_err(errno)?.readonly = true; }
+        }
+        Ok(files)
+    }
+
+    // Writes the subtree at `guest` out to the host directory `host` and returns the number of files written.
+    // Symlinks are exported as symlinks (on Unix hosts) and never followed; devices are skipped.
+    pub fn export(&self, guest: &str, host: &Path) -> Result<usize, String> {
+        let ino = self.lresolve(guest).map_err(|e| format!("{}: errno {}", guest, e))?;
+        self.export_node(ino, host)
+    }
+
+    fn export_node(&self, ino: u64, host: &Path) -> Result<usize, String> {
+        let err = |e: std::io::Error| format!("{}: {}", host.display(), e);
+        match &self.node(ino).map_err(|_| "dangling inode")?.kind {
+            Kind::Dir { entries, .. } => {
+                std::fs::create_dir_all(host).map_err(err)?;
+                entries.iter().map(|(name, i)| self.export_node(*i, &host.join(name))).sum()
+            }
+            Kind::File(b) => { std::fs::write(host, b.to_vec()).map_err(err)?; Ok(1) }
+            #[cfg(unix)]
+            Kind::Symlink(t) => { let _ = std::fs::remove_file(host); std::os::unix::fs::symlink(t, host).map_err(err)?; Ok(0) }
+            _ => Ok(0),
+        }
+    }
+
+    // Each distinct chunk is written once, in first-use order, and files refer to chunks by index.
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        let mut index: HashMap<*const u8, u64> = HashMap::new();
+        let mut table = Vec::new();
+        for n in self.inodes.values() {
+            let Kind::File(b) = &n.kind else { continue };
+            for c in &b.chunks { index.entry(c.data.as_ptr()).or_insert_with(|| { table.push(&c.data); table.len() as u64 - 1 }); }
+        }
+        put(out, table.len() as u64);
+        for d in table { put_bytes(out, d); }
+        put(out, self.now); put(out, self.next_ino); put(out, self.inodes.len() as u64);
+        for (ino, n) in &self.inodes {
+            for v in [*ino, n.mode, n.nlink, n.mtime] { put(out, v); }
+            put(out, n.readonly as u64);
+            match &n.kind {
+                Kind::File(b) => { put(out, 0); put(out, b.chunks.len() as u64); for c in &b.chunks { put(out, index[&c.data.as_ptr()]); } }
+                Kind::Dir { entries, parent } => { put(out, 1); put(out, *parent); put(out, entries.len() as u64); for (name, i) in entries { put_bytes(out, name.as_bytes()); put(out, *i); } }
+                Kind::Symlink(t) => { put(out, 2); put_bytes(out, t.as_bytes()); }
+                Kind::CharDev(d) => { let (name, state) = d.encode(); put(out, 3); put_bytes(out, name.as_bytes()); put_bytes(out, &state); }
+            }
+        }
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Result<Vfs, String> {
+        let mut store = ChunkStore::default();
+        let table = (0..r.usize()?).map(|_| Ok(store.intern(r.bytes()?))).collect::<Result<Vec<_>, String>>()?;
+        let mut v = Vfs { inodes: BTreeMap::new(), now: r.get()?, next_ino: r.get()?, store };
+        for _ in 0..r.usize()? {
+            let (ino, mode, nlink, mtime) = (r.get()?, r.get()?, r.get()?, r.get()?);
+            let readonly = r.get()? != 0;
+            let kind = match r.get()? {
+                0 => {
+                    let chunks = (0..r.usize()?).map(|_| table.get(r.usize()?).cloned().ok_or_else(|| "chunk index out of range".to_string())).collect::<Result<Vec<_>, _>>()?;
+                    Kind::File(Blob::from_chunks(chunks).ok_or("malformed chunk list")?)
+                }
+                1 => { let parent = r.get()?; let mut entries = BTreeMap::new(); for _ in 0..r.usize()? { let name = r.string()?; entries.insert(name, r.get()?); } Kind::Dir { entries, parent } }
+                2 => Kind::Symlink(r.string()?),
+                3 => { let name = r.string()?; Kind::CharDev(device::decode(&name, r.bytes()?)?) }
+                k => return Err(format!("unknown inode kind {}", k)),
+            };
+            v.inodes.insert(ino, Inode { kind, mode, nlink, mtime, readonly });
+        }
+        if !v.inodes.get(&ROOT).is_some_and(|n| n.is_dir()) { return Err("vfs has no root directory".into()); }
+        Ok(v)
+    }
+}